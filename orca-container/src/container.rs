@@ -3,17 +3,21 @@ mod parent;
 mod terminal;
 
 use super::image::ContainerImage;
+use super::print_error;
+use super::process::Process;
 use super::OrExit;
 use super::STACK_SIZE;
 use anyhow::bail;
 use anyhow::{Context, Result};
 use nix::libc::SIGCHLD;
 use nix::sched::{clone, CloneFlags};
-use nix::sys::wait::wait;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::Pid;
 use os_pipe::pipe;
 use os_pipe::PipeReader;
 use os_pipe::PipeWriter;
 use parent::io_connector::IoConnector;
+use parent::HostNamespace;
 use std::io::stdin;
 use std::io::{Read, Write};
 use std::path::Path;
@@ -24,12 +28,14 @@ const SIGNAL_OPEN_PTMX: &[u8] = b"2";
 
 pub struct Container<T> {
     image: T,
+    pid: Pid,
+    host_namespace: HostNamespace,
     io_connector: IoConnector,
     terminal: Terminal,
 }
 
 impl<T: ContainerImage> Container<T> {
-    pub fn new<P>(image: T, command: Vec<String>, work_dir: P) -> Result<Self>
+    pub fn new<P>(image: T, process: Process, work_dir: P) -> Result<Self>
     where
         T: ContainerImage,
         P: AsRef<Path>,
@@ -39,7 +45,7 @@ impl<T: ContainerImage> Container<T> {
         let (mut parent_reader, mut child_writer) = pipe().unwrap();
         let cb = Box::new(|| {
             child_main(
-                &command,
+                &process,
                 &image,
                 work_dir.as_ref(),
                 &mut child_reader,
@@ -54,6 +60,7 @@ impl<T: ContainerImage> Container<T> {
         let child_pid =
             unsafe { clone(cb, stack, flags, signals).context("Failed to clone child process")? };
 
+        let host_namespace = HostNamespace::save()?;
         parent::Initilizer::setns(child_pid, flags).context("Failed to enter namespace")?;
 
         let mut signal_buf: [u8; 1] = [b'0'; 1];
@@ -70,16 +77,27 @@ impl<T: ContainerImage> Container<T> {
 
         Ok(Container {
             image,
+            pid: child_pid,
+            host_namespace,
             io_connector,
             terminal,
         })
     }
 
-    pub fn wait(self) -> Result<T> {
-        wait().context("Failed to wait child process")?;
+    /// Waits for the command to finish and returns its exit status
+    /// (128 + signal number if it was killed by a signal)
+    pub fn wait(self) -> Result<(T, i32)> {
+        let status = loop {
+            match waitpid(self.pid, None).context("Failed to wait child process")? {
+                WaitStatus::Exited(_, code) => break code,
+                WaitStatus::Signaled(_, signal, _) => break 128 + signal as i32,
+                _ => continue,
+            }
+        };
         self.io_connector.stop()?;
         std::mem::drop(self.terminal);
-        Ok(self.image)
+        self.host_namespace.restore()?;
+        Ok((self.image, status))
     }
 }
 
@@ -104,16 +122,14 @@ fn sync_tty_size(src: &Terminal, tar: &mut Terminal) -> Result<()> {
     Ok(())
 }
 
-#[allow(clippy::needless_return)]
-fn child_main<T, I>(
-    command: &[T],
+fn child_main<I>(
+    process: &Process,
     image: &I,
     work_dir: &Path,
     reader: &mut PipeReader,
     writer: &mut PipeWriter,
 ) -> isize
 where
-    T: AsRef<str>,
     I: ContainerImage,
 {
     use child::Initializer;
@@ -150,9 +166,22 @@ where
     Initializer::unmount_old_root()
         .context(error_message)
         .or_exit();
-    Initializer::exec(command)
-        .context("Failed to initialize container")
-        .or_exit();
-
-    return 0; // This return is unreadchable but neccessary for CloneCb
+    if let Some(cwd) = &process.cwd {
+        Initializer::change_dir(cwd)
+            .context(error_message)
+            .or_exit();
+    }
+    let mut env = vec![];
+    if let Some(user) = &process.user {
+        let home = Initializer::switch_user(user)
+            .context(error_message)
+            .or_exit();
+        if let Some(home) = home {
+            env.push(format!("HOME={home}"));
+        }
+    }
+    env.extend(process.env.iter().cloned());
+    let Err(err) = Initializer::exec(&process.argv, &env).context("Failed to initialize container");
+    print_error(err);
+    1
 }
@@ -2,12 +2,17 @@ use crate::mount::*;
 use anyhow::{Context, Result};
 use const_format::concatcp;
 use core::convert::Infallible;
-use nix::unistd;
+use nix::unistd::{self, Gid, Uid};
 use std::ffi::CString;
 use std::fs::{self, copy, create_dir_all};
 use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
 
+const PASSWD_PATH: &str = "/etc/passwd";
+const GROUP_PATH: &str = "/etc/group";
+/// PATH to look up commands in if neither orca nor the image has one
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
 pub struct Initializer;
 
 const OLDROOT_NAME: &str = "oldroot";
@@ -182,7 +187,69 @@ impl Initializer {
         Ok(())
     }
 
-    pub fn exec<S>(command: &[S]) -> Result<Infallible>
+    /// Changes the current directory to `cwd`, creating it like the WORKDIR of an image
+    pub fn change_dir(cwd: &str) -> Result<()> {
+        create_dir_all(cwd).with_context(|| format!("Failed to create '{}'", cwd))?;
+        unistd::chdir(cwd).with_context(|| format!("Failed to chdir to {}", cwd))?;
+        Ok(())
+    }
+
+    /// Switches to `user` given as `user[:group]`, each by name or ID.
+    /// Returns the home directory of the user if it is in /etc/passwd.
+    pub fn switch_user(user: &str) -> Result<Option<String>> {
+        let (user, group) = match user.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (user, None),
+        };
+        let passwd = read_db(PASSWD_PATH)?;
+        let groups = read_db(GROUP_PATH)?;
+
+        // name:password:uid:gid:gecos:home:shell
+        let entry = passwd.iter().find(|entry| match user.parse::<u32>() {
+            Ok(uid) => entry.get(2).and_then(|id| id.parse().ok()) == Some(uid),
+            Err(_) => entry[0] == user,
+        });
+        let uid = match (user.parse::<u32>(), entry) {
+            (Ok(uid), _) => uid,
+            (Err(_), Some(entry)) => parse_id(entry, 2, PASSWD_PATH)?,
+            (Err(_), None) => anyhow::bail!("User '{user}' is not found in {PASSWD_PATH}"),
+        };
+        // name:password:gid:members
+        let gid = match group {
+            Some(group) => match group.parse::<u32>() {
+                Ok(gid) => gid,
+                Err(_) => match groups.iter().find(|entry| entry[0] == group) {
+                    Some(entry) => parse_id(entry, 2, GROUP_PATH)?,
+                    None => anyhow::bail!("Group '{group}' is not found in {GROUP_PATH}"),
+                },
+            },
+            None => match entry {
+                Some(entry) => parse_id(entry, 3, PASSWD_PATH)?,
+                None => 0,
+            },
+        };
+        let mut supplementary = vec![Gid::from_raw(gid)];
+        if let Some(entry) = entry {
+            for group in groups.iter() {
+                let is_member = group
+                    .get(3)
+                    .is_some_and(|members| members.split(',').any(|member| member == entry[0]));
+                if is_member {
+                    supplementary.push(Gid::from_raw(parse_id(group, 2, GROUP_PATH)?));
+                }
+            }
+        }
+
+        unistd::setgroups(&supplementary).context("Failed to set supplementary groups")?;
+        unistd::setgid(Gid::from_raw(gid)).with_context(|| format!("Failed to setgid {gid}"))?;
+        unistd::setuid(Uid::from_raw(uid)).with_context(|| format!("Failed to setuid {uid}"))?;
+        Ok(entry
+            .and_then(|entry| entry.get(5))
+            .map(|home| home.to_string()))
+    }
+
+    /// Executes `command` with the environment variables of orca overridden by `env`
+    pub fn exec<S>(command: &[S], env: &[String]) -> Result<Infallible>
     where
         S: AsRef<str>,
     {
@@ -196,7 +263,62 @@ impl Initializer {
             argv.push(arg_cstring);
         }
 
-        unistd::execvp(command_cstring.as_c_str(), &argv)
-            .with_context(|| format!("Not found: '{}'", command_cstring.to_str().unwrap()))
+        let mut envp: Vec<String> = std::env::vars()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect();
+        for var in env {
+            let key = var.split_once('=').map_or(var.as_str(), |(key, _)| key);
+            envp.retain(|current| !current.starts_with(&format!("{key}=")));
+            envp.push(var.clone());
+        }
+        // The command is looked up in PATH of the new environment, not of orca
+        let path = envp
+            .iter()
+            .find_map(|var| var.strip_prefix("PATH="))
+            .unwrap_or(DEFAULT_PATH);
+        let command_path = resolve_command(command[0].as_ref(), path)
+            .with_context(|| format!("Not found: '{}'", command_cstring.to_str().unwrap()))?;
+        let command_path =
+            CString::new(command_path).context("Failed to change command into CSting")?;
+        let envp = envp
+            .into_iter()
+            .map(CString::new)
+            .collect::<Result<Vec<_>, _>>()
+            .context("Failed to change environment variable into CString")?;
+
+        unistd::execve(command_path.as_c_str(), &argv, &envp)
+            .with_context(|| format!("Failed to execute '{}'", command_cstring.to_str().unwrap()))
+    }
+}
+
+/// Reads /etc/passwd or /etc/group into colon-separated fields, skipping comments
+fn read_db(path: &str) -> Result<Vec<Vec<String>>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {path}"))?,
+    };
+    Ok(content
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.split(':').map(|field| field.to_string()).collect())
+        .collect())
+}
+
+fn parse_id(entry: &[String], index: usize, path: &str) -> Result<u32> {
+    entry
+        .get(index)
+        .and_then(|id| id.parse().ok())
+        .with_context(|| format!("Invalid entry of '{}' in {path}", entry[0]))
+}
+
+/// Finds `command` in `path` unless it is a path already
+fn resolve_command(command: &str, path: &str) -> Option<String> {
+    if command.contains('/') {
+        return Some(command.to_string());
     }
+    path.split(':')
+        .map(|dir| Path::new(dir).join(command))
+        .find(|candidate| candidate.is_file())
+        .map(|candidate| candidate.to_string_lossy().into_owned())
 }
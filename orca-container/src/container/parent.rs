@@ -4,13 +4,36 @@ use anyhow::{Context, Result};
 use io_connector::IoConnector;
 use nix::pty::{grantpt, posix_openpt, unlockpt, PtyMaster};
 use nix::sched::{self, CloneFlags};
-use nix::unistd::Pid;
+use nix::unistd::{fchdir, Pid};
 use std::fs::File;
 use std::io::{stdin, stdout};
-use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::fd::AsFd;
 
 pub struct Initilizer;
 
+/// Mount namespace and working directory of the parent before entering the container's ones
+pub struct HostNamespace {
+    mntns: File,
+    cwd: File,
+}
+
+impl HostNamespace {
+    pub fn save() -> Result<Self> {
+        let mntns =
+            File::open("/proc/self/ns/mnt").context("Failed to open '/proc/self/ns/mnt'")?;
+        let cwd = File::open(".").context("Failed to open current directory")?;
+        Ok(Self { mntns, cwd })
+    }
+
+    /// Goes back to the saved namespace. Must be called after other threads have finished.
+    pub fn restore(self) -> Result<()> {
+        sched::setns(self.mntns, CloneFlags::CLONE_NEWNS)
+            .context("Failed to setns to host mntns")?;
+        fchdir(self.cwd).context("Failed to restore current directory")?;
+        Ok(())
+    }
+}
+
 impl Initilizer {
     pub fn setns(child_pid: Pid, clone_flags: CloneFlags) -> Result<()> {
         let raw_child_pid = child_pid.as_raw() as isize;
@@ -40,7 +63,7 @@ impl Initilizer {
 
         let stdout = stdout();
         let stdin = stdin();
-        let pty_master_clone = unsafe { Self::clone_pty_master(&pty_master) };
+        let pty_master_clone = Self::clone_pty_master(&pty_master)?;
 
         Ok(IoConnector::new(
             stdout,
@@ -50,10 +73,12 @@ impl Initilizer {
         ))
     }
 
-    unsafe fn clone_pty_master(master: &PtyMaster) -> PtyMaster {
-        unsafe {
-            let fd = OwnedFd::from_raw_fd(master.as_raw_fd());
-            PtyMaster::from_owned_fd(fd)
-        }
+    /// Duplicates fd of `master` so that each copy closes its own fd
+    fn clone_pty_master(master: &PtyMaster) -> Result<PtyMaster> {
+        let fd = master
+            .as_fd()
+            .try_clone_to_owned()
+            .context("Failed to duplicate pty master")?;
+        Ok(unsafe { PtyMaster::from_owned_fd(fd) })
     }
 }
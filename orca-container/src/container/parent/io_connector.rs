@@ -69,8 +69,8 @@ impl IoConnector {
 
             'outer: loop {
                 let num_events = epoll.wait(&mut events, EpollTimeout::NONE).unwrap();
-                for i in 0..num_events {
-                    match events[i].data().try_into() {
+                for event in events.iter().take(num_events) {
+                    match event.data().try_into() {
                         // Data available on parent_stdin
                         Ok(IOEvent::ParentStdin) => match read(&parent_stdin, &mut buffer) {
                             Ok(bytes) if bytes > 0 => {
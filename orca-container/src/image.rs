@@ -1,3 +1,7 @@
+mod guest_image;
+
+pub use guest_image::GuestImage;
+
 use crate::mount::{FileType, Mount, MountFlags};
 use anyhow::{Context, Result};
 use std::{
@@ -24,6 +28,16 @@ pub trait ContainerImage {
     fn rootfs_path(&self) -> &Path;
 }
 
+impl<T: ContainerImage + ?Sized> ContainerImage for Box<T> {
+    fn mount(&self) -> Result<()> {
+        (**self).mount()
+    }
+
+    fn rootfs_path(&self) -> &Path {
+        (**self).rootfs_path()
+    }
+}
+
 impl HostImage {
     pub fn new<S1, S2, S3, S4, S5>(
         mount_point: S1,
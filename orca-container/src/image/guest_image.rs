@@ -1,134 +1,110 @@
-mod downloader;
-mod merger;
-
+use super::{create_all_dirs, ContainerImage, OverlayConfig, OVERLAYFS_FSTYPE};
+use crate::mount::{FileType, Mount, MountFlags};
 use anyhow::{Context, Result};
-use downloader::ImageDownloader;
-use merger::ImageMerger;
-use rm_rf::remove;
-use std::fs::create_dir_all;
+use orca_image::downloader::ImageDownloader;
+use orca_image::importer::{ImageImporter, ImportedImage};
+use orca_image::platform::Platform;
+use orca_image::reference::Reference;
+use orca_image::store::{BlobStore, StoredImage};
 use std::path::{Path, PathBuf};
 
-const ROOTFS_NAME: &str = "rootfs";
-const DEFAULT_WORKDIR: &str = "/tmp/image_downloader/";
-
+/// Image whose bottom layers are the layers of a container image
+/// instead of the root filesystem of the host.
+/// The layers are shared with other environments through a [`BlobStore`].
 pub struct GuestImage {
-    image_path: PathBuf,
-    container_path: PathBuf,
-    workdir_path: PathBuf,
-    image_name: String,
-    image_tag: String,
-    container_name: String,
-    display_progress: bool,
+    mount_config: OverlayConfig,
 }
 
 impl GuestImage {
-    pub fn new<S, T, U, V>(rootfs_prefix: S, image_name: T, image_tag: U, container_name: V) -> Self
+    /// `image_layers` are directories of the layers of the image from the bottom one
+    pub fn new<S1, S2, S3, S4, S5>(
+        mount_point: S1,
+        upperdir: S2,
+        additional_lowerdirs: Vec<S3>,
+        workdir: S4,
+        image_layers: Vec<S5>,
+    ) -> Self
     where
-        S: AsRef<Path>,
-        T: ToString,
-        U: ToString,
-        V: ToString,
+        S1: Into<PathBuf>,
+        S2: Into<PathBuf>,
+        S3: Into<PathBuf>,
+        S4: Into<PathBuf>,
+        S5: Into<PathBuf>,
     {
-        let rootfs_prefix = rootfs_prefix.as_ref().to_path_buf();
-        let image_name = image_name.to_string();
-        let image_tag = image_tag.to_string();
-        let container_name = container_name.to_string();
-        let image_name_safe = if image_name.contains('/') {
-            image_name.replace("/", "_")
-        } else {
-            image_name.clone()
-        };
-        let image_root = rootfs_prefix.join(&image_name_safe).join(&image_tag);
-        let image_path = image_root.join(ROOTFS_NAME);
-        let container_path = image_root.join(&container_name).join(ROOTFS_NAME);
-
-        Self {
-            image_path,
-            container_path,
-            workdir_path: AsRef::<Path>::as_ref(DEFAULT_WORKDIR).to_path_buf(),
-            image_name,
-            image_tag,
-            container_name,
-            display_progress: false,
+        let mut lowerdir: Vec<PathBuf> =
+            additional_lowerdirs.into_iter().map(|p| p.into()).collect();
+        // overlayfs rejects a layer given twice, whose topmost copy alone shows the same files
+        for layer in image_layers.into_iter().rev().map(|p| p.into()) {
+            if !lowerdir.contains(&layer) {
+                lowerdir.push(layer);
+            }
         }
-    }
 
-    pub fn workdir<T: AsRef<Path>>(mut self, path: T) -> Self {
-        self.workdir_path = path.as_ref().to_path_buf();
-        self
-    }
+        let mount_config = OverlayConfig {
+            mp: mount_point.into(),
+            upperdir: upperdir.into(),
+            lowerdir,
+            workdir: workdir.into(),
+        };
+        create_all_dirs(&mount_config);
 
-    pub fn display_progress(mut self, b: bool) -> Self {
-        self.display_progress = b;
-        self
+        Self { mount_config }
     }
 
-    pub fn download(&self) -> Result<()> {
-        let workdir = self.workdir_path.as_path();
-        if workdir.exists() {
-            remove(&self.workdir_path)
-                .with_context(|| format!("Failed to remove: {}", workdir.display()))?;
+    /// Downloads layers of the image of `reference` for `platform` into `store`,
+    /// skipping ones already there, and gets the config of the image
+    pub fn download(
+        store: &BlobStore,
+        reference: &Reference,
+        platform: Platform,
+    ) -> Result<StoredImage> {
+        let mut image_downloader = ImageDownloader::new(reference, store);
+        image_downloader.platform(platform);
+        image_downloader.pre_download_display(display_pre_download);
+        image_downloader.post_download_display(display_post_download);
+        let image = image_downloader.download()?;
+        for (digest, report) in image.reports.iter() {
+            println!("  {digest}: {report}");
         }
-        create_dir_all(&self.workdir_path)
-            .with_context(|| format!("Failed to create: {}", workdir.display()))?;
-
-        let mut image_downloader =
-            ImageDownloader::new(&self.image_name, &self.image_tag, &self.image_path, workdir);
-        if self.display_progress {
-            image_downloader.pre_download_display(display_pre_download);
-            image_downloader.post_download_display(display_post_download);
-        }
-        let layers = image_downloader.download_from_dockerhub()?;
-        ImageMerger::new(&self.image_path)
-            .add_layers(layers)
-            .merge()?;
-        remove(&self.workdir_path)
-            .with_context(|| format!("Failed to remove: {}", workdir.display()))?;
-
-        Ok(())
-    }
-
-    pub fn create_container_image(&self) -> Result<()> {
-        let container = &self.container_path;
-        let image = &self.image_path;
-        create_dir_all(container)
-            .with_context(|| format!("Failed to create dir: '{}'", container.display()))?;
-        ImageMerger::new(container).add_layer(image).merge()
-    }
-
-    pub fn exists_image(&self) -> bool {
-        self.image_path.exists()
+        Ok(image)
     }
 
-    pub fn container_path(&self) -> PathBuf {
-        self.container_path.clone()
-    }
-
-    pub fn exists_container(&self) -> bool {
-        self.container_path.exists()
-    }
-
-    pub fn remove_image(&self) -> Result<()> {
-        remove(&self.image_path)?;
-        Ok(())
-    }
-
-    pub fn remove_container(&self) -> Result<()> {
-        remove(&self.container_path)?;
-        Ok(())
+    /// Imports layers of an image saved in `source` (an archive or an OCI image layout)
+    /// into the store of `importer`, skipping ones already there
+    pub fn import(mut importer: ImageImporter, source: &Path) -> Result<ImportedImage> {
+        println!("Import container image: {} ", source.display());
+        importer.post_import_display(display_post_import);
+        let imported = importer.import()?;
+        for (digest, report) in imported.image.reports.iter() {
+            println!("  {digest}: {report}");
+        }
+        Ok(imported)
     }
+}
 
-    pub fn container_name(&self) -> String {
-        self.container_name.clone()
+impl ContainerImage for GuestImage {
+    fn mount(&self) -> Result<()> {
+        Mount::new("/", FileType::Dir)
+            .add_flags(MountFlags::MS_PRIVATE)
+            .add_flags(MountFlags::MS_REC)
+            .mount()
+            .context("Failed to make '/' private")?;
+
+        Mount::new(&self.mount_config.mp, FileType::Dir)
+            .fs_type(OVERLAYFS_FSTYPE)
+            .data(self.mount_config.to_option_string().as_str())
+            .mount()
     }
 
-    pub fn image_root(&self) -> PathBuf {
-        self.container_path.clone()
+    fn rootfs_path(&self) -> &Path {
+        self.mount_config.mp.as_path()
     }
 }
 
 fn display_pre_download(name: &str, tag: &str) {
-    println!("Download container image: {}:{} ", name, tag);
+    // `tag` is a digest if the image is pinned to one
+    let separator = if tag.contains(':') { '@' } else { ':' };
+    println!("Download container image: {}{}{} ", name, separator, tag);
 }
 
 fn display_post_download(num_of_layer: usize, downloaded_layer: usize) {
@@ -138,3 +114,11 @@ fn display_post_download(num_of_layer: usize, downloaded_layer: usize) {
     print!("\r\x1b[1A\x1b[K");
     println!("{}/{} layer has downloaded", downloaded_layer, num_of_layer);
 }
+
+fn display_post_import(num_of_layer: usize, imported_layer: usize) {
+    if imported_layer == 0 {
+        println!();
+    }
+    print!("\r\x1b[1A\x1b[K");
+    println!("{}/{} layer has imported", imported_layer, num_of_layer);
+}
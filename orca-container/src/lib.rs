@@ -1,6 +1,7 @@
 pub mod container;
 pub mod image;
 pub mod mount;
+pub mod process;
 
 const STACK_SIZE: usize = 1024 * 1024;
 
@@ -0,0 +1,38 @@
+/// Command run in a container, with the environment it starts in
+#[derive(Debug, Clone)]
+pub struct Process {
+    pub(crate) argv: Vec<String>,
+    pub(crate) env: Vec<String>,
+    pub(crate) cwd: Option<String>,
+    pub(crate) user: Option<String>,
+}
+
+impl Process {
+    /// Runs `argv` as root in `/`, with the environment variables of orca
+    pub fn new(argv: Vec<String>) -> Self {
+        Self {
+            argv,
+            env: vec![],
+            cwd: None,
+            user: None,
+        }
+    }
+
+    /// Sets `KEY=value` variables, overriding the ones inherited from orca
+    pub fn env(mut self, env: Vec<String>) -> Self {
+        self.env = env;
+        self
+    }
+
+    /// Directory to start in, created if it does not exist
+    pub fn cwd<S: Into<String>>(mut self, cwd: S) -> Self {
+        self.cwd = Some(cwd.into());
+        self
+    }
+
+    /// `user[:group]`, each by name or ID, looked up in /etc/passwd and /etc/group of the container
+    pub fn user<S: Into<String>>(mut self, user: S) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+}
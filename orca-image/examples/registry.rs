@@ -0,0 +1,474 @@
+//! Registry standing in for a real one, to try the registry client without network.
+//!
+//! Serves each repository from `<root>/<repository>`, which is an OCI image layout
+//! (`index.json` and `blobs/sha256/...`). Tags are taken from the
+//! `org.opencontainers.image.ref.name` annotation of the manifests in `index.json`.
+//!
+//! Images can be pushed too: blobs are uploaded in one PUT or in PATCHes of chunks
+//! (kept in `<root>/.uploads` until they are complete) or mounted from another repository,
+//! and pushed manifests are tagged in `index.json` of the repository.
+//!
+//! ```text
+//! cargo run -p orca-image --example registry -- <root> [--listen 127.0.0.1:5000]
+//!     [--basic user:password | --bearer user:password]
+//! ```
+//!
+//! With `--basic` or `--bearer`, requests must be authorized by the corresponding scheme.
+//! Bearer tokens are issued by `/token` of the same server.
+//!
+//! `tests/registry.rs` includes this file to run the registry client against it.
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tiny_http::{Header, Method, Request, Response, ResponseBox, Server};
+
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+const TOKEN: &str = "stand-in-token";
+const UPLOADS_DIR: &str = ".uploads";
+
+pub enum Auth {
+    Anonymous,
+    Basic(String),
+    Bearer(String),
+}
+
+pub struct Registry {
+    root: PathBuf,
+    /// Address the server listens on (e.g. '127.0.0.1:5000')
+    pub addr: String,
+    auth: Auth,
+    /// Number of uploads started so far, which names the next one
+    uploads: u64,
+}
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let mut root = None;
+    let mut listen = "127.0.0.1:5000".to_string();
+    let mut auth = Auth::Anonymous;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => listen = args.next().context("--listen needs an address")?,
+            "--basic" => auth = Auth::Basic(args.next().context("--basic needs user:password")?),
+            "--bearer" => auth = Auth::Bearer(args.next().context("--bearer needs user:password")?),
+            _ => root = Some(PathBuf::from(arg)),
+        }
+    }
+    let root =
+        root.context("Usage: registry <root> [--listen addr] [--basic|--bearer user:password]")?;
+
+    let (server, registry) = Registry::bind(root, &listen, auth)?;
+    println!(
+        "Serving '{}' on http://{}",
+        registry.root.display(),
+        registry.addr
+    );
+    registry.serve(&server);
+    Ok(())
+}
+
+impl Registry {
+    /// Listens on `listen`, where port 0 picks a free port (see [`Registry::addr`])
+    pub fn bind(root: PathBuf, listen: &str, auth: Auth) -> Result<(Server, Self)> {
+        let server =
+            Server::http(listen).map_err(|e| anyhow!("Failed to listen on {listen}: {e}"))?;
+        let registry = Registry {
+            root,
+            addr: server.server_addr().to_string(),
+            auth,
+            uploads: 0,
+        };
+        Ok((server, registry))
+    }
+
+    /// Handles requests of `server` one at a time until it is closed
+    pub fn serve(mut self, server: &Server) {
+        for mut request in server.incoming_requests() {
+            let response = match self.handle(&mut request) {
+                Ok(response) => response,
+                Err(e) => Response::from_string(format!("{e:#}"))
+                    .with_status_code(500)
+                    .boxed(),
+            };
+            println!(
+                "{} {} -> {}",
+                request.method(),
+                request.url(),
+                response.status_code().0
+            );
+            let _ = request.respond(response);
+        }
+    }
+
+    fn handle(&mut self, request: &mut Request) -> Result<ResponseBox> {
+        let url = request.url().to_string();
+        if let Some(query) = url.strip_prefix("/token") {
+            return Ok(self.token(request, query));
+        }
+        let Some(path) = url.strip_prefix("/v2/") else {
+            return Ok(not_found(&url));
+        };
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+        let repository = path
+            .rsplit_once("/manifests/")
+            .or_else(|| path.rsplit_once("/blobs/"))
+            .map(|(repository, _)| repository)
+            .unwrap_or_default();
+        if let Some(challenge) = self.challenge(request, repository) {
+            return Ok(Response::from_string("Unauthorized")
+                .with_status_code(401)
+                .with_header(header("WWW-Authenticate", &challenge))
+                .boxed());
+        }
+        if path.is_empty() {
+            return Ok(Response::empty(200).boxed());
+        }
+
+        if let Some((repository, upload)) = path.rsplit_once("/blobs/uploads/") {
+            return match request.method() {
+                Method::Post => self.start_upload(repository, query),
+                Method::Patch => self.upload_chunk(request, repository, upload),
+                Method::Put => self.finish_upload(request, repository, upload, query),
+                _ => Ok(not_found(&url)),
+            };
+        }
+        if let Some((repository, reference)) = path.rsplit_once("/manifests/")
+            && *request.method() == Method::Put
+        {
+            return self.put_manifest(request, repository, reference);
+        }
+        if let Some((repository, reference)) = path.rsplit_once("/manifests/") {
+            let layout = self.layout(repository)?;
+            let Some((digest, media_type)) = find_manifest(&layout, reference)? else {
+                return Ok(not_found(&url));
+            };
+            return serve_blob(&layout, &digest, &media_type);
+        }
+        if let Some((repository, digest)) = path.rsplit_once("/blobs/") {
+            let layout = self.layout(repository)?;
+            return serve_blob(&layout, digest, "application/octet-stream");
+        }
+        Ok(not_found(&url))
+    }
+
+    /// Returns the challenge to answer if `request` is not authorized
+    fn challenge(&self, request: &Request, repository: &str) -> Option<String> {
+        let authorization = header_value(request, "Authorization");
+        match &self.auth {
+            Auth::Anonymous => None,
+            Auth::Basic(credentials) => (authorization != Some(basic(credentials)))
+                .then(|| "Basic realm=\"stand-in\"".to_string()),
+            Auth::Bearer(_) => (authorization != Some(format!("Bearer {TOKEN}"))).then(|| {
+                let actions = match request.method() {
+                    Method::Get | Method::Head => "pull",
+                    _ => "pull,push",
+                };
+                format!(
+                    "Bearer realm=\"http://{}/token\",service=\"stand-in\",\
+                    scope=\"repository:{repository}:{actions}\"",
+                    self.addr
+                )
+            }),
+        }
+    }
+
+    fn token(&self, request: &Request, query: &str) -> ResponseBox {
+        let Auth::Bearer(credentials) = &self.auth else {
+            return not_found(request.url());
+        };
+        let authorized = request.headers().iter().any(|header| {
+            header.field.equiv("Authorization") && header.value == basic(credentials)
+        });
+        if !authorized || !query.contains("scope=") {
+            return Response::from_string("Invalid credentials")
+                .with_status_code(401)
+                .boxed();
+        }
+        Response::from_string(format!("{{\"token\": \"{TOKEN}\"}}"))
+            .with_header(header("Content-Type", "application/json"))
+            .boxed()
+    }
+
+    fn layout(&self, repository: &str) -> Result<PathBuf> {
+        if repository
+            .split('/')
+            .any(|component| component.is_empty() || component == "..")
+        {
+            bail!("Invalid repository: '{repository}'");
+        }
+        Ok(self.root.join(repository))
+    }
+
+    /// Mounts the blob of `mount` from repository `from` if both are in `query` and
+    /// the blob is there, or starts an upload to be continued at the returned location
+    fn start_upload(&mut self, repository: &str, query: &str) -> Result<ResponseBox> {
+        let layout = self.layout(repository)?;
+        if let (Some(digest), Some(from)) = (param(query, "mount"), param(query, "from"))
+            && let Some(hex) = digest.strip_prefix("sha256:")
+            && let Ok(source) = self.layout(&from)
+            && source.join("blobs/sha256").join(hex).is_file()
+        {
+            create_layout(&layout)?;
+            fs::copy(
+                source.join("blobs/sha256").join(hex),
+                layout.join("blobs/sha256").join(hex),
+            )?;
+            return Ok(Response::empty(201)
+                .with_header(header(
+                    "Location",
+                    &format!("/v2/{repository}/blobs/{digest}"),
+                ))
+                .with_header(header("Docker-Content-Digest", &digest))
+                .boxed());
+        }
+
+        self.uploads += 1;
+        let id = format!("{}-{}", std::process::id(), self.uploads);
+        fs::create_dir_all(self.root.join(UPLOADS_DIR))?;
+        File::create(self.root.join(UPLOADS_DIR).join(&id))?;
+        Ok(Response::empty(202)
+            .with_header(header(
+                "Location",
+                &format!("/v2/{repository}/blobs/uploads/{id}"),
+            ))
+            .with_header(header("Range", "0-0"))
+            .boxed())
+    }
+
+    /// Appends a chunk, which must start where the upload has reached
+    fn upload_chunk(
+        &self,
+        request: &mut Request,
+        repository: &str,
+        id: &str,
+    ) -> Result<ResponseBox> {
+        let Some(path) = self.upload_path(id) else {
+            return Ok(not_found(id));
+        };
+        let size = fs::metadata(&path)?.len();
+        let start = header_value(request, "Content-Range")
+            .and_then(|range| range.split_once('-').map(|(start, _)| start.to_string()))
+            .and_then(|start| start.parse::<u64>().ok());
+        if start.is_some_and(|start| start != size) {
+            return Ok(Response::from_string(format!("Upload has reached {size}"))
+                .with_status_code(416)
+                .boxed());
+        }
+        let size = append(request, &path)?;
+        Ok(Response::empty(202)
+            .with_header(header(
+                "Location",
+                &format!("/v2/{repository}/blobs/uploads/{id}"),
+            ))
+            .with_header(header("Range", &format!("0-{}", size.saturating_sub(1))))
+            .boxed())
+    }
+
+    /// Appends the rest of the blob, and moves it into the repository if it has the digest
+    fn finish_upload(
+        &self,
+        request: &mut Request,
+        repository: &str,
+        id: &str,
+        query: &str,
+    ) -> Result<ResponseBox> {
+        let Some(path) = self.upload_path(id) else {
+            return Ok(not_found(id));
+        };
+        let Some(digest) = param(query, "digest") else {
+            bail!("Upload is finished without digest");
+        };
+        append(request, &path)?;
+        let actual = format!("sha256:{:x}", Sha256::digest(fs::read(&path)?));
+        if actual != digest {
+            fs::remove_file(&path)?;
+            return Ok(Response::from_string(format!(
+                "Digest mismatch: {digest} was given, but blob is {actual}"
+            ))
+            .with_status_code(400)
+            .boxed());
+        }
+        let layout = self.layout(repository)?;
+        create_layout(&layout)?;
+        let hex = &actual["sha256:".len()..];
+        fs::rename(&path, layout.join("blobs/sha256").join(hex))?;
+        Ok(Response::empty(201)
+            .with_header(header(
+                "Location",
+                &format!("/v2/{repository}/blobs/{digest}"),
+            ))
+            .with_header(header("Docker-Content-Digest", &digest))
+            .boxed())
+    }
+
+    /// Stores a manifest, and tags it in `index.json` unless it is put by its digest
+    fn put_manifest(
+        &self,
+        request: &mut Request,
+        repository: &str,
+        reference: &str,
+    ) -> Result<ResponseBox> {
+        let media_type = header_value(request, "Content-Type").unwrap_or_default();
+        let mut manifest = vec![];
+        request.as_reader().read_to_end(&mut manifest)?;
+        let digest = format!("sha256:{:x}", Sha256::digest(&manifest));
+        if reference.starts_with("sha256:") && reference != digest {
+            return Ok(
+                Response::from_string(format!("Digest mismatch: manifest is {digest}"))
+                    .with_status_code(400)
+                    .boxed(),
+            );
+        }
+
+        let layout = self.layout(repository)?;
+        create_layout(&layout)?;
+        fs::write(
+            layout.join("blobs/sha256").join(&digest["sha256:".len()..]),
+            &manifest,
+        )?;
+        if !reference.starts_with("sha256:") {
+            let index_path = layout.join("index.json");
+            let mut index: Value = match fs::read_to_string(&index_path) {
+                Ok(index) => serde_json::from_str(&index)?,
+                Err(_) => json!({ "schemaVersion": 2, "manifests": [] }),
+            };
+            let Some(manifests) = index["manifests"].as_array_mut() else {
+                bail!("'{}' has no manifests", index_path.display());
+            };
+            manifests.retain(|manifest| {
+                manifest["annotations"][REF_NAME_ANNOTATION].as_str() != Some(reference)
+            });
+            manifests.push(json!({
+                "mediaType": media_type,
+                "digest": digest,
+                "size": manifest.len(),
+                "annotations": { REF_NAME_ANNOTATION: reference },
+            }));
+            fs::write(&index_path, serde_json::to_vec_pretty(&index)?)?;
+        }
+        Ok(Response::empty(201)
+            .with_header(header(
+                "Location",
+                &format!("/v2/{repository}/manifests/{digest}"),
+            ))
+            .with_header(header("Docker-Content-Digest", &digest))
+            .boxed())
+    }
+
+    /// File of upload `id`, if it has been started
+    fn upload_path(&self, id: &str) -> Option<PathBuf> {
+        let valid = !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit() || b == b'-');
+        let path = self.root.join(UPLOADS_DIR).join(id);
+        (valid && path.is_file()).then_some(path)
+    }
+}
+
+/// Makes `layout` an OCI image layout if it is not yet
+fn create_layout(layout: &Path) -> Result<()> {
+    fs::create_dir_all(layout.join("blobs/sha256"))?;
+    let oci_layout = layout.join("oci-layout");
+    if !oci_layout.exists() {
+        fs::write(oci_layout, "{\"imageLayoutVersion\": \"1.0.0\"}")?;
+    }
+    Ok(())
+}
+
+/// Appends the body of `request` to `path`, and returns the size it has reached
+fn append(request: &mut Request, path: &Path) -> Result<u64> {
+    let mut file = OpenOptions::new().append(true).open(path)?;
+    let mut body = vec![];
+    request.as_reader().read_to_end(&mut body)?;
+    file.write_all(&body)?;
+    Ok(file.metadata()?.len())
+}
+
+/// Percent-decoded value of parameter `name` in `query`
+fn param(query: &str, name: &str) -> Option<String> {
+    let value = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))?;
+    let mut decoded = vec![];
+    let mut bytes = value.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex: String = bytes.by_ref().take(2).map(char::from).collect();
+                decoded.push(u8::from_str_radix(&hex, 16).ok()?);
+            }
+            b'+' => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn header_value(request: &Request, field: &'static str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(field))
+        .map(|header| header.value.to_string())
+}
+
+/// Looks up a manifest by its tag in `index.json`, or by its digest among all blobs
+/// (manifests of multi-platform images are not listed in `index.json`)
+fn find_manifest(layout: &Path, reference: &str) -> Result<Option<(String, String)>> {
+    if let Some(hex) = reference.strip_prefix("sha256:") {
+        let Ok(manifest) = fs::read_to_string(layout.join("blobs/sha256").join(hex)) else {
+            return Ok(None);
+        };
+        let manifest: Value = serde_json::from_str(&manifest)?;
+        let media_type = manifest["mediaType"].as_str().unwrap_or_default();
+        return Ok(Some((reference.to_string(), media_type.to_string())));
+    }
+    let Ok(index) = fs::read_to_string(layout.join("index.json")) else {
+        return Ok(None);
+    };
+    let index: Value = serde_json::from_str(&index)?;
+    let manifests = index["manifests"].as_array().cloned().unwrap_or_default();
+    Ok(manifests.iter().find_map(|manifest| {
+        let ref_name = manifest["annotations"][REF_NAME_ANNOTATION].as_str();
+        (ref_name == Some(reference)).then(|| {
+            let digest = manifest["digest"].as_str().unwrap_or_default();
+            let media_type = manifest["mediaType"].as_str().unwrap_or_default();
+            (digest.to_string(), media_type.to_string())
+        })
+    }))
+}
+
+fn serve_blob(layout: &Path, digest: &str, media_type: &str) -> Result<ResponseBox> {
+    let Some(hex) = digest.strip_prefix("sha256:") else {
+        return Ok(not_found(digest));
+    };
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Ok(not_found(digest));
+    }
+    let Ok(file) = File::open(layout.join("blobs/sha256").join(hex)) else {
+        return Ok(not_found(digest));
+    };
+    // tiny_http leaves out the body for HEAD
+    Ok(Response::from_file(file)
+        .with_header(header("Content-Type", media_type))
+        .with_header(header("Docker-Content-Digest", digest))
+        .boxed())
+}
+
+fn basic(credentials: &str) -> String {
+    format!("Basic {}", STANDARD.encode(credentials))
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field, value).expect("Header should be ASCII")
+}
+
+fn not_found(what: &str) -> ResponseBox {
+    Response::from_string(format!("Not found: {what}"))
+        .with_status_code(404)
+        .boxed()
+}
@@ -0,0 +1,93 @@
+use crate::platform::Platform;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Defaults for running containers of an image, the `config` object of its config blob.
+/// Fields missing from the image (or `null` in it) are `None`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "PascalCase")]
+pub struct ImageConfig {
+    /// Environment variables as `KEY=value`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmd: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<String>,
+    /// `user[:group]`, each by name or ID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+/// Config blob of an image, of which only the runtime defaults, the platform and the layers
+/// are read or written
+#[derive(Serialize, Deserialize)]
+struct ConfigBlob {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    architecture: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    os: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    variant: Option<String>,
+    #[serde(default)]
+    config: Option<ImageConfig>,
+    #[serde(default, skip_deserializing)]
+    rootfs: Option<RootFs>,
+}
+
+#[derive(Serialize)]
+struct RootFs {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// Digests of the uncompressed layers from the bottom one
+    diff_ids: Vec<String>,
+}
+
+impl ImageConfig {
+    /// Parses the config blob of an image (`application/vnd.oci.image.config.v1+json`)
+    pub fn from_blob(blob: &[u8]) -> Result<Self> {
+        let blob: ConfigBlob = serde_json::from_slice(blob).context("Invalid image config")?;
+        Ok(blob.config.unwrap_or_default())
+    }
+
+    /// Platform the config blob of an image says it is built for
+    pub fn platform_of_blob(blob: &[u8]) -> Result<Option<Platform>> {
+        let blob: ConfigBlob = serde_json::from_slice(blob).context("Invalid image config")?;
+        Ok(match (blob.os, blob.architecture) {
+            (Some(os), Some(architecture)) => Some(Platform {
+                os,
+                architecture,
+                variant: blob.variant,
+            }),
+            _ => None,
+        })
+    }
+
+    /// Writes a config blob of an image for `platform` made of layers whose uncompressed
+    /// tarballs have `diff_ids` (from the bottom one)
+    pub fn to_blob(&self, platform: &Platform, diff_ids: Vec<String>) -> Result<Vec<u8>> {
+        let blob = ConfigBlob {
+            architecture: Some(platform.architecture.clone()),
+            os: Some(platform.os.clone()),
+            variant: platform.variant.clone(),
+            config: Some(self.clone()),
+            rootfs: Some(RootFs {
+                kind: "layers",
+                diff_ids,
+            }),
+        };
+        Ok(serde_json::to_vec(&blob)?)
+    }
+
+    /// Entrypoint followed by Cmd, which is what a container runs by default
+    pub fn command(&self) -> Vec<String> {
+        self.entrypoint
+            .iter()
+            .chain(self.cmd.iter())
+            .flatten()
+            .cloned()
+            .collect()
+    }
+}
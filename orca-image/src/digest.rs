@@ -0,0 +1,218 @@
+use sha2::{Digest, Sha256, Sha512};
+use std::io::{self, Read, Write};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Digest of blob does not match: expected {expected}, got {actual}")]
+    DigestMismatch { expected: String, actual: String },
+
+    #[error("Size of blob {digest} does not match: expected {expected} bytes, got {actual}")]
+    SizeMismatch {
+        digest: String,
+        expected: u64,
+        actual: u64,
+    },
+
+    #[error("Unsupported digest: '{0}'")]
+    UnsupportedDigest(String),
+
+    #[error("Failed to read blob")]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    /// Hasher for the algorithm of `digest` (e.g. 'sha256:...')
+    fn for_digest(digest: &str) -> Result<Self> {
+        let valid_hex =
+            |hex: &str, len: usize| hex.len() == len && hex.bytes().all(|b| b.is_ascii_hexdigit());
+        match digest.split_once(':') {
+            Some(("sha256", hex)) if valid_hex(hex, 64) => Ok(Self::Sha256(Sha256::new())),
+            Some(("sha512", hex)) if valid_hex(hex, 128) => Ok(Self::Sha512(Sha512::new())),
+            _ => Err(Error::UnsupportedDigest(digest.to_string())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finalize(self) -> String {
+        match self {
+            Self::Sha256(hasher) => format!("sha256:{:x}", hasher.finalize()),
+            Self::Sha512(hasher) => format!("sha512:{:x}", hasher.finalize()),
+        }
+    }
+}
+
+/// Reader hashing and counting a blob while it streams through.
+///
+/// Reading more than the expected size fails at once. The digest can only be
+/// checked at the end, by [`VerifyingReader::finish`].
+pub struct VerifyingReader<R> {
+    inner: R,
+    digest: String,
+    size: u64,
+    hasher: Hasher,
+    read: u64,
+}
+
+impl<R: Read> VerifyingReader<R> {
+    /// Verifies that `inner` yields `size` bytes hashed to `digest`
+    pub fn new(inner: R, digest: &str, size: u64) -> Result<Self> {
+        Ok(Self {
+            inner,
+            digest: digest.to_string(),
+            size,
+            hasher: Hasher::for_digest(digest)?,
+            read: 0,
+        })
+    }
+
+    /// Reads the rest of the blob, which its consumer may have left, and checks it
+    pub fn finish(mut self) -> Result<()> {
+        let drained = io::copy(&mut self, &mut io::sink());
+        if self.read != self.size {
+            return Err(self.size_mismatch());
+        }
+        drained?;
+        let actual = self.hasher.finalize();
+        if actual != self.digest {
+            return Err(Error::DigestMismatch {
+                expected: self.digest,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    fn size_mismatch(&self) -> Error {
+        Error::SizeMismatch {
+            digest: self.digest.clone(),
+            expected: self.size,
+            actual: self.read,
+        }
+    }
+}
+
+impl<R: Read> Read for VerifyingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        if self.read > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                self.size_mismatch(),
+            ));
+        }
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Checks a blob already read into memory, such as a manifest
+pub fn verify(data: &[u8], digest: &str) -> Result<()> {
+    VerifyingReader::new(data, digest, data.len() as u64)?.finish()
+}
+
+/// Writer computing the sha256 digest and the size of a blob while it is written
+pub struct DigestWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    written: u64,
+}
+
+impl<W: Write> DigestWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            written: 0,
+        }
+    }
+
+    /// Returns `inner` with the digest (e.g. 'sha256:...') and the size of what has been written
+    pub fn finish(self) -> (W, String, u64) {
+        let digest = format!("sha256:{:x}", self.hasher.finalize());
+        (self.inner, digest, self.written)
+    }
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256(data: &[u8]) -> String {
+        format!("sha256:{:x}", Sha256::digest(data))
+    }
+
+    #[test]
+    fn accepts_matching_blobs() {
+        let digest = sha256(b"blob");
+        let mut reader = VerifyingReader::new(&b"blob"[..], &digest, 4).unwrap();
+        let mut head = [0; 2];
+        reader.read_exact(&mut head).unwrap();
+        // The rest left by the consumer is drained and hashed too
+        reader.finish().unwrap();
+
+        let digest = format!("sha512:{:x}", Sha512::digest(b"blob"));
+        verify(b"blob", &digest).unwrap();
+    }
+
+    #[test]
+    fn rejects_blobs_not_matching_digests() {
+        let digest = sha256(b"blob");
+        let result = VerifyingReader::new(&b"blub"[..], &digest, 4)
+            .unwrap()
+            .finish();
+        assert!(matches!(result, Err(Error::DigestMismatch { .. })));
+    }
+
+    #[test]
+    fn rejects_blobs_not_matching_sizes() {
+        let digest = sha256(b"blob");
+        let result = VerifyingReader::new(&b"blo"[..], &digest, 4)
+            .unwrap()
+            .finish();
+        assert!(matches!(result, Err(Error::SizeMismatch { actual: 3, .. })));
+
+        // Reading past the expected size fails without waiting for the end
+        let mut reader = VerifyingReader::new(&b"blob!"[..], &digest, 4).unwrap();
+        let error = io::copy(&mut reader, &mut io::sink()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(reader.finish(), Err(Error::SizeMismatch { .. })));
+    }
+
+    #[test]
+    fn rejects_unsupported_digests() {
+        for digest in ["md5:abc", "sha256:xyz", "sha256", &sha256(b"blob")[..20]] {
+            assert!(matches!(
+                VerifyingReader::new(&b""[..], digest, 0),
+                Err(Error::UnsupportedDigest(_))
+            ));
+        }
+    }
+}
@@ -1,83 +1,51 @@
 extern crate anyhow;
-extern crate flate2;
 extern crate reqwest;
-extern crate rm_rf;
 extern crate serde;
 extern crate tar;
 
+use crate::config::ImageConfig;
+use crate::digest::{self, VerifyingReader};
+use crate::layer::{Compression, ExtractReport};
+use crate::manifest::{
+    select_manifest, Descriptor, Manifest, ManifestOrIndex, DOCKER_MANIFEST, MANIFEST_MEDIA_TYPES,
+    OCI_MANIFEST,
+};
+use crate::platform::Platform;
+use crate::reference::Reference;
+use crate::registry::RegistryClient;
+use crate::store::{BlobStore, StoredImage};
 use anyhow::{bail, Context, Result};
-use flate2::bufread::MultiGzDecoder;
-use reqwest::blocking::{Client, Response};
-use reqwest::{header, IntoUrl};
-use serde::{Deserialize, Serialize};
-use std::fmt::Display;
-use std::fs::create_dir_all;
-use std::io::{BufReader, Read};
-use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::io::Read;
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
-use tar::Archive;
-
-#[derive(Serialize, Deserialize)]
-struct Token {
-    token: String,
-    access_token: String,
-    expires_in: u32,
-    issued_at: String,
-}
-
-#[derive(Serialize, Deserialize)]
-#[allow(non_snake_case)]
-struct Layer {
-    mediaType: String,
-    size: usize,
-    digest: String,
-}
-
-#[derive(Serialize, Deserialize)]
-#[allow(non_snake_case)]
-struct Manifest {
-    schemaVersion: usize,
-    mediaType: String,
-    config: Layer,
-    layers: Vec<Layer>,
-}
-
-#[derive(Serialize, Deserialize)]
-struct Config {}
 
 pub struct ImageDownloader {
-    image_name: String,
-    image_tag: String,
-    store_path: PathBuf,
-    workdir_prefix: PathBuf,
+    reference: Reference,
+    platform: Platform,
+    store: BlobStore,
     pre_download_fn: Option<fn(&str, &str)>,
     post_download_fn: Option<fn(usize, usize)>,
 }
 
 impl ImageDownloader {
-    pub fn new<S, T, U, V>(image_name: S, image_tag: T, store_path: U, workdir_prefix: V) -> Self
-    where
-        S: ToString + AsRef<str>,
-        T: ToString,
-        U: AsRef<Path>,
-        V: AsRef<Path>,
-    {
-        let image_name = if !image_name.as_ref().contains('/') {
-            format!("library/{}", image_name.as_ref())
-        } else {
-            image_name.to_string()
-        };
+    pub fn new(reference: &Reference, store: &BlobStore) -> Self {
         Self {
-            image_name,
-            image_tag: image_tag.to_string(),
-            store_path: store_path.as_ref().to_path_buf(),
-            workdir_prefix: workdir_prefix.as_ref().to_path_buf(),
+            reference: reference.clone(),
+            platform: Platform::host(),
+            store: store.clone(),
             pre_download_fn: None,
             post_download_fn: None,
         }
     }
 
+    /// Platform chosen from multi-platform images, the one of the host by default
+    pub fn platform(&mut self, platform: Platform) -> &mut Self {
+        self.platform = platform;
+        self
+    }
+
     pub fn pre_download_display(&mut self, f: fn(&str, &str)) -> &mut Self {
         self.pre_download_fn = Some(f);
         self
@@ -88,165 +56,166 @@ impl ImageDownloader {
         self
     }
 
-    pub fn download_from_dockerhub(self) -> Result<Vec<PathBuf>> {
+    /// Downloads the config of the image and its layers which are not in the store yet.
+    /// The layers are counted as used by one more environment, see [`StoredImage`].
+    pub fn download(self) -> Result<StoredImage> {
         if let Some(f) = self.pre_download_fn {
-            f(&self.image_name, &self.image_tag);
+            f(&self.reference.name(), self.reference.manifest_reference());
         }
 
-        let token_url = format!(
-            "https://auth.docker.io/token?service=registry.docker.io&scope=repository:{}:pull",
-            self.image_name
-        );
-        let token = get_bearer_token(&token_url)?;
-
-        let manifest_url = format!(
-            "https://registry-1.docker.io/v2/{}/manifests/{}",
-            self.image_name, self.image_tag
-        );
-        let layer_ids = get_layer_ids(&manifest_url, &token)?;
-
-        create_dir_all(&self.workdir_prefix).with_context(|| {
-            format!(
-                "Failed to create dir: '{}'",
-                &self.workdir_prefix.as_path().display()
-            )
-        })?;
-        create_dir_all(&self.store_path).with_context(|| {
-            format!(
-                "Failed to create dir: '{}'",
-                &self.store_path.as_path().display()
-            )
-        })?;
-
-        let mut threads: Vec<JoinHandle<Result<PathBuf>>> = Vec::new();
-        let token = Arc::new(token);
-        let num_of_layer: usize = layer_ids.len();
+        let client = RegistryClient::new(&self.reference);
+        let manifest = get_manifest(&client, self.reference.manifest_reference(), &self.platform)?;
+        let config = get_config(&client, &manifest.config)?;
+        let digests: Vec<String> = manifest
+            .layers
+            .iter()
+            .map(|layer| layer.digest.clone())
+            .collect();
+
+        // Counted before looking into the store, so that pruning it meanwhile never removes
+        // layers found there or being added
+        self.store.add_refs(&digests)?;
+        match self.download_layers(client, manifest.layers) {
+            Ok(reports) => Ok(StoredImage {
+                layers: digests,
+                config,
+                reports,
+            }),
+            Err(e) => {
+                let _ = self.store.release(&digests);
+                Err(e)
+            }
+        }
+    }
+
+    /// Downloads `layers` which are not in the store yet
+    fn download_layers(
+        &self,
+        client: RegistryClient,
+        layers: Vec<Descriptor>,
+    ) -> Result<Vec<(String, ExtractReport)>> {
+        let num_of_layer: usize = layers.len();
+
+        // Layers shared with other images, or repeated in this one, are downloaded once
+        let mut seen = HashSet::new();
+        let missing: Vec<Descriptor> = layers
+            .into_iter()
+            .filter(|layer| !self.store.has_layer(&layer.digest))
+            .filter(|layer| seen.insert(layer.digest.clone()))
+            .collect();
+
+        // Unsupported layers fail before anything is downloaded
+        let missing = missing
+            .into_iter()
+            .map(|layer| {
+                let compression = Compression::from_media_type(&layer.mediaType)
+                    .with_context(|| format!("Failed to download layer {}", layer.digest))?;
+                Ok((layer, compression))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut threads: Vec<JoinHandle<Result<(String, ExtractReport)>>> = Vec::new();
+        let client = Arc::new(client);
+        let num_of_cached = num_of_layer - missing.len();
 
         if let Some(f) = self.post_download_fn {
             f(num_of_layer, 0);
+            if num_of_cached > 0 {
+                f(num_of_layer, num_of_cached);
+            }
         }
 
-        for (i, layer_id) in layer_ids.iter().enumerate() {
-            let token = Arc::clone(&token);
-            let layer_id = layer_id.clone();
-            let dir_name = format!("image_{}", i);
-            let store_path = self.workdir_prefix.clone().join(dir_name);
-            let image_url = format!(
-                "https://registry-1.docker.io/v2/{}/blobs/{}",
-                self.image_name, layer_id
-            );
+        for (layer, compression) in missing {
+            let client = Arc::clone(&client);
+            let store = self.store.clone();
 
             let join_handle = thread::spawn(move || {
-                let layer_tar_gz = download_layer_tarball(&image_url, token)?;
-                extract(layer_tar_gz, &store_path)?;
-                Ok(store_path)
+                let blob = client
+                    .blob(&layer.digest)
+                    .context("Failed to donwload image")?;
+                let report = store.add_layer(&layer.digest, layer.size, compression, blob)?;
+                Ok((layer.digest, report))
             });
 
             threads.push(join_handle);
         }
 
-        let mut layers: Vec<PathBuf> = Vec::new();
+        let mut reports = vec![];
         for (i, thread) in threads.into_iter().enumerate() {
-            let store_path = match thread.join() {
-                Ok(res) => res?,
+            match thread.join() {
+                Ok(res) => reports.push(res?),
                 Err(_) => bail!("Thread has abended"),
             };
             if let Some(f) = self.post_download_fn {
-                f(num_of_layer, i + 1);
+                f(num_of_layer, num_of_cached + i + 1);
             }
-            layers.push(store_path);
         }
-
-        Ok(layers)
+        Ok(reports)
     }
 }
 
-fn get_bearer_token<T: IntoUrl>(url: T) -> Result<String> {
-    let client = Client::new();
+/// Resolves `reference` into the digest of its manifest (e.g. "sha256:..."),
+/// which keeps pointing at the same image even if the tag is moved
+pub fn resolve_digest(reference: &Reference) -> Result<String> {
+    let client = RegistryClient::new(reference);
     let res = client
-        .get(url)
-        .send()
-        .context("Failed to get bearer token")?
-        .text()
-        .context("Failed to convert response to string")?;
-    let res_token: std::result::Result<Token, serde_json::Error> = serde_json::from_str(&res);
-    if res_token.is_err() {
-        let errors: serde_json::Value =
-            serde_json::from_str(&res).context("Failed to convert errors into json")?;
-        bail!("token returns errors:\n{}", errors)
+        .manifest(reference.manifest_reference(), &MANIFEST_MEDIA_TYPES, true)
+        .context("Failed to get image manifest")?;
+    if let Some(digest) = res
+        .headers()
+        .get("Docker-Content-Digest")
+        .and_then(|digest| digest.to_str().ok())
+    {
+        return Ok(digest.to_string());
     }
-    Ok(res_token.unwrap().token)
-}
 
-fn get_layer_ids<S: IntoUrl, T: Display>(url: S, token: T) -> Result<Vec<String>> {
-    let client = Client::new();
-    let res = client
-        .get(url)
-        .header(
-            header::ACCEPT,
-            "application/vnd.docker.distribution.manifest.v2+json",
-        )
-        .bearer_auth(token)
-        .send()
-        .context("Failed to get image id")?
-        .text()
-        .context("Failed to convert resopnse to string")?;
-
-    let res_manifest: std::result::Result<Manifest, serde_json::Error> = serde_json::from_str(&res);
-    if res_manifest.is_err() {
-        let errors: serde_json::Value =
-            serde_json::from_str(&res).context("Failed to convert errors into json")?;
-        bail!("manifest returns errors:\n{}", errors)
-    }
-    let layers_ids: Vec<String> = res_manifest
-        .unwrap()
-        .layers
-        .into_iter()
-        .map(|layer| layer.digest)
-        .collect();
-    Ok(layers_ids)
+    // The header is optional, then the digest is computed from the manifest itself
+    let manifest = client
+        .manifest(reference.manifest_reference(), &MANIFEST_MEDIA_TYPES, false)?
+        .bytes()
+        .context("Failed to get image manifest")?;
+    Ok(format!("sha256:{:x}", Sha256::digest(&manifest)))
 }
 
-#[allow(dead_code)]
-fn get_config<S: Display, T: IntoUrl>(token: S, url: T) -> Result<Config> {
-    let client = reqwest::blocking::Client::new();
-    let resp = client
-        .get(url)
-        .bearer_auth(token)
-        .send()
-        .unwrap()
-        .text()
-        .unwrap();
-    let _config: serde_json::Value = serde_json::from_str(&resp).unwrap();
-    // let config = parse(config);
-    Ok(Config {})
+/// Gets the manifest of `reference`. If it is an index, the manifest for `platform` in it is got.
+fn get_manifest(client: &RegistryClient, reference: &str, platform: &Platform) -> Result<Manifest> {
+    let res = get_manifest_bytes(client, reference, &MANIFEST_MEDIA_TYPES)?;
+    let index = match ManifestOrIndex::parse(&res)? {
+        ManifestOrIndex::Manifest(manifest) => return Ok(manifest),
+        ManifestOrIndex::Index(index) => index,
+    };
+    let entry = select_manifest(&index, platform)?;
+    let manifest = get_manifest_bytes(client, &entry.digest, &[DOCKER_MANIFEST, OCI_MANIFEST])?;
+    serde_json::from_slice(&manifest).context("Invalid image manifest")
 }
 
-fn download_layer_tarball<S, T>(url: S, token: T) -> Result<Response>
-where
-    S: IntoUrl,
-    T: Display,
-{
-    let client = reqwest::blocking::Client::new();
-    let resp = client
-        .get(url)
-        .bearer_auth(token)
-        .send()
-        .context("Failed to donwload image")?;
-
-    Ok(resp)
+/// Gets the manifest of `reference`, verified if `reference` is a digest
+fn get_manifest_bytes(
+    client: &RegistryClient,
+    reference: &str,
+    accept: &[&str],
+) -> Result<Vec<u8>> {
+    let res = client
+        .manifest(reference, accept, false)
+        .context("Failed to get image manifest")?
+        .bytes()
+        .context("Failed to get image manifest")?;
+    if reference.contains(':') {
+        digest::verify(&res, reference)?;
+    }
+    Ok(res.to_vec())
 }
 
-fn extract<S, T>(tar_gz: S, dest: T) -> Result<()>
-where
-    S: Read,
-    T: AsRef<Path>,
-{
-    let reader = BufReader::new(tar_gz);
-    let tar = MultiGzDecoder::new(reader);
-    let mut archive = Archive::new(tar);
-    archive
-        .unpack(dest.as_ref())
-        .context("Failed to unpack tarball")?;
-    Ok(())
+/// Gets the config blob described by `descriptor`, verified before it is parsed
+fn get_config(client: &RegistryClient, descriptor: &Descriptor) -> Result<ImageConfig> {
+    let res = client
+        .blob(&descriptor.digest)
+        .context("Failed to get image config")?;
+    let mut blob = VerifyingReader::new(res, &descriptor.digest, descriptor.size)?;
+    let mut config = vec![];
+    let read = blob.read_to_end(&mut config);
+    // A broken blob makes reading fail too, but its digest tells why
+    blob.finish()?;
+    read.context("Failed to get image config")?;
+    ImageConfig::from_blob(&config)
 }
@@ -0,0 +1,319 @@
+use crate::config::ImageConfig;
+use crate::digest::DigestWriter;
+use crate::layer::{pack_layer, Compression};
+use crate::manifest::{
+    Descriptor, DockerArchiveEntry, Index, IndexEntry, Manifest, DOCKER_ARCHIVE_MANIFEST,
+    OCI_CONFIG, OCI_INDEX, OCI_LAYOUT_FILE, OCI_LAYOUT_INDEX, OCI_LAYOUT_VERSION, OCI_MANIFEST,
+    REF_NAME_ANNOTATION,
+};
+use crate::platform::Platform;
+use anyhow::{bail, Context, Result};
+use flate2::write::GzEncoder;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use tar::{Builder, HeaderMode};
+
+const BLOBS_DIR_NAME: &str = "blobs";
+
+/// Layer of an image to export
+pub enum ExportLayer {
+    /// Layer tarball written as it is (e.g. a layer of the base image kept in a `BlobStore`)
+    Blob(PathBuf),
+    /// Directory of a layer in the format of overlayfs (e.g. the layer of a commit)
+    Dir(PathBuf),
+}
+
+/// Image written by [`ImageExporter`]
+pub struct ExportedImage {
+    /// Digest of the manifest
+    pub digest: String,
+    pub manifest: Manifest,
+    /// Manifest as written, whose digest is `digest`
+    pub manifest_bytes: Vec<u8>,
+}
+
+/// Writes layers with a generated config and manifest as an OCI image, either into an OCI
+/// image layout directory or into a tarball which `docker load` reads.
+///
+/// Layers given as directories are converted from the format of overlayfs into the one of
+/// OCI (see [`pack_layer`]) and compressed with gzip.
+pub struct ImageExporter {
+    layers: Vec<ExportLayer>,
+    config: ImageConfig,
+    platform: Platform,
+    tag: Option<String>,
+    post_export_fn: Option<fn(usize, usize)>,
+}
+
+impl ImageExporter {
+    /// Exporter of an image running with `config` on `platform`
+    pub fn new(config: ImageConfig, platform: Platform) -> Self {
+        Self {
+            layers: vec![],
+            config,
+            platform,
+            tag: None,
+            post_export_fn: None,
+        }
+    }
+
+    /// Adds a layer on top of the ones added before
+    pub fn add_layer(&mut self, layer: ExportLayer) -> &mut Self {
+        self.layers.push(layer);
+        self
+    }
+
+    /// Name of the image (e.g. 'tools/app:1.0'), recorded as the
+    /// `org.opencontainers.image.ref.name` annotation and as RepoTags of `docker load`
+    pub fn tag(&mut self, tag: &str) -> &mut Self {
+        self.tag = Some(tag.to_string());
+        self
+    }
+
+    pub fn post_export_display(&mut self, f: fn(usize, usize)) -> &mut Self {
+        self.post_export_fn = Some(f);
+        self
+    }
+
+    /// Writes the image into `dir`, which must not exist or be empty
+    pub fn write_oci_layout<P: AsRef<Path>>(&self, dir: P) -> Result<ExportedImage> {
+        let dir = dir.as_ref();
+        if fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some()) {
+            bail!("'{}' already exists and is not empty", dir.display());
+        }
+        let blobs_dir = dir.join(BLOBS_DIR_NAME).join("sha256");
+        fs::create_dir_all(&blobs_dir)
+            .with_context(|| format!("Failed to create dir: '{}'", blobs_dir.display()))?;
+
+        let num_of_layer = self.layers.len();
+        if let Some(f) = self.post_export_fn {
+            f(num_of_layer, 0);
+        }
+        let mut layers = vec![];
+        let mut diff_ids = vec![];
+        for (i, layer) in self.layers.iter().enumerate() {
+            let (descriptor, diff_id) = match layer {
+                ExportLayer::Blob(path) => copy_layer_blob(path, &blobs_dir)?,
+                ExportLayer::Dir(path) => write_layer_dir(path, &blobs_dir)?,
+            };
+            layers.push(descriptor);
+            diff_ids.push(diff_id);
+            if let Some(f) = self.post_export_fn {
+                f(num_of_layer, i + 1);
+            }
+        }
+
+        let config = self.config.to_blob(&self.platform, diff_ids)?;
+        let manifest = Manifest {
+            schemaVersion: 2,
+            mediaType: Some(OCI_MANIFEST.to_string()),
+            config: write_blob(&blobs_dir, OCI_CONFIG, &config)?,
+            layers,
+        };
+        let manifest_bytes = serde_json::to_vec(&manifest)?;
+        let manifest_descriptor = write_blob(&blobs_dir, OCI_MANIFEST, &manifest_bytes)?;
+
+        let mut annotations = BTreeMap::new();
+        if let Some(tag) = &self.tag {
+            annotations.insert(REF_NAME_ANNOTATION.to_string(), tag.clone());
+        }
+        let index = Index {
+            schemaVersion: 2,
+            mediaType: Some(OCI_INDEX.to_string()),
+            manifests: vec![IndexEntry {
+                mediaType: Some(OCI_MANIFEST.to_string()),
+                size: manifest_descriptor.size,
+                digest: manifest_descriptor.digest.clone(),
+                platform: Some(self.platform.clone()),
+                annotations,
+            }],
+        };
+        fs::write(dir.join(OCI_LAYOUT_INDEX), serde_json::to_vec(&index)?)?;
+        fs::write(
+            dir.join(OCI_LAYOUT_FILE),
+            format!("{{\"imageLayoutVersion\":\"{OCI_LAYOUT_VERSION}\"}}"),
+        )?;
+
+        Ok(ExportedImage {
+            digest: manifest_descriptor.digest,
+            manifest,
+            manifest_bytes,
+        })
+    }
+
+    /// Writes the image into a tarball at `file_path`, which `docker load` reads
+    /// (by its manifest.json) as well as tools reading OCI image layouts
+    pub fn write_docker_archive<P: AsRef<Path>>(&self, file_path: P) -> Result<ExportedImage> {
+        let file_path = file_path.as_ref();
+        let file_name = file_path
+            .file_name()
+            .with_context(|| format!("Invalid path: '{}'", file_path.display()))?;
+        // The layout is staged next to the tarball, where there is room for it
+        let mut staging_name = std::ffi::OsString::from(".");
+        staging_name.push(file_name);
+        staging_name.push(format!(".{}.tmp", std::process::id()));
+        let staging_dir = file_path.with_file_name(staging_name);
+
+        let result = self.write_docker_archive_via(&staging_dir, file_path);
+        if staging_dir.exists() {
+            fs::remove_dir_all(&staging_dir)
+                .with_context(|| format!("Failed to remove: '{}'", staging_dir.display()))?;
+        }
+        result
+    }
+
+    fn write_docker_archive_via(
+        &self,
+        staging_dir: &Path,
+        file_path: &Path,
+    ) -> Result<ExportedImage> {
+        let exported = self.write_oci_layout(staging_dir)?;
+        let blob_path = |descriptor: &Descriptor| -> Result<String> {
+            match descriptor.digest.split_once(':') {
+                Some((algorithm, hex)) => Ok(format!("{BLOBS_DIR_NAME}/{algorithm}/{hex}")),
+                None => bail!("Invalid digest: '{}'", descriptor.digest),
+            }
+        };
+        let entry = DockerArchiveEntry {
+            config: blob_path(&exported.manifest.config)?,
+            repo_tags: self.tag.as_deref().map(|tag| vec![repo_tag(tag)]),
+            layers: exported
+                .manifest
+                .layers
+                .iter()
+                .map(blob_path)
+                .collect::<Result<_>>()?,
+        };
+        fs::write(
+            staging_dir.join(DOCKER_ARCHIVE_MANIFEST),
+            serde_json::to_vec(&[entry])?,
+        )?;
+
+        let file = File::create(file_path)
+            .with_context(|| format!("Failed to create: '{}'", file_path.display()))?;
+        let mut builder = Builder::new(file);
+        builder.mode(HeaderMode::Deterministic);
+        for name in [
+            OCI_LAYOUT_FILE,
+            OCI_LAYOUT_INDEX,
+            DOCKER_ARCHIVE_MANIFEST,
+            BLOBS_DIR_NAME,
+        ] {
+            let path = staging_dir.join(name);
+            if path.is_dir() {
+                builder.append_dir_all(name, &path)?;
+            } else {
+                builder.append_path_with_name(&path, name)?;
+            }
+        }
+        builder.into_inner()?.sync_all()?;
+        Ok(exported)
+    }
+}
+
+/// Copies a layer tarball into `blobs_dir`, and returns its descriptor and diff ID
+fn copy_layer_blob(path: &Path, blobs_dir: &Path) -> Result<(Descriptor, String)> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open: '{}'", path.display()))?;
+    let mut magic = [0; 4];
+    let read = file.read(&mut magic)?;
+    let compression = Compression::detect(&magic[..read]);
+    let file = File::open(path)?;
+
+    let tmp_path = blobs_dir.join(format!(".layer.{}.tmp", std::process::id()));
+    let mut blob = DigestWriter::new(File::create(&tmp_path)?);
+    let mut diff = DigestWriter::new(io::sink());
+    // The blob is copied and decompressed for its diff ID in one pass
+    let mut reader = TeeReader {
+        inner: BufReader::new(file),
+        copy: &mut blob,
+    };
+    io::copy(
+        &mut compression.decoder(BufReader::new(&mut reader))?,
+        &mut diff,
+    )
+    .with_context(|| format!("Broken layer: '{}'", path.display()))?;
+    // Bytes after the end of the compressed stream, if any, are still part of the blob
+    io::copy(&mut reader, &mut io::sink())?;
+    let (file, digest, size) = blob.finish();
+    file.sync_all()?;
+    let (_, diff_id, _) = diff.finish();
+
+    rename_blob(&tmp_path, blobs_dir, &digest)?;
+    let descriptor = Descriptor {
+        mediaType: compression.media_type().to_string(),
+        size,
+        digest,
+    };
+    Ok((descriptor, diff_id))
+}
+
+/// Packs a layer directory into a gzip compressed blob in `blobs_dir`,
+/// and returns its descriptor and diff ID
+fn write_layer_dir(dir: &Path, blobs_dir: &Path) -> Result<(Descriptor, String)> {
+    let tmp_path = blobs_dir.join(format!(".layer.{}.tmp", std::process::id()));
+    let blob = DigestWriter::new(File::create(&tmp_path)?);
+    let encoder = GzEncoder::new(blob, flate2::Compression::default());
+    let diff = pack_layer(dir, DigestWriter::new(encoder))
+        .with_context(|| format!("Failed to pack layer: '{}'", dir.display()))?;
+    let (encoder, diff_id, _) = diff.finish();
+    let (file, digest, size) = encoder.finish()?.finish();
+    file.sync_all()?;
+
+    rename_blob(&tmp_path, blobs_dir, &digest)?;
+    let descriptor = Descriptor {
+        mediaType: Compression::Gzip.media_type().to_string(),
+        size,
+        digest,
+    };
+    Ok((descriptor, diff_id))
+}
+
+/// Writes `bytes` into `blobs_dir` and returns its descriptor
+fn write_blob(blobs_dir: &Path, media_type: &str, bytes: &[u8]) -> Result<Descriptor> {
+    let mut writer = DigestWriter::new(io::sink());
+    writer.write_all(bytes)?;
+    let (_, digest, size) = writer.finish();
+    fs::write(blob_file(blobs_dir, &digest), bytes)?;
+    Ok(Descriptor {
+        mediaType: media_type.to_string(),
+        size,
+        digest,
+    })
+}
+
+fn rename_blob(tmp_path: &Path, blobs_dir: &Path, digest: &str) -> Result<()> {
+    let path = blob_file(blobs_dir, digest);
+    fs::rename(tmp_path, &path).with_context(|| format!("Failed to write: '{}'", path.display()))
+}
+
+/// Path of a sha256 blob in `blobs_dir` ('blobs/sha256')
+fn blob_file(blobs_dir: &Path, digest: &str) -> PathBuf {
+    blobs_dir.join(digest.trim_start_matches("sha256:"))
+}
+
+/// `tag` as RepoTags of `docker load` want, which always have a tag (e.g. 'app' is 'app:latest')
+fn repo_tag(tag: &str) -> String {
+    let name = tag.rsplit('/').next().unwrap_or(tag);
+    if name.contains(':') {
+        tag.to_string()
+    } else {
+        format!("{tag}:latest")
+    }
+}
+
+/// Reader copying what is read from `inner` into `copy`
+struct TeeReader<'a, R, W> {
+    inner: R,
+    copy: &'a mut W,
+}
+
+impl<R: Read, W: Write> Read for TeeReader<'_, R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.copy.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
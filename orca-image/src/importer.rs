@@ -0,0 +1,755 @@
+use crate::config::ImageConfig;
+use crate::digest;
+use crate::layer::{Compression, ExtractReport};
+use crate::manifest::{
+    select_manifest, Descriptor, DockerArchiveEntry, Index, IndexEntry, Manifest, ManifestOrIndex,
+    DOCKER_ARCHIVE_MANIFEST, OCI_LAYOUT_INDEX, REF_NAME_ANNOTATION,
+};
+use crate::platform::Platform;
+use crate::store::{BlobStore, StoredImage};
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, EntryType};
+
+/// Symlinks followed at most when opening a file in a tarball
+const MAX_SYMLINKS: usize = 8;
+
+/// Where the files of a local image are read from
+enum Source {
+    Dir(PathBuf),
+    /// Tarball with the offset and the size of each file in it, and the target of each symlink
+    Tarball {
+        path: PathBuf,
+        files: HashMap<String, (u64, u64)>,
+        symlinks: HashMap<String, String>,
+    },
+}
+
+/// Image imported by [`ImageImporter::import`]
+pub struct ImportedImage {
+    /// Digest of the manifest, or of the config for `docker save` tarballs (the image ID)
+    pub digest: String,
+    /// Platform the image is built for, if its config says
+    pub platform: Option<Platform>,
+    pub image: StoredImage,
+}
+
+/// Reads an image saved on the local filesystem into a [`BlobStore`], without any registry.
+///
+/// Reads OCI image layouts (`index.json` and `blobs/`), either as a directory or as a tarball,
+/// and tarballs written by `docker save` (`manifest.json`). Blobs are verified as if they
+/// were downloaded, and layers already in the store are not read again.
+pub struct ImageImporter {
+    source: Source,
+    tag: Option<String>,
+    platform: Platform,
+    store: BlobStore,
+    post_import_fn: Option<fn(usize, usize)>,
+}
+
+impl ImageImporter {
+    /// Importer of a tarball of `docker save`, or of an OCI image layout
+    pub fn from_archive<P: AsRef<Path>>(path: P, store: &BlobStore) -> Result<Self> {
+        Ok(Self::new(Source::open_tarball(path.as_ref())?, store))
+    }
+
+    /// Importer of an OCI image layout directory
+    pub fn from_oci_layout<P: AsRef<Path>>(dir: P, store: &BlobStore) -> Result<Self> {
+        let dir = dir.as_ref();
+        if !dir.join(OCI_LAYOUT_INDEX).is_file() {
+            bail!(
+                "'{}' is not an OCI image layout (no {OCI_LAYOUT_INDEX})",
+                dir.display()
+            );
+        }
+        Ok(Self::new(Source::Dir(dir.to_path_buf()), store))
+    }
+
+    fn new(source: Source, store: &BlobStore) -> Self {
+        Self {
+            source,
+            tag: None,
+            platform: Platform::host(),
+            store: store.clone(),
+            post_import_fn: None,
+        }
+    }
+
+    /// Tag of the image to import if there are several, matched against the
+    /// `org.opencontainers.image.ref.name` annotation or the RepoTags of `docker save`
+    pub fn tag(&mut self, tag: &str) -> &mut Self {
+        self.tag = Some(tag.to_string());
+        self
+    }
+
+    /// Platform chosen from multi-platform images, the one of the host by default
+    pub fn platform(&mut self, platform: Platform) -> &mut Self {
+        self.platform = platform;
+        self
+    }
+
+    pub fn post_import_display(&mut self, f: fn(usize, usize)) -> &mut Self {
+        self.post_import_fn = Some(f);
+        self
+    }
+
+    pub fn import(self) -> Result<ImportedImage> {
+        if self.source.exists(OCI_LAYOUT_INDEX) {
+            self.import_oci_layout()
+        } else if self.source.exists(DOCKER_ARCHIVE_MANIFEST) {
+            self.import_docker_archive()
+        } else {
+            bail!(
+                "Neither {OCI_LAYOUT_INDEX} of an OCI image layout \
+                nor {DOCKER_ARCHIVE_MANIFEST} of 'docker save' is found"
+            )
+        }
+    }
+
+    fn import_oci_layout(self) -> Result<ImportedImage> {
+        let index: Index = serde_json::from_slice(&self.source.read(OCI_LAYOUT_INDEX)?)
+            .with_context(|| format!("Invalid {OCI_LAYOUT_INDEX}"))?;
+        let entry = self.select_tagged(&index)?;
+        // Recorded like the digest a tag in a registry points at, even if it is an index
+        let digest = entry.digest.clone();
+        let (mut manifest_digest, mut size) = (entry.digest.clone(), entry.size);
+        let manifest = loop {
+            let bytes = self.read_blob(&manifest_digest, size)?;
+            match ManifestOrIndex::parse(&bytes)? {
+                ManifestOrIndex::Manifest(manifest) => break manifest,
+                ManifestOrIndex::Index(index) => {
+                    let entry = select_manifest(&index, &self.platform)?;
+                    (manifest_digest, size) = (entry.digest.clone(), entry.size);
+                }
+            }
+        };
+
+        let config = self.read_blob(&manifest.config.digest, manifest.config.size)?;
+        let image = self.add_layers(&manifest, &config)?;
+        Ok(ImportedImage {
+            digest,
+            platform: ImageConfig::platform_of_blob(&config)?,
+            image,
+        })
+    }
+
+    fn import_docker_archive(self) -> Result<ImportedImage> {
+        let entries: Vec<DockerArchiveEntry> =
+            serde_json::from_slice(&self.source.read(DOCKER_ARCHIVE_MANIFEST)?)
+                .with_context(|| format!("Invalid {DOCKER_ARCHIVE_MANIFEST}"))?;
+        let entry = self.select_repo_tag(&entries)?;
+        let config = self.source.read(&entry.config)?;
+        let config_digest = format!("sha256:{:x}", Sha256::digest(&config));
+
+        // Layers of 'docker save' come with neither digests nor media types
+        let mut manifest = Manifest {
+            schemaVersion: 2,
+            mediaType: None,
+            config: Descriptor {
+                mediaType: String::new(),
+                size: config.len() as u64,
+                digest: config_digest.clone(),
+            },
+            layers: vec![],
+        };
+        let mut paths = HashMap::new();
+        for path in entry.layers.iter() {
+            let (mut reader, size) = self.source.open(path)?;
+            let mut magic = [0; 4];
+            let read = read_up_to(&mut reader, &mut magic)?;
+            let digest = match blob_path_digest(path) {
+                Some(digest) => digest,
+                None => {
+                    let mut hasher = Sha256::new();
+                    hasher.update(&magic[..read]);
+                    io::copy(&mut reader, &mut hasher)?;
+                    format!("sha256:{:x}", hasher.finalize())
+                }
+            };
+            manifest.layers.push(Descriptor {
+                mediaType: String::new(),
+                size,
+                digest: digest.clone(),
+            });
+            paths.insert(digest, (path.clone(), Compression::detect(&magic[..read])));
+        }
+
+        let image = self.add_layers_with(&manifest, &config, |digest| {
+            let (path, compression) = &paths[digest];
+            Ok((self.source.open(path)?.0, *compression))
+        })?;
+        Ok(ImportedImage {
+            digest: config_digest,
+            platform: ImageConfig::platform_of_blob(&config)?,
+            image,
+        })
+    }
+
+    /// Adds layers of an OCI `manifest`, whose blobs are in `blobs/`
+    fn add_layers(&self, manifest: &Manifest, config: &[u8]) -> Result<StoredImage> {
+        // Unsupported layers fail before anything is imported
+        let compressions = manifest
+            .layers
+            .iter()
+            .map(|layer| {
+                let compression = Compression::from_media_type(&layer.mediaType)
+                    .with_context(|| format!("Failed to import layer {}", layer.digest))?;
+                Ok((layer.digest.as_str(), compression))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        self.add_layers_with(manifest, config, |digest| {
+            Ok((
+                self.source.open(&blob_path(digest)?)?.0,
+                compressions[digest],
+            ))
+        })
+    }
+
+    /// Adds layers of `manifest` missing from the store, each read by `open`,
+    /// and counts them as used by one more environment
+    fn add_layers_with<F>(&self, manifest: &Manifest, config: &[u8], open: F) -> Result<StoredImage>
+    where
+        F: Fn(&str) -> Result<(Box<dyn Read>, Compression)>,
+    {
+        let digests: Vec<String> = manifest
+            .layers
+            .iter()
+            .map(|layer| layer.digest.clone())
+            .collect();
+        let config = ImageConfig::from_blob(config)?;
+
+        // Counted before looking into the store, so that pruning it meanwhile never removes
+        // layers found there or being added
+        self.store.add_refs(&digests)?;
+        match self.add_missing_layers(manifest, open) {
+            Ok(reports) => Ok(StoredImage {
+                layers: digests,
+                config,
+                reports,
+            }),
+            Err(e) => {
+                let _ = self.store.release(&digests);
+                Err(e)
+            }
+        }
+    }
+
+    /// Adds layers of `manifest` not in the store yet, each read by `open`
+    fn add_missing_layers<F>(
+        &self,
+        manifest: &Manifest,
+        open: F,
+    ) -> Result<Vec<(String, ExtractReport)>>
+    where
+        F: Fn(&str) -> Result<(Box<dyn Read>, Compression)>,
+    {
+        let mut seen = HashSet::new();
+        let missing: Vec<_> = manifest
+            .layers
+            .iter()
+            .filter(|layer| !self.store.has_layer(&layer.digest))
+            .filter(|layer| seen.insert(layer.digest.clone()))
+            .collect();
+        let num_of_layer = manifest.layers.len();
+        let num_of_cached = num_of_layer - missing.len();
+        if let Some(f) = self.post_import_fn {
+            f(num_of_layer, 0);
+            if num_of_cached > 0 {
+                f(num_of_layer, num_of_cached);
+            }
+        }
+
+        let mut reports = vec![];
+        for (i, layer) in missing.into_iter().enumerate() {
+            let (reader, compression) = open(&layer.digest)?;
+            let report = self
+                .store
+                .add_layer(&layer.digest, layer.size, compression, reader)?;
+            reports.push((layer.digest.clone(), report));
+            if let Some(f) = self.post_import_fn {
+                f(num_of_layer, num_of_cached + i + 1);
+            }
+        }
+        Ok(reports)
+    }
+
+    /// Picks the image tagged `tag` from `index.json`, which may list several
+    fn select_tagged<'a>(&self, index: &'a Index) -> Result<&'a IndexEntry> {
+        let tagged = self.tag.as_ref().and_then(|tag| {
+            index.manifests.iter().find(|entry| {
+                entry
+                    .annotations
+                    .get(REF_NAME_ANNOTATION)
+                    .is_some_and(|name| matches_tag(name, tag))
+            })
+        });
+        if let Some(entry) = tagged {
+            return Ok(entry);
+        }
+        match index.manifests.as_slice() {
+            [] => bail!("{OCI_LAYOUT_INDEX} lists no image"),
+            [entry] => Ok(entry),
+            entries if entries.iter().any(|entry| entry.platform.is_some()) => {
+                select_manifest(index, &self.platform)
+            }
+            entries => {
+                let names: Vec<&str> = entries
+                    .iter()
+                    .filter_map(|entry| entry.annotations.get(REF_NAME_ANNOTATION))
+                    .map(|name| name.as_str())
+                    .collect();
+                bail!(
+                    "{OCI_LAYOUT_INDEX} lists several images, choose one by --tag (available: {})",
+                    names.join(", ")
+                )
+            }
+        }
+    }
+
+    /// Picks the image tagged `tag` from `manifest.json`, which may list several
+    fn select_repo_tag<'a>(
+        &self,
+        entries: &'a [DockerArchiveEntry],
+    ) -> Result<&'a DockerArchiveEntry> {
+        let tagged = self.tag.as_ref().and_then(|tag| {
+            entries.iter().find(|entry| {
+                entry
+                    .repo_tags
+                    .iter()
+                    .flatten()
+                    .any(|name| matches_tag(name, tag))
+            })
+        });
+        if let Some(entry) = tagged {
+            return Ok(entry);
+        }
+        match entries {
+            [] => bail!("{DOCKER_ARCHIVE_MANIFEST} lists no image"),
+            [entry] => Ok(entry),
+            entries => {
+                let names: Vec<&str> = entries
+                    .iter()
+                    .flat_map(|entry| entry.repo_tags.iter().flatten())
+                    .map(|name| name.as_str())
+                    .collect();
+                bail!(
+                    "{DOCKER_ARCHIVE_MANIFEST} lists several images, choose one by --tag (available: {})",
+                    names.join(", ")
+                )
+            }
+        }
+    }
+
+    /// Reads a small blob such as a manifest, verified against `digest` and `size`
+    fn read_blob(&self, digest: &str, size: u64) -> Result<Vec<u8>> {
+        let (reader, _) = self.source.open(&blob_path(digest)?)?;
+        let mut blob = digest::VerifyingReader::new(reader, digest, size)?;
+        let mut bytes = vec![];
+        let read = blob.read_to_end(&mut bytes);
+        // A broken blob makes reading fail too, but its digest tells why
+        blob.finish()?;
+        read?;
+        Ok(bytes)
+    }
+}
+
+impl Source {
+    /// Indexes files in the tarball at `path`, so that each of them can be read by seeking
+    fn open_tarball(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open: '{}'", path.display()))?;
+        let mut archive = Archive::new(file);
+        let mut files = HashMap::new();
+        let mut symlinks = HashMap::new();
+        for entry in archive
+            .entries()
+            .with_context(|| format!("Failed to read: '{}'", path.display()))?
+        {
+            let entry = entry.with_context(|| format!("Broken tarball: '{}'", path.display()))?;
+            let Some(name) = normalize(&entry.path()?) else {
+                continue;
+            };
+            match entry.header().entry_type() {
+                EntryType::Regular | EntryType::Continuous => {
+                    files.insert(name, (entry.raw_file_position(), entry.size()));
+                }
+                EntryType::Symlink | EntryType::Link => {
+                    let Some(target) = entry.link_name()? else {
+                        continue;
+                    };
+                    // Symlinks are relative to their directory, and hardlinks to the top
+                    let target = match entry.header().entry_type() {
+                        EntryType::Symlink => Path::new(&name)
+                            .parent()
+                            .unwrap_or(Path::new(""))
+                            .join(target),
+                        _ => target.into_owned(),
+                    };
+                    if let Some(target) = normalize(&target) {
+                        symlinks.insert(name, target);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Self::Tarball {
+            path: path.to_path_buf(),
+            files,
+            symlinks,
+        })
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        match self {
+            Self::Dir(dir) => dir.join(name).is_file(),
+            Self::Tarball {
+                files, symlinks, ..
+            } => files.contains_key(name) || symlinks.contains_key(name),
+        }
+    }
+
+    /// Opens file `name` and returns it with its size
+    fn open(&self, name: &str) -> Result<(Box<dyn Read>, u64)> {
+        let Some(mut name) = normalize(Path::new(name)) else {
+            bail!("Invalid path in image: '{name}'");
+        };
+        match self {
+            Self::Dir(dir) => {
+                let path = dir.join(&name);
+                let file = File::open(&path)
+                    .with_context(|| format!("Failed to open: '{}'", path.display()))?;
+                let size = file.metadata()?.len();
+                Ok((Box::new(file), size))
+            }
+            Self::Tarball {
+                path,
+                files,
+                symlinks,
+            } => {
+                for _ in 0..MAX_SYMLINKS {
+                    match symlinks.get(&name) {
+                        Some(target) => name = target.clone(),
+                        None => break,
+                    }
+                }
+                let Some(&(offset, size)) = files.get(&name) else {
+                    bail!("'{name}' is not found in '{}'", path.display());
+                };
+                let mut file = File::open(path)
+                    .with_context(|| format!("Failed to open: '{}'", path.display()))?;
+                file.seek(SeekFrom::Start(offset))?;
+                Ok((Box::new(file.take(size)), size))
+            }
+        }
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>> {
+        let (mut reader, _) = self.open(name)?;
+        let mut bytes = vec![];
+        reader
+            .read_to_end(&mut bytes)
+            .with_context(|| format!("Failed to read: '{name}'"))?;
+        Ok(bytes)
+    }
+}
+
+/// `path` relative to the top of the image, or None if it escapes it
+fn normalize(path: &Path) -> Option<String> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir => {}
+            Component::ParentDir if normalized.pop() => {}
+            _ => return None,
+        }
+    }
+    normalized.to_str().map(|path| path.to_string())
+}
+
+/// 'sha256:abc...' is stored at 'blobs/sha256/abc...' of OCI image layouts
+fn blob_path(digest: &str) -> Result<String> {
+    match digest.split_once(':') {
+        Some((algorithm, hex)) if !algorithm.contains('/') && !hex.contains('/') => {
+            Ok(format!("blobs/{algorithm}/{hex}"))
+        }
+        _ => bail!("Invalid digest: '{digest}'"),
+    }
+}
+
+/// Digest of a blob at 'blobs/<algorithm>/<hex>', which newer `docker save` writes
+fn blob_path_digest(path: &str) -> Option<String> {
+    let normalized = normalize(Path::new(path))?;
+    let rest = normalized.strip_prefix("blobs/")?;
+    let (algorithm, hex) = rest.split_once('/')?;
+    Some(format!("{algorithm}:{hex}"))
+}
+
+/// Whether a tag `name` (e.g. 'docker.io/library/ubuntu:22.04', or just '22.04') is `tag`,
+/// which may be given with or without the repository
+fn matches_tag(name: &str, tag: &str) -> bool {
+    name == tag || name.ends_with(&format!(":{tag}")) || name.ends_with(&format!("/{tag}"))
+}
+
+/// Reads until `buf` is full or the end, and returns the number of bytes read
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporter::{ExportLayer, ExportedImage, ImageExporter};
+    use std::fs;
+    use tar::{Builder, Header};
+    use tempfile::TempDir;
+
+    /// Exports an image tagged `tag` whose only layer has 'greeting' saying `tag`
+    /// into an OCI image layout at `work/<name>`
+    fn export(work: &Path, name: &str, tag: &str) -> (PathBuf, ExportedImage) {
+        let layer = work.join(format!("{name}.layer"));
+        fs::create_dir(&layer).unwrap();
+        fs::write(layer.join("greeting"), tag).unwrap();
+        let mut exporter = ImageExporter::new(ImageConfig::default(), Platform::host());
+        exporter.add_layer(ExportLayer::Dir(layer)).tag(tag);
+        let layout = work.join(name);
+        let image = exporter.write_oci_layout(&layout).unwrap();
+        (layout, image)
+    }
+
+    fn greeting(store: &BlobStore, imported: &ImportedImage) -> String {
+        let layer = store.layer_dir(&imported.image.layers[0]);
+        fs::read_to_string(layer.join("greeting")).unwrap()
+    }
+
+    /// Tarball of the files of `dir`
+    fn pack(dir: &Path, path: &Path) {
+        let mut builder = Builder::new(File::create(path).unwrap());
+        builder.append_dir_all(".", dir).unwrap();
+        builder.finish().unwrap();
+    }
+
+    fn new_store() -> (TempDir, BlobStore) {
+        let work = tempfile::tempdir().unwrap();
+        let store = BlobStore::new(work.path().join("store"));
+        (work, store)
+    }
+
+    #[test]
+    fn imports_oci_layouts_and_docker_archives() {
+        let (work, store) = new_store();
+        let (layout, image) = export(work.path(), "layout", "tools/app:1.0");
+        let imported = ImageImporter::from_oci_layout(&layout, &store)
+            .unwrap()
+            .import()
+            .unwrap();
+        assert_eq!(imported.digest, image.digest);
+        assert_eq!(imported.platform, Some(Platform::host()));
+        assert_eq!(greeting(&store, &imported), "tools/app:1.0");
+
+        let archive = work.path().join("app.tar");
+        let mut exporter = ImageExporter::new(ImageConfig::default(), Platform::host());
+        exporter
+            .add_layer(ExportLayer::Dir(work.path().join("layout.layer")))
+            .tag("tools/app:1.0");
+        exporter.write_docker_archive(&archive).unwrap();
+        // Read by its manifest.json as 'docker load' does, without index.json
+        let mut docker_archive = Builder::new(vec![]);
+        let mut source = Archive::new(File::open(&archive).unwrap());
+        for entry in source.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.path().unwrap() != Path::new(OCI_LAYOUT_INDEX) {
+                let mut header = entry.header().clone();
+                let path = entry.path().unwrap().into_owned();
+                docker_archive
+                    .append_data(&mut header, path, &mut entry)
+                    .unwrap();
+            }
+        }
+        fs::write(&archive, docker_archive.into_inner().unwrap()).unwrap();
+
+        let (_other, store) = new_store();
+        let imported = ImageImporter::from_archive(&archive, &store)
+            .unwrap()
+            .import()
+            .unwrap();
+        assert_eq!(imported.digest, image.manifest.config.digest);
+        assert_eq!(greeting(&store, &imported), "tools/app:1.0");
+    }
+
+    #[test]
+    fn selects_images_by_tags() {
+        let (work, store) = new_store();
+        let (layout, _) = export(work.path(), "first", "tools/app:1.0");
+        let (second, _) = export(work.path(), "second", "tools/app:2.0");
+        // One layout listing both images
+        let mut index: Index =
+            serde_json::from_slice(&fs::read(layout.join(OCI_LAYOUT_INDEX)).unwrap()).unwrap();
+        let second_index: Index =
+            serde_json::from_slice(&fs::read(second.join(OCI_LAYOUT_INDEX)).unwrap()).unwrap();
+        index.manifests.extend(second_index.manifests);
+        fs::write(
+            layout.join(OCI_LAYOUT_INDEX),
+            serde_json::to_vec(&index).unwrap(),
+        )
+        .unwrap();
+        for entry in fs::read_dir(second.join("blobs/sha256")).unwrap() {
+            let entry = entry.unwrap();
+            fs::copy(
+                entry.path(),
+                layout.join("blobs/sha256").join(entry.file_name()),
+            )
+            .unwrap();
+        }
+
+        for tag in ["tools/app:2.0", "2.0", "app:2.0"] {
+            let mut importer = ImageImporter::from_oci_layout(&layout, &store).unwrap();
+            importer.tag(tag);
+            assert_eq!(
+                greeting(&store, &importer.import().unwrap()),
+                "tools/app:2.0"
+            );
+        }
+        // Without a tag, the index is taken as one of platforms
+        let imported = ImageImporter::from_oci_layout(&layout, &store)
+            .unwrap()
+            .import()
+            .unwrap();
+        assert_eq!(greeting(&store, &imported), "tools/app:1.0");
+
+        // Images of 'docker save' have no platforms, so several of them need a tag
+        let entries: Vec<DockerArchiveEntry> =
+            [(&layout, "tools/app:1.0"), (&second, "tools/app:2.0")]
+                .iter()
+                .map(|(dir, tag)| {
+                    let index: Index =
+                        serde_json::from_slice(&fs::read(dir.join(OCI_LAYOUT_INDEX)).unwrap())
+                            .unwrap();
+                    let (_, hex) = index
+                        .manifests
+                        .last()
+                        .unwrap()
+                        .digest
+                        .split_once(':')
+                        .unwrap();
+                    let manifest: Manifest = serde_json::from_slice(
+                        &fs::read(dir.join("blobs/sha256").join(hex)).unwrap(),
+                    )
+                    .unwrap();
+                    let path = |descriptor: &Descriptor| blob_path(&descriptor.digest).unwrap();
+                    DockerArchiveEntry {
+                        config: path(&manifest.config),
+                        repo_tags: Some(vec![tag.to_string()]),
+                        layers: manifest.layers.iter().map(path).collect(),
+                    }
+                })
+                .collect();
+        fs::remove_file(layout.join(OCI_LAYOUT_INDEX)).unwrap();
+        fs::write(
+            layout.join(DOCKER_ARCHIVE_MANIFEST),
+            serde_json::to_vec(&entries).unwrap(),
+        )
+        .unwrap();
+        let archive = work.path().join("images.tar");
+        pack(&layout, &archive);
+        let mut importer = ImageImporter::from_archive(&archive, &store).unwrap();
+        importer.tag("2.0");
+        assert_eq!(
+            greeting(&store, &importer.import().unwrap()),
+            "tools/app:2.0"
+        );
+        let error = ImageImporter::from_archive(&archive, &store)
+            .unwrap()
+            .import()
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("choose one by --tag"), "{error}");
+    }
+
+    #[test]
+    fn keeps_symlinks_inside_tarballs() {
+        let (work, store) = new_store();
+        let (layout, _) = export(work.path(), "layout", "tools/app:1.0");
+        let archive = work.path().join("app.tar");
+        pack(&layout, &archive);
+        assert!(ImageImporter::from_archive(&archive, &store)
+            .unwrap()
+            .import()
+            .is_ok());
+
+        // Names leading out of the tarball are left out, so nothing is found by them
+        for (name, target) in [
+            (OCI_LAYOUT_INDEX, "../layout/index.json"),
+            (OCI_LAYOUT_INDEX, "/etc/hostname"),
+            ("blobs", "../layout/blobs"),
+        ] {
+            let mut builder = Builder::new(File::create(&archive).unwrap());
+            for entry in fs::read_dir(&layout).unwrap() {
+                let entry = entry.unwrap();
+                if entry.file_name() == name {
+                    continue;
+                }
+                if entry.path().is_dir() {
+                    builder
+                        .append_dir_all(entry.file_name(), entry.path())
+                        .unwrap();
+                } else {
+                    builder
+                        .append_path_with_name(entry.path(), entry.file_name())
+                        .unwrap();
+                }
+            }
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Symlink);
+            header.set_size(0);
+            builder.append_link(&mut header, name, target).unwrap();
+            builder.finish().unwrap();
+            drop(builder);
+
+            let (_other, store) = new_store();
+            let error = ImageImporter::from_archive(&archive, &store)
+                .and_then(|importer| importer.import())
+                .err()
+                .unwrap();
+            assert!(
+                error.to_string().contains("not found") || error.to_string().contains("Neither"),
+                "{name} -> {target}: {error}"
+            );
+        }
+
+        assert_eq!(normalize(Path::new("a/./b/../c")).as_deref(), Some("a/c"));
+        assert_eq!(normalize(Path::new("a/../../etc/passwd")), None);
+        assert_eq!(normalize(Path::new("/etc/passwd")), None);
+    }
+
+    #[test]
+    fn rejects_blobs_not_matching_digests() {
+        let (work, store) = new_store();
+        let (layout, image) = export(work.path(), "layout", "tools/app:1.0");
+        let layer = &image.manifest.layers[0];
+        let layer_path = layout.join(blob_path(&layer.digest).unwrap());
+        let mut blob = fs::read(&layer_path).unwrap();
+        let last = blob.len() - 1;
+        blob[last] ^= 1;
+        fs::write(&layer_path, blob).unwrap();
+
+        let error = ImageImporter::from_oci_layout(&layout, &store)
+            .unwrap()
+            .import()
+            .err()
+            .unwrap();
+        assert!(format!("{error:#}").contains("does not match"), "{error:#}");
+        assert!(!store.has_layer(&layer.digest));
+    }
+}
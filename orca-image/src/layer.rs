@@ -0,0 +1,876 @@
+use anyhow::{bail, Context, Result};
+use flate2::bufread::MultiGzDecoder;
+use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+use nix::sys::time::TimeVal;
+use std::ffi::{OsStr, OsString};
+use std::fmt::{self, Display};
+use std::fs::{self, create_dir_all};
+use std::io::{self, BufRead, ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{lchown, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, Builder, Entry, EntryType, Header, HeaderMode};
+use walkdir::WalkDir;
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+const OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+/// Xattrs of overlayfs itself, which a layer must not set (e.g. redirects to other paths)
+const OVERLAY_XATTR_PREFIX: &str = "trusted.overlay.";
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+/// Same limit as the kernel when resolving symlinks
+const MAX_SYMLINKS: usize = 40;
+
+/// Media types of layers, of OCI and of Docker.
+/// Nondistributable layers of OCI are deprecated but still found in old Windows-based images.
+const UNCOMPRESSED_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.layer.v1.tar",
+    "application/vnd.oci.image.layer.nondistributable.v1.tar",
+];
+const GZIP_MEDIA_TYPES: [&str; 4] = [
+    "application/vnd.oci.image.layer.v1.tar+gzip",
+    "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip",
+    "application/vnd.docker.image.rootfs.diff.tar.gzip",
+    "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip",
+];
+const ZSTD_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.layer.v1.tar+zstd",
+    "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd",
+];
+
+/// How a layer tarball is compressed, told by the media type of the layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_media_type(media_type: &str) -> Result<Self> {
+        if UNCOMPRESSED_MEDIA_TYPES.contains(&media_type) {
+            Ok(Self::None)
+        } else if GZIP_MEDIA_TYPES.contains(&media_type) {
+            Ok(Self::Gzip)
+        } else if ZSTD_MEDIA_TYPES.contains(&media_type) {
+            Ok(Self::Zstd)
+        } else {
+            bail!(
+                "Unsupported media type of layer: '{media_type}' \
+                (expected a tar, tar+gzip or tar+zstd layer)"
+            )
+        }
+    }
+
+    /// OCI media type of layers compressed this way
+    pub fn media_type(self) -> &'static str {
+        match self {
+            Self::None => UNCOMPRESSED_MEDIA_TYPES[0],
+            Self::Gzip => GZIP_MEDIA_TYPES[0],
+            Self::Zstd => ZSTD_MEDIA_TYPES[0],
+        }
+    }
+
+    /// Tells the compression of a layer without a media type (e.g. in a `docker save` tarball)
+    /// from its first bytes
+    pub fn detect(magic: &[u8]) -> Self {
+        match magic {
+            [0x1f, 0x8b, ..] => Self::Gzip,
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Self::Zstd,
+            _ => Self::None,
+        }
+    }
+
+    /// Wraps `reader` of a compressed layer so that the tarball is read from it
+    pub fn decoder<'a, R: BufRead + 'a>(self, reader: R) -> Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Self::None => Box::new(reader),
+            Self::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Self::Zstd => Box::new(
+                zstd::stream::read::Decoder::with_buffer(reader)
+                    .context("Failed to start zstd decompression")?,
+            ),
+        })
+    }
+}
+
+/// What extracting a layer may create.
+///
+/// Paths of entries must stay inside the layer, and symlinks met on the way to an entry are
+/// resolved as if the layer were the root directory, so that nothing is written outside of it.
+/// These hold regardless of the policy.
+#[derive(Debug, Clone, Default)]
+pub struct ExtractPolicy {
+    /// Create character and block devices. They are skipped unless this is set.
+    pub allow_devices: bool,
+    /// The layer is in the format of overlayfs rather than of OCI (e.g. a layer of orca):
+    /// character devices 0/0 are whiteouts, created even if devices are not allowed,
+    /// `.wh.` names are not converted, and xattrs of overlayfs (e.g. the opaque one)
+    /// are set instead of being skipped.
+    pub overlay_format: bool,
+}
+
+/// What has been extracted from a layer
+#[derive(Debug, Clone, Default)]
+pub struct ExtractReport {
+    pub files: usize,
+    pub dirs: usize,
+    pub symlinks: usize,
+    pub hardlinks: usize,
+    pub whiteouts: usize,
+    pub devices: usize,
+    /// Devices not created because the policy does not allow them
+    pub skipped_devices: usize,
+    /// Xattrs of overlayfs in the layer, which are not set
+    pub skipped_xattrs: usize,
+}
+
+impl Display for ExtractReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} files, {} dirs, {} symlinks, {} hardlinks, {} whiteouts",
+            self.files, self.dirs, self.symlinks, self.hardlinks, self.whiteouts
+        )?;
+        if self.devices > 0 {
+            write!(f, ", {} devices", self.devices)?;
+        }
+        if self.skipped_devices > 0 {
+            write!(f, ", skipped {} devices", self.skipped_devices)?;
+        }
+        if self.skipped_xattrs > 0 {
+            write!(f, ", skipped {} overlayfs xattrs", self.skipped_xattrs)?;
+        }
+        Ok(())
+    }
+}
+
+/// Extracts a layer tarball read from `reader` into `dest` so that it can be used as a lowerdir.
+///
+/// OCI whiteouts are converted into the ones of overlayfs:
+/// `.wh.<name>` becomes a character device 0/0 named `<name>`,
+/// and `.wh..wh..opq` sets the opaque xattr on its directory.
+/// Owners, modes, mtimes and xattrs (e.g. `security.capability`) are kept.
+pub fn extract_layer<R: Read, P: AsRef<Path>>(
+    reader: R,
+    dest: P,
+    policy: &ExtractPolicy,
+) -> Result<ExtractReport> {
+    let mut extractor = LayerExtractor::new(dest, policy)?;
+    let mut archive = Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(true);
+    archive.set_preserve_mtime(true);
+    // Xattrs are set by `set_xattrs`, which filters them and covers directories too
+    archive.set_unpack_xattrs(false);
+
+    for entry in archive.entries().context("Failed to read layer tarball")? {
+        let mut entry = entry.context("Broken layer tarball")?;
+        let path = entry.path()?.into_owned();
+        extractor.extract(&mut entry, &path)?;
+    }
+    extractor.finish()
+}
+
+/// Extracts entries of a layer one at a time, for tarballs carrying more than one layer
+/// (e.g. each under its own directory).
+///
+/// Entries are extracted as by [`extract_layer`], so the archive they come from must preserve
+/// permissions, ownerships and mtimes, and leave xattrs to the extractor.
+pub struct LayerExtractor {
+    dest: PathBuf,
+    policy: ExtractPolicy,
+    report: ExtractReport,
+    dir_mtimes: Vec<(PathBuf, i64)>,
+}
+
+impl LayerExtractor {
+    pub fn new<P: AsRef<Path>>(dest: P, policy: &ExtractPolicy) -> Result<Self> {
+        let dest = dest.as_ref();
+        create_dir_all(dest)
+            .with_context(|| format!("Failed to create dir: '{}'", dest.display()))?;
+        Ok(Self {
+            dest: dest.to_path_buf(),
+            policy: policy.clone(),
+            report: ExtractReport::default(),
+            dir_mtimes: vec![],
+        })
+    }
+
+    /// Extracts `entry` to `path` in the layer
+    pub fn extract<R: Read>(&mut self, entry: &mut Entry<R>, path: &Path) -> Result<()> {
+        let dest = self.dest.as_path();
+        let Some(relative) = normalize_path(path) else {
+            bail!("Invalid path in layer tarball: '{}'", path.display());
+        };
+        let entry_type = entry.header().entry_type();
+        let target = match relative.file_name() {
+            Some(name) => resolve_parent(dest, &relative)?.join(name),
+            // The root directory of the layer itself (e.g. './')
+            None if entry_type == EntryType::Directory => dest.to_path_buf(),
+            None => bail!("Invalid path in layer tarball: '{}'", path.display()),
+        };
+        let file_name = relative
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+
+        if !self.policy.overlay_format {
+            if file_name == OPAQUE_WHITEOUT {
+                let parent = target.parent().unwrap();
+                xattr::set(parent, OPAQUE_XATTR, b"y").with_context(|| {
+                    format!("Failed to make dir opaque: '{}'", parent.display())
+                })?;
+                self.report.whiteouts += 1;
+                return Ok(());
+            }
+            if let Some(name) = file_name.strip_prefix(WHITEOUT_PREFIX) {
+                // '.wh..' and '.wh...' would remove the layer itself or its parent
+                if matches!(name, "" | "." | "..") || name.contains('/') {
+                    bail!("Invalid whiteout in layer tarball: '{}'", path.display());
+                }
+                let whiteout = target.with_file_name(name);
+                remove_existing(&whiteout, false)?;
+                mknod(&whiteout, SFlag::S_IFCHR, Mode::empty(), makedev(0, 0)).with_context(
+                    || format!("Failed to create whiteout: '{}'", whiteout.display()),
+                )?;
+                self.report.whiteouts += 1;
+                return Ok(());
+            }
+        }
+
+        let whiteout = self.policy.overlay_format && is_overlay_whiteout(entry)?;
+        match entry_type {
+            EntryType::Char | EntryType::Block if !self.policy.allow_devices && !whiteout => {
+                self.report.skipped_devices += 1;
+                return Ok(());
+            }
+            EntryType::Char | EntryType::Block | EntryType::Fifo => {
+                remove_existing(&target, false)?;
+                unpack_special(entry, &target)?;
+                if whiteout {
+                    self.report.whiteouts += 1;
+                } else if entry_type == EntryType::Fifo {
+                    self.report.files += 1;
+                } else {
+                    self.report.devices += 1;
+                }
+            }
+            EntryType::Link => {
+                let source = link_source(dest, entry)?;
+                remove_existing(&target, false)?;
+                fs::hard_link(&source, &target).with_context(|| {
+                    format!(
+                        "Failed to link '{}' to '{}'",
+                        path.display(),
+                        source.display()
+                    )
+                })?;
+                self.report.hardlinks += 1;
+            }
+            EntryType::Directory => {
+                remove_existing(&target, true)?;
+                entry
+                    .unpack(&target)
+                    .with_context(|| format!("Failed to extract: '{}'", path.display()))?;
+                self.dir_mtimes
+                    .push((target.clone(), entry.header().mtime()? as i64));
+                self.report.dirs += 1;
+            }
+            EntryType::Symlink => {
+                remove_existing(&target, false)?;
+                entry
+                    .unpack(&target)
+                    .with_context(|| format!("Failed to extract: '{}'", path.display()))?;
+                self.report.symlinks += 1;
+            }
+            _ => {
+                remove_existing(&target, false)?;
+                entry
+                    .unpack(&target)
+                    .with_context(|| format!("Failed to extract: '{}'", path.display()))?;
+                self.report.files += 1;
+            }
+        }
+        if entry_type != EntryType::Symlink {
+            self.report.skipped_xattrs += set_xattrs(entry, &target, self.policy.overlay_format)?;
+        }
+        Ok(())
+    }
+
+    /// Sets mtimes of directories, which creating their children has changed,
+    /// and returns what has been extracted
+    pub fn finish(self) -> Result<ExtractReport> {
+        for (dir, mtime) in self.dir_mtimes.into_iter().rev() {
+            // The directory may have been replaced by a later entry
+            if !fs::symlink_metadata(&dir).is_ok_and(|metadata| metadata.is_dir()) {
+                continue;
+            }
+            let time = TimeVal::new(mtime, 0);
+            nix::sys::stat::lutimes(&dir, &time, &time)?;
+        }
+        Ok(self.report)
+    }
+}
+
+/// Writes `dir`, a layer in the format of overlayfs, into `writer` as a layer tarball.
+///
+/// This is the reverse of [`extract_layer`]: a character device 0/0 named `<name>` becomes
+/// `.wh.<name>`, and a directory with the opaque xattr gets `.wh..wh..opq`.
+/// Other xattrs of overlayfs are dropped, and the rest are kept in PAX headers.
+pub fn pack_layer<W: Write, P: AsRef<Path>>(dir: P, writer: W) -> Result<W> {
+    let dir = dir.as_ref();
+    let mut builder = Builder::new(writer);
+    builder.follow_symlinks(false);
+    builder.mode(HeaderMode::Complete);
+
+    for entry in WalkDir::new(dir).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        let path = entry.path();
+        let relative = path.strip_prefix(dir).unwrap();
+        let metadata = entry.metadata()?;
+        let file_type = metadata.file_type();
+
+        if file_type.is_char_device() && metadata.rdev() == 0 {
+            let mut name = OsString::from(WHITEOUT_PREFIX);
+            name.push(relative.file_name().unwrap());
+            let mut header = Header::new_gnu();
+            header.set_metadata_in_mode(&metadata, HeaderMode::Complete);
+            header.set_entry_type(EntryType::Regular);
+            header.set_size(0);
+            builder.append_data(&mut header, relative.with_file_name(name), io::empty())?;
+            continue;
+        }
+
+        append_xattrs(&mut builder, path)?;
+        if file_type.is_char_device() || file_type.is_block_device() || file_type.is_fifo() {
+            // tar cannot add devices from paths
+            let mut header = Header::new_gnu();
+            header.set_metadata_in_mode(&metadata, HeaderMode::Complete);
+            header.set_entry_type(if file_type.is_char_device() {
+                EntryType::Char
+            } else if file_type.is_block_device() {
+                EntryType::Block
+            } else {
+                EntryType::Fifo
+            });
+            header.set_size(0);
+            header.set_device_major(nix::sys::stat::major(metadata.rdev()) as u32)?;
+            header.set_device_minor(nix::sys::stat::minor(metadata.rdev()) as u32)?;
+            builder.append_data(&mut header, relative, io::empty())?;
+        } else {
+            builder
+                .append_path_with_name(path, relative)
+                .with_context(|| format!("Failed to add: '{}'", path.display()))?;
+        }
+
+        if file_type.is_dir()
+            && matches!(xattr::get(path, OPAQUE_XATTR)?, Some(value) if value == b"y")
+        {
+            let mut header = Header::new_gnu();
+            header.set_metadata_in_mode(&metadata, HeaderMode::Complete);
+            header.set_entry_type(EntryType::Regular);
+            header.set_mode(0o644);
+            header.set_size(0);
+            builder.append_data(&mut header, relative.join(OPAQUE_WHITEOUT), io::empty())?;
+        }
+    }
+    Ok(builder.into_inner()?)
+}
+
+/// Adds a PAX header carrying xattrs of `path`, except the ones of overlayfs,
+/// for the entry appended next
+fn append_xattrs<W: Write>(builder: &mut Builder<W>, path: &Path) -> Result<()> {
+    let mut xattrs = vec![];
+    let mut names: Vec<OsString> = xattr::list(path)?.collect();
+    names.sort();
+    for name in names {
+        if name.as_bytes().starts_with(OVERLAY_XATTR_PREFIX.as_bytes()) {
+            continue;
+        }
+        if let Some(value) = xattr::get(path, &name)? {
+            let key = [PAX_XATTR_PREFIX.as_bytes(), name.as_bytes()].concat();
+            xattrs.push((key, value));
+        }
+    }
+    if xattrs.is_empty() {
+        return Ok(());
+    }
+    // Keys of PAX headers are strings, so xattrs with names not in UTF-8 are left out
+    let extensions = xattrs
+        .iter()
+        .filter_map(|(key, value)| Some((std::str::from_utf8(key).ok()?, value.as_slice())));
+    builder.append_pax_extensions(extensions)?;
+    Ok(())
+}
+
+/// Returns `path` without '.' components, or None if it escapes the layer
+fn normalize_path(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(normalized)
+}
+
+/// Resolves the parent directory of `relative` inside `root`, creating missing directories.
+/// Symlinks are followed as if `root` were '/', so an absolute target or too many '..'
+/// cannot lead outside of `root`.
+fn resolve_parent(root: &Path, relative: &Path) -> Result<PathBuf> {
+    let mut resolved = PathBuf::new();
+    // Components still to resolve, the next one at the end
+    let mut pending: Vec<OsString> = relative
+        .parent()
+        .into_iter()
+        .flat_map(|parent| parent.iter())
+        .rev()
+        .map(|name| name.to_os_string())
+        .collect();
+    let mut symlinks = 0;
+    while let Some(name) = pending.pop() {
+        if name == ".." {
+            resolved.pop();
+            continue;
+        }
+        if name.is_empty() || name == "." || name == "/" {
+            continue;
+        }
+        let candidate = resolved.join(&name);
+        let full = root.join(&candidate);
+        match fs::symlink_metadata(&full) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                symlinks += 1;
+                if symlinks > MAX_SYMLINKS {
+                    bail!(
+                        "Too many levels of symlinks in layer: '{}'",
+                        relative.display()
+                    );
+                }
+                let target = fs::read_link(&full)?;
+                if target.has_root() {
+                    resolved = PathBuf::new();
+                }
+                pending.extend(target.iter().rev().map(|name| name.to_os_string()));
+            }
+            Ok(metadata) if metadata.is_dir() => resolved = candidate,
+            Ok(_) => bail!(
+                "Parent of '{}' in layer is not a directory",
+                relative.display()
+            ),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                fs::create_dir(&full)
+                    .with_context(|| format!("Failed to create dir: '{}'", full.display()))?;
+                resolved = candidate;
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read: '{}'", full.display()))
+            }
+        }
+    }
+    Ok(root.join(resolved))
+}
+
+/// Path of the file a hardlink entry points at, which must have been extracted from the layer
+fn link_source<R: Read>(root: &Path, entry: &Entry<R>) -> Result<PathBuf> {
+    let link_name = entry
+        .link_name()?
+        .context("Hardlink in layer tarball has no target")?;
+    let Some(relative) = normalize_path(&link_name) else {
+        bail!(
+            "Invalid hardlink target in layer tarball: '{}'",
+            link_name.display()
+        );
+    };
+    let Some(name) = relative.file_name() else {
+        bail!(
+            "Invalid hardlink target in layer tarball: '{}'",
+            link_name.display()
+        );
+    };
+    let source = resolve_parent(root, &relative)?.join(name);
+    match fs::symlink_metadata(&source) {
+        Ok(metadata) if !metadata.is_dir() => Ok(source),
+        _ => bail!(
+            "Hardlink target is not a file in the layer: '{}'",
+            link_name.display()
+        ),
+    }
+}
+
+/// Removes what is at `path` so that an entry can be created there.
+/// A directory is kept if `keep_dir` is set, so that entries of the same directory are merged.
+fn remove_existing(path: &Path, keep_dir: bool) -> Result<()> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() && keep_dir => return Ok(()),
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => Err(e),
+    };
+    result.with_context(|| format!("Failed to replace: '{}'", path.display()))
+}
+
+/// Sets xattrs in the PAX headers of `entry` on `path`, after its owner and mode
+/// because changing the owner clears `security.capability`.
+/// Xattrs of overlayfs are skipped unless `keep_overlay` is set.
+/// Returns the number of overlayfs xattrs skipped.
+fn set_xattrs<R: Read>(entry: &mut Entry<R>, path: &Path, keep_overlay: bool) -> Result<usize> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(0);
+    };
+    let mut skipped = 0;
+    for extension in extensions {
+        let extension = extension?;
+        let Some(name) = extension
+            .key_bytes()
+            .strip_prefix(PAX_XATTR_PREFIX.as_bytes())
+        else {
+            continue;
+        };
+        if !keep_overlay && name.starts_with(OVERLAY_XATTR_PREFIX.as_bytes()) {
+            skipped += 1;
+            continue;
+        }
+        let name = OsStr::from_bytes(name);
+        xattr::set(path, name, extension.value_bytes()).with_context(|| {
+            format!(
+                "Failed to set xattr '{}' on '{}'",
+                name.to_string_lossy(),
+                path.display()
+            )
+        })?;
+    }
+    Ok(skipped)
+}
+
+/// Whether `entry` is a whiteout of overlayfs, a character device 0/0
+fn is_overlay_whiteout<R: Read>(entry: &Entry<R>) -> Result<bool> {
+    let header = entry.header();
+    Ok(header.entry_type() == EntryType::Char
+        && header.device_major()?.unwrap_or(0) == 0
+        && header.device_minor()?.unwrap_or(0) == 0)
+}
+
+/// Creates a device or a fifo, which tar cannot unpack by itself
+fn unpack_special<R: Read>(entry: &Entry<R>, dest: &Path) -> Result<()> {
+    let header = entry.header();
+    let kind = match header.entry_type() {
+        EntryType::Char => SFlag::S_IFCHR,
+        EntryType::Block => SFlag::S_IFBLK,
+        _ => SFlag::S_IFIFO,
+    };
+    // Device numbers of fifos are left blank by some archivers
+    let (major, minor) = match kind {
+        SFlag::S_IFIFO => (0, 0),
+        _ => (
+            header.device_major()?.unwrap_or(0),
+            header.device_minor()?.unwrap_or(0),
+        ),
+    };
+
+    mknod(
+        dest,
+        kind,
+        Mode::empty(),
+        makedev(major as u64, minor as u64),
+    )
+    .with_context(|| format!("Failed to create: '{}'", dest.display()))?;
+    lchown(dest, Some(header.uid()? as u32), Some(header.gid()? as u32))?;
+    fs::set_permissions(dest, fs::Permissions::from_mode(header.mode()?))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// cap_net_bind_service in the format of `security.capability` (VFS_CAP_REVISION_2)
+    const CAPABILITY: [u8; 20] = [1, 0, 0, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+    /// Layer tarball built in memory. Names are written as they are,
+    /// since `tar` itself refuses the '..' which layers must be checked for.
+    struct Tarball(Builder<Vec<u8>>);
+
+    fn header(entry_type: EntryType, size: u64) -> Header {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(match entry_type {
+            EntryType::Directory => 0o755,
+            EntryType::Char => 0o666,
+            _ => 0o644,
+        });
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_size(size);
+        header
+    }
+
+    impl Tarball {
+        fn new() -> Self {
+            Self(Builder::new(vec![]))
+        }
+
+        fn append(&mut self, name: &str, entry_type: EntryType, data: &[u8]) -> &mut Self {
+            let mut header = header(entry_type, data.len() as u64);
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_cksum();
+            self.0.append(&header, data).unwrap();
+            self
+        }
+
+        fn file(&mut self, name: &str) -> &mut Self {
+            self.append(name, EntryType::Regular, name.as_bytes())
+        }
+
+        fn link(&mut self, name: &str, entry_type: EntryType, target: &str) -> &mut Self {
+            let mut header = header(entry_type, 0);
+            header.as_old_mut().linkname[..target.len()].copy_from_slice(target.as_bytes());
+            self.0.append_data(&mut header, name, io::empty()).unwrap();
+            self
+        }
+
+        fn device(&mut self, name: &str, major: u32, minor: u32) -> &mut Self {
+            let mut header = header(EntryType::Char, 0);
+            header.set_device_major(major).unwrap();
+            header.set_device_minor(minor).unwrap();
+            self.0.append_data(&mut header, name, io::empty()).unwrap();
+            self
+        }
+
+        fn extract(&mut self, dest: &Path, policy: &ExtractPolicy) -> Result<ExtractReport> {
+            let builder = std::mem::replace(&mut self.0, Builder::new(vec![]));
+            extract_layer(builder.into_inner()?.as_slice(), dest, policy)
+        }
+    }
+
+    /// Work directory, with a file beside the layer 'work/layer' to tell whether it survives
+    fn work() -> (TempDir, PathBuf) {
+        let work = tempfile::tempdir().unwrap();
+        fs::write(work.path().join("outside"), "outside").unwrap();
+        let dest = work.path().join("layer");
+        (work, dest)
+    }
+
+    #[test]
+    fn rejects_paths_escaping_layers() {
+        for name in ["../escape", "a/../../escape", "/etc/escape"] {
+            let (work, dest) = work();
+            let result = Tarball::new()
+                .file(name)
+                .extract(&dest, &ExtractPolicy::default());
+            assert!(result.is_err(), "{name}");
+            assert!(!work.path().join("escape").exists());
+        }
+    }
+
+    #[test]
+    fn rejects_whiteouts_of_layers_themselves() {
+        for name in [".wh..", ".wh...", "a/.wh..", "a/.wh..."] {
+            let (work, dest) = work();
+            let error = Tarball::new()
+                .file("a/kept")
+                .file(name)
+                .extract(&dest, &ExtractPolicy::default())
+                .unwrap_err();
+            assert!(error.to_string().starts_with("Invalid whiteout"), "{name}");
+            assert!(dest.join("a/kept").is_file());
+            assert!(work.path().join("outside").is_file());
+        }
+    }
+
+    #[test]
+    fn converts_whiteouts() {
+        let (_work, dest) = work();
+        let report = Tarball::new()
+            .file("a/removed")
+            .file("a/.wh.removed")
+            .file("a/.wh..wh..opq")
+            .extract(&dest, &ExtractPolicy::default())
+            .unwrap();
+        let metadata = dest.join("a/removed").symlink_metadata().unwrap();
+        assert!(metadata.file_type().is_char_device() && metadata.rdev() == 0);
+        assert!(!dest.join("a/.wh.removed").exists());
+        assert_eq!(
+            xattr::get(dest.join("a"), OPAQUE_XATTR).unwrap().unwrap(),
+            b"y"
+        );
+        assert_eq!(report.whiteouts, 2);
+    }
+
+    #[test]
+    fn keeps_symlinks_inside_layers() {
+        let (work, dest) = work();
+        Tarball::new()
+            .link("root", EntryType::Symlink, "/")
+            .file("root/etc/passwd")
+            .link("up", EntryType::Symlink, "../..")
+            .file("up/outside")
+            .link("nested/up", EntryType::Symlink, "../../../..")
+            .file("nested/up/escape")
+            .extract(&dest, &ExtractPolicy::default())
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(dest.join("etc/passwd")).unwrap(),
+            "root/etc/passwd"
+        );
+        assert_eq!(
+            fs::read_to_string(dest.join("outside")).unwrap(),
+            "up/outside"
+        );
+        assert!(dest.join("escape").is_file());
+        assert_eq!(
+            fs::read_to_string(work.path().join("outside")).unwrap(),
+            "outside"
+        );
+        assert!(!work.path().join("escape").exists());
+        assert_eq!(fs::read_link(dest.join("root")).unwrap(), Path::new("/"));
+    }
+
+    #[test]
+    fn links_only_inside_layers() {
+        let (_work, dest) = work();
+        let report = Tarball::new()
+            .file("a/file")
+            .link("b/link", EntryType::Link, "a/file")
+            .extract(&dest, &ExtractPolicy::default())
+            .unwrap();
+        let ino = |path: &str| dest.join(path).metadata().unwrap().ino();
+        assert_eq!(ino("a/file"), ino("b/link"));
+        assert_eq!(report.hardlinks, 1);
+
+        for target in ["../outside", "/etc/hostname", "root/etc/hostname", "a"] {
+            let (work, dest) = work();
+            let result = Tarball::new()
+                .file("a/file")
+                .link("root", EntryType::Symlink, "/")
+                .link("link", EntryType::Link, target)
+                .extract(&dest, &ExtractPolicy::default());
+            assert!(result.is_err(), "{target}");
+            assert_eq!(work.path().join("outside").metadata().unwrap().nlink(), 1);
+        }
+    }
+
+    #[test]
+    fn skips_devices_unless_allowed() {
+        let (_work, dest) = work();
+        let report = Tarball::new()
+            .device("dev/null", 1, 3)
+            .device("dev/whiteout", 0, 0)
+            .extract(&dest, &ExtractPolicy::default())
+            .unwrap();
+        assert_eq!(report.skipped_devices, 2);
+        assert_eq!(report.devices, 0);
+        assert!(dest.join("dev/null").symlink_metadata().is_err());
+
+        let (_work, dest) = work();
+        let policy = ExtractPolicy {
+            allow_devices: true,
+            ..Default::default()
+        };
+        let report = Tarball::new()
+            .device("dev/null", 1, 3)
+            .extract(&dest, &policy)
+            .unwrap();
+        assert_eq!(report.devices, 1);
+        let metadata = dest.join("dev/null").symlink_metadata().unwrap();
+        assert!(metadata.file_type().is_char_device());
+        assert_eq!(metadata.rdev(), makedev(1, 3));
+    }
+
+    #[test]
+    fn keeps_capabilities_after_chown() {
+        let (_work, dest) = work();
+        let mut tarball = Tarball::new();
+        tarball
+            .0
+            .append_pax_extensions([
+                ("SCHILY.xattr.security.capability", CAPABILITY.as_slice()),
+                ("SCHILY.xattr.trusted.overlay.redirect", b"/etc".as_slice()),
+            ])
+            .unwrap();
+        let mut header = header(EntryType::Regular, 0);
+        header.set_mode(0o755);
+        header.set_uid(1000);
+        header.set_gid(1000);
+        tarball
+            .0
+            .append_data(&mut header, "bin/server", io::empty())
+            .unwrap();
+        let report = tarball.extract(&dest, &ExtractPolicy::default()).unwrap();
+
+        let server = dest.join("bin/server");
+        assert_eq!(server.metadata().unwrap().uid(), 1000);
+        let capability = xattr::get(&server, "security.capability").unwrap();
+        assert_eq!(capability.as_deref(), Some(CAPABILITY.as_slice()));
+        assert_eq!(
+            xattr::get(&server, "trusted.overlay.redirect").unwrap(),
+            None
+        );
+        assert_eq!(report.skipped_xattrs, 1);
+    }
+
+    #[test]
+    fn packs_overlay_layers_into_oci_ones() {
+        let (work, dir) = work();
+        fs::create_dir_all(dir.join("d")).unwrap();
+        fs::write(dir.join("d/file"), "file").unwrap();
+        xattr::set(dir.join("d"), OPAQUE_XATTR, b"y").unwrap();
+        xattr::set(dir.join("d/file"), "user.note", b"kept").unwrap();
+        xattr::set(dir.join("d/file"), "trusted.overlay.origin", b"dropped").unwrap();
+        mknod(
+            &dir.join("gone"),
+            SFlag::S_IFCHR,
+            Mode::empty(),
+            makedev(0, 0),
+        )
+        .unwrap();
+        let tarball = pack_layer(&dir, vec![]).unwrap();
+
+        let mut names = vec![];
+        let mut archive = Archive::new(tarball.as_slice());
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            names.push(entry.path().unwrap().to_string_lossy().into_owned());
+            for extension in entry.pax_extensions().unwrap().into_iter().flatten() {
+                let key = extension.unwrap().key().unwrap().to_string();
+                assert!(!key.contains(OVERLAY_XATTR_PREFIX), "{key}");
+            }
+        }
+        assert_eq!(names, ["d", "d/.wh..wh..opq", "d/file", ".wh.gone"]);
+
+        let dest = work.path().join("extracted");
+        let report = extract_layer(tarball.as_slice(), &dest, &ExtractPolicy::default()).unwrap();
+        assert_eq!(report.whiteouts, 2);
+        let gone = dest.join("gone").symlink_metadata().unwrap();
+        assert!(gone.file_type().is_char_device() && gone.rdev() == 0);
+        assert_eq!(
+            xattr::get(dest.join("d"), OPAQUE_XATTR).unwrap().unwrap(),
+            b"y"
+        );
+        let file = dest.join("d/file");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "file");
+        assert_eq!(xattr::get(&file, "user.note").unwrap().unwrap(), b"kept");
+        assert_eq!(xattr::get(&file, "trusted.overlay.origin").unwrap(), None);
+    }
+
+    #[test]
+    fn resolves_absolute_symlinks_in_layers() {
+        let (work, dest) = work();
+        Tarball::new()
+            .link("a", EntryType::Symlink, work.path().to_str().unwrap())
+            .file("a/escape")
+            .extract(&dest, &ExtractPolicy::default())
+            .unwrap();
+        assert!(!work.path().join("escape").exists());
+        assert!(dest
+            .join(work.path().strip_prefix("/").unwrap())
+            .join("escape")
+            .is_file());
+    }
+}
@@ -1,2 +1,12 @@
+pub mod config;
+pub mod digest;
 pub mod downloader;
-pub mod merger;
+pub mod exporter;
+pub mod importer;
+pub mod layer;
+pub mod manifest;
+pub mod platform;
+pub mod pusher;
+pub mod reference;
+pub mod registry;
+pub mod store;
@@ -0,0 +1,211 @@
+use crate::platform::Platform;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+pub const DOCKER_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+pub const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub const OCI_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+pub const MANIFEST_MEDIA_TYPES: [&str; 4] = [
+    DOCKER_MANIFEST,
+    DOCKER_MANIFEST_LIST,
+    OCI_MANIFEST,
+    OCI_INDEX,
+];
+/// Annotation of OCI image layouts naming the tag of a manifest
+pub const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+/// Files of OCI image layouts, beside `blobs/<algorithm>/<hex>`
+pub const OCI_LAYOUT_INDEX: &str = "index.json";
+pub const OCI_LAYOUT_FILE: &str = "oci-layout";
+pub const OCI_LAYOUT_VERSION: &str = "1.0.0";
+/// Top file of tarballs of `docker save` and for `docker load`
+pub const DOCKER_ARCHIVE_MANIFEST: &str = "manifest.json";
+
+/// Descriptor of a blob, with either a Docker or an OCI media type
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[allow(non_snake_case)]
+pub struct Descriptor {
+    pub mediaType: String,
+    pub size: u64,
+    pub digest: String,
+}
+
+/// Docker image manifest v2 schema 2, or OCI image manifest
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[allow(non_snake_case)]
+pub struct Manifest {
+    pub schemaVersion: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mediaType: Option<String>,
+    pub config: Descriptor,
+    pub layers: Vec<Descriptor>,
+}
+
+/// Entry of an index pointing at the manifest for one platform (or one tag in image layouts)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[allow(non_snake_case)]
+pub struct IndexEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mediaType: Option<String>,
+    pub size: u64,
+    pub digest: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+}
+
+/// Docker manifest list, or OCI image index
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[allow(non_snake_case)]
+pub struct Index {
+    pub schemaVersion: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mediaType: Option<String>,
+    pub manifests: Vec<IndexEntry>,
+}
+
+/// Image in a tarball of `docker save`, listed in its manifest.json
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct DockerArchiveEntry {
+    /// Path of the config blob in the tarball
+    pub config: String,
+    #[serde(default)]
+    pub repo_tags: Option<Vec<String>>,
+    /// Paths of the layer tarballs from the bottom one
+    pub layers: Vec<String>,
+}
+
+/// What a manifest reference points at
+pub enum ManifestOrIndex {
+    Manifest(Manifest),
+    Index(Index),
+}
+
+impl ManifestOrIndex {
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let value: serde_json::Value =
+            serde_json::from_slice(bytes).context("Manifest of image is not JSON")?;
+
+        // mediaType is optional in OCI, then an index is told by its 'manifests'
+        let media_type = value["mediaType"]
+            .as_str()
+            .map(|media_type| media_type.to_string());
+        match media_type.as_deref() {
+            Some(DOCKER_MANIFEST_LIST | OCI_INDEX) => {}
+            None if value.get("manifests").is_some() => {}
+            Some(DOCKER_MANIFEST | OCI_MANIFEST) | None => {
+                let manifest = serde_json::from_value(value).context("Invalid image manifest")?;
+                return Ok(Self::Manifest(manifest));
+            }
+            Some(media_type) => bail!("Unsupported type of image manifest: '{media_type}'"),
+        }
+        let index = serde_json::from_value(value).context("Invalid image index")?;
+        Ok(Self::Index(index))
+    }
+}
+
+/// Picks the entry for `platform` from `index`
+pub fn select_manifest<'a>(index: &'a Index, platform: &Platform) -> Result<&'a IndexEntry> {
+    if let Some(entry) = index.manifests.iter().find(|entry| {
+        entry
+            .platform
+            .as_ref()
+            .is_some_and(|candidate| platform.matches(candidate))
+    }) {
+        return Ok(entry);
+    }
+    // Attestations are listed as 'unknown/unknown', which are not images
+    let available: Vec<String> = index
+        .manifests
+        .iter()
+        .filter_map(|entry| entry.platform.as_ref())
+        .filter(|candidate| candidate.os != "unknown")
+        .map(|candidate| candidate.to_string())
+        .collect();
+    bail!(
+        "Image has no manifest for platform '{platform}' (available: {})",
+        available.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Index listing a manifest for each of `platforms`, digested by its position
+    fn index(platforms: &[&str]) -> Index {
+        let manifests: Vec<String> = platforms
+            .iter()
+            .enumerate()
+            .map(|(i, platform)| {
+                let platform: Platform = platform.parse().unwrap();
+                format!(
+                    r#"{{"mediaType": "{OCI_MANIFEST}", "size": 1, "digest": "sha256:{i}",
+                    "platform": {}}}"#,
+                    serde_json::to_string(&platform).unwrap()
+                )
+            })
+            .collect();
+        let json = format!(
+            r#"{{"schemaVersion": 2, "manifests": [{}]}}"#,
+            manifests.join(",")
+        );
+        match ManifestOrIndex::parse(json.as_bytes()).unwrap() {
+            ManifestOrIndex::Index(index) => index,
+            ManifestOrIndex::Manifest(_) => panic!("parsed as a manifest"),
+        }
+    }
+
+    fn select(index: &Index, platform: &str) -> Result<String> {
+        let entry = select_manifest(index, &platform.parse()?)?;
+        Ok(entry.digest.clone())
+    }
+
+    #[test]
+    fn selects_manifests_for_platforms() {
+        let index = index(&["linux/amd64", "linux/arm/v7", "linux/arm64/v8"]);
+        assert_eq!(select(&index, "linux/amd64").unwrap(), "sha256:0");
+        assert_eq!(select(&index, "linux/arm64").unwrap(), "sha256:2");
+        assert_eq!(select(&index, "linux/arm64/v8").unwrap(), "sha256:2");
+        assert_eq!(select(&index, "linux/arm/v7").unwrap(), "sha256:1");
+    }
+
+    #[test]
+    fn tells_available_platforms_if_none_matches() {
+        let index = index(&["linux/amd64", "linux/arm/v7", "unknown/unknown"]);
+        let error = select(&index, "linux/arm/v6").unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "Image has no manifest for platform 'linux/arm/v6' \
+            (available: linux/amd64, linux/arm/v7)"
+        );
+        assert!(select(&index, "linux/s390x").is_err());
+        assert!(select(&index, "windows/amd64").is_err());
+    }
+
+    #[test]
+    fn tells_manifests_from_indexes() {
+        let manifest = format!(
+            r#"{{"schemaVersion": 2, "mediaType": "{DOCKER_MANIFEST}",
+            "config": {{"mediaType": "{OCI_CONFIG}", "size": 1, "digest": "sha256:0"}},
+            "layers": []}}"#
+        );
+        assert!(matches!(
+            ManifestOrIndex::parse(manifest.as_bytes()).unwrap(),
+            ManifestOrIndex::Manifest(_)
+        ));
+        let list = format!(
+            r#"{{"schemaVersion": 2, "mediaType": "{DOCKER_MANIFEST_LIST}", "manifests": []}}"#
+        );
+        assert!(matches!(
+            ManifestOrIndex::parse(list.as_bytes()).unwrap(),
+            ManifestOrIndex::Index(_)
+        ));
+        let unknown = r#"{"schemaVersion": 1, "mediaType": "text/plain"}"#;
+        assert!(ManifestOrIndex::parse(unknown.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+/// Platform an image runs on, written as `os/architecture[/variant]` (e.g. 'linux/arm64/v8')
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    /// Platform of the running host, in the names used by OCI (e.g. 'amd64' for x86_64)
+    pub fn host() -> Self {
+        let architecture = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "x86" => "386",
+            "aarch64" => "arm64",
+            "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+            "loongarch64" => "loong64",
+            arch => arch,
+        };
+        Self {
+            os: std::env::consts::OS.to_string(),
+            architecture: architecture.to_string(),
+            variant: None,
+        }
+    }
+
+    /// Whether an image built for `other` runs on this platform.
+    /// The variant is compared only if this platform has one.
+    pub fn matches(&self, other: &Platform) -> bool {
+        self.os == other.os
+            && self.architecture == other.architecture
+            && (self.variant.is_none() || self.variant == other.variant)
+    }
+}
+
+impl FromStr for Platform {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split('/').collect();
+        if parts.iter().any(|part| part.is_empty()) {
+            bail!("Invalid platform: '{s}' (expected os/architecture[/variant])");
+        }
+        match parts[..] {
+            [os, architecture] => Ok(Self {
+                os: os.to_string(),
+                architecture: architecture.to_string(),
+                variant: None,
+            }),
+            [os, architecture, variant] => Ok(Self {
+                os: os.to_string(),
+                architecture: architecture.to_string(),
+                variant: Some(variant.to_string()),
+            }),
+            _ => bail!("Invalid platform: '{s}' (expected os/architecture[/variant])"),
+        }
+    }
+}
+
+impl Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{variant}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Platform {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_platforms_with_variants() {
+        let platform = parse("linux/arm64/v8");
+        assert_eq!(platform.os, "linux");
+        assert_eq!(platform.architecture, "arm64");
+        assert_eq!(platform.variant.as_deref(), Some("v8"));
+        assert_eq!(platform.to_string(), "linux/arm64/v8");
+        assert_eq!(parse("linux/amd64").variant, None);
+        for invalid in ["linux", "linux/", "/amd64", "linux//v8", "linux/arm/v7/x"] {
+            assert!(invalid.parse::<Platform>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn compares_variants_only_if_given() {
+        assert!(parse("linux/arm64").matches(&parse("linux/arm64/v8")));
+        assert!(parse("linux/arm64/v8").matches(&parse("linux/arm64/v8")));
+        assert!(!parse("linux/arm64/v8").matches(&parse("linux/arm64")));
+        assert!(!parse("linux/arm/v7").matches(&parse("linux/arm/v6")));
+        assert!(!parse("linux/arm64").matches(&parse("linux/arm/v7")));
+        assert!(!parse("linux/amd64").matches(&parse("windows/amd64")));
+    }
+}
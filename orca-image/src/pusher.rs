@@ -0,0 +1,154 @@
+use crate::exporter::ExportedImage;
+use crate::manifest::OCI_MANIFEST;
+use crate::reference::Reference;
+use crate::registry::RegistryClient;
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Blobs larger than this are uploaded in chunks
+pub const DEFAULT_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// How blobs have been sent by [`ImagePusher::push`]
+#[derive(Debug, Default)]
+pub struct PushReport {
+    pub uploaded: usize,
+    /// Blobs mounted from another repository of the registry instead of being uploaded
+    pub mounted: usize,
+    /// Blobs the repository already had
+    pub existing: usize,
+}
+
+/// Pushes an image written by `ImageExporter` as an OCI image layout to a registry.
+///
+/// Blobs the repository already has are skipped. The others are mounted from the repository
+/// given by [`ImagePusher::mount_from`] if the registry can, or uploaded otherwise:
+/// in one request if they are small, and in chunks of [`ImagePusher::chunk_size`] if not.
+/// The manifest is put last, so that the tag only points at a complete image.
+pub struct ImagePusher {
+    reference: Reference,
+    mount_from: Option<String>,
+    chunk_size: u64,
+    post_push_fn: Option<fn(usize, usize)>,
+}
+
+impl ImagePusher {
+    pub fn new(reference: &Reference) -> Self {
+        Self {
+            reference: reference.clone(),
+            mount_from: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            post_push_fn: None,
+        }
+    }
+
+    /// Repository to mount blobs from (e.g. the one of the base image),
+    /// ignored unless it is another repository of the same registry
+    pub fn mount_from(&mut self, source: &Reference) -> &mut Self {
+        if source.registry == self.reference.registry
+            && source.repository != self.reference.repository
+        {
+            self.mount_from = Some(source.repository.clone());
+        }
+        self
+    }
+
+    pub fn chunk_size(&mut self, chunk_size: u64) -> &mut Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    pub fn post_push_display(&mut self, f: fn(usize, usize)) -> &mut Self {
+        self.post_push_fn = Some(f);
+        self
+    }
+
+    /// Pushes `image`, whose blobs are in the OCI image layout `layout`
+    pub fn push<P: AsRef<Path>>(self, layout: P, image: &ExportedImage) -> Result<PushReport> {
+        let layout = layout.as_ref();
+        let sources: Vec<&str> = self.mount_from.iter().map(|s| s.as_str()).collect();
+        let client = RegistryClient::new(&self.reference).for_push(&sources);
+
+        let mut digests: Vec<&str> = vec![];
+        for descriptor in image.manifest.layers.iter().chain([&image.manifest.config]) {
+            if !digests.contains(&descriptor.digest.as_str()) {
+                digests.push(&descriptor.digest);
+            }
+        }
+
+        let mut report = PushReport::default();
+        if let Some(f) = self.post_push_fn {
+            f(digests.len(), 0);
+        }
+        for (i, digest) in digests.iter().enumerate() {
+            if client.has_blob(digest)? {
+                report.existing += 1;
+            } else {
+                let mount = self.mount_from.as_deref().map(|source| (*digest, source));
+                match client.start_upload(mount)? {
+                    Some(location) => {
+                        self.upload(&client, &location, layout, digest)
+                            .with_context(|| format!("Failed to upload blob {digest}"))?;
+                        report.uploaded += 1;
+                    }
+                    None => report.mounted += 1,
+                }
+            }
+            if let Some(f) = self.post_push_fn {
+                f(digests.len(), i + 1);
+            }
+        }
+
+        let stored = client.put_manifest(
+            self.reference.manifest_reference(),
+            OCI_MANIFEST,
+            &image.manifest_bytes,
+        )?;
+        if let Some(stored) = stored
+            && stored != image.digest
+        {
+            bail!(
+                "Registry stored the manifest as {stored}, but it is {}",
+                image.digest
+            );
+        }
+        Ok(report)
+    }
+
+    /// Uploads blob `digest` in one request if it fits in a chunk, or in chunks
+    fn upload(
+        &self,
+        client: &RegistryClient,
+        location: &str,
+        layout: &Path,
+        digest: &str,
+    ) -> Result<()> {
+        let Some((algorithm, hex)) = digest.split_once(':') else {
+            bail!("Invalid digest: '{digest}'");
+        };
+        let path = layout.join("blobs").join(algorithm).join(hex);
+        let mut file =
+            File::open(&path).with_context(|| format!("Failed to open: '{}'", path.display()))?;
+        let size = file.metadata()?.len();
+        if size <= self.chunk_size {
+            let mut blob = vec![];
+            file.read_to_end(&mut blob)?;
+            return client.finish_upload(location, digest, &blob);
+        }
+
+        let mut location = location.to_string();
+        let mut offset = 0;
+        let mut chunk = vec![];
+        while offset < size {
+            chunk.clear();
+            (&mut file).take(self.chunk_size).read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                bail!("'{}' has been truncated while uploading", path.display());
+            }
+            location = client.upload_chunk(&location, offset, &chunk)?;
+            offset += chunk.len() as u64;
+        }
+        client.finish_upload(&location, digest, &[])
+    }
+}
@@ -0,0 +1,237 @@
+use anyhow::{bail, Result};
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+/// Registry used when a reference does not name one (e.g. 'ubuntu:22.04')
+pub const DEFAULT_REGISTRY: &str = "docker.io";
+const DEFAULT_REGISTRY_HOST: &str = "registry-1.docker.io";
+const DEFAULT_TAG: &str = "latest";
+/// Namespace of official images on Docker Hub
+const OFFICIAL_NAMESPACE: &str = "library/";
+
+/// Reference to an image in a registry, `[registry[:port]/]namespace/name[:tag][@digest]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    /// Host of the registry with an optional port (e.g. 'registry.example.com:5000')
+    pub registry: String,
+    /// Repository in the registry (e.g. 'library/ubuntu')
+    pub repository: String,
+    pub tag: Option<String>,
+    /// Digest of the manifest (e.g. 'sha256:...'), which takes precedence over the tag
+    pub digest: Option<String>,
+}
+
+impl Reference {
+    /// Tag or digest to look up the manifest with
+    pub fn manifest_reference(&self) -> &str {
+        self.digest
+            .as_deref()
+            .or(self.tag.as_deref())
+            .unwrap_or(DEFAULT_TAG)
+    }
+
+    /// Sets the tag if the reference has neither a tag nor a digest
+    pub fn or_tag(mut self, tag: &str) -> Self {
+        if self.tag.is_none() && self.digest.is_none() {
+            self.tag = Some(tag.to_string());
+        }
+        self
+    }
+
+    /// Same image pinned to `digest`, which keeps pointing at it even if the tag is moved
+    pub fn with_digest(&self, digest: &str) -> Self {
+        Self {
+            digest: Some(digest.to_string()),
+            ..self.clone()
+        }
+    }
+
+    /// Reference without the tag and the digest (e.g. 'ubuntu', 'localhost:5000/tools/jq')
+    pub fn name(&self) -> String {
+        if self.registry == DEFAULT_REGISTRY {
+            let repository = self.repository.as_str();
+            repository
+                .strip_prefix(OFFICIAL_NAMESPACE)
+                .unwrap_or(repository)
+                .to_string()
+        } else {
+            format!("{}/{}", self.registry, self.repository)
+        }
+    }
+
+    /// Host to send requests of the registry API to
+    pub fn api_host(&self) -> &str {
+        if self.registry == DEFAULT_REGISTRY {
+            DEFAULT_REGISTRY_HOST
+        } else {
+            &self.registry
+        }
+    }
+
+    /// Registries on the local host are reached over plain HTTP, others over HTTPS
+    pub fn is_local(&self) -> bool {
+        let host = match self.registry.rsplit_once(':') {
+            Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+            _ => self.registry.as_str(),
+        };
+        matches!(host, "localhost" | "127.0.0.1" | "[::1]")
+    }
+}
+
+impl FromStr for Reference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (rest, digest) = match s.split_once('@') {
+            Some((rest, digest)) => (rest, Some(digest)),
+            None => (s, None),
+        };
+        let (name, tag) = match rest.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => (name, Some(tag)),
+            _ => (rest, None),
+        };
+
+        // The first component is a registry only if it cannot be a namespace
+        let (registry, repository) = match name.split_once('/') {
+            Some((first, repository))
+                if first.contains('.') || first.contains(':') || first == "localhost" =>
+            {
+                (first.to_string(), repository.to_string())
+            }
+            _ => (DEFAULT_REGISTRY.to_string(), name.to_string()),
+        };
+        let repository = if registry == DEFAULT_REGISTRY && !repository.contains('/') {
+            format!("{OFFICIAL_NAMESPACE}{repository}")
+        } else {
+            repository
+        };
+
+        let valid_repository = repository.split('/').all(|component| {
+            !component.is_empty()
+                && component.bytes().all(|b| {
+                    b.is_ascii_lowercase() || b.is_ascii_digit() || matches!(b, b'.' | b'_' | b'-')
+                })
+        });
+        if !valid_repository {
+            bail!("Invalid image reference: '{s}' (repository must be lowercase)");
+        }
+        if let Some(tag) = tag {
+            let valid_tag = tag.len() <= 128
+                && !tag.starts_with(['.', '-'])
+                && tag
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'.' | b'-'));
+            if !valid_tag {
+                bail!("Invalid image reference: '{s}' (invalid tag)");
+            }
+        }
+        if let Some(digest) = digest {
+            let valid_digest = match digest.split_once(':') {
+                Some((algorithm, hex)) => {
+                    !algorithm.is_empty()
+                        && !hex.is_empty()
+                        && hex.bytes().all(|b| b.is_ascii_hexdigit())
+                }
+                None => false,
+            };
+            if !valid_digest {
+                bail!("Invalid image reference: '{s}' (invalid digest)");
+            }
+        }
+
+        Ok(Self {
+            registry,
+            repository,
+            tag: tag.map(|tag| tag.to_string()),
+            digest: digest.map(|digest| digest.to_string()),
+        })
+    }
+}
+
+/// Short form as users write it, e.g. 'ubuntu:22.04' for 'docker.io/library/ubuntu:22.04'
+impl Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{tag}")?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{digest}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Reference {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn defaults_to_official_images_on_docker_hub() {
+        let reference = parse("ubuntu");
+        assert_eq!(reference.registry, DEFAULT_REGISTRY);
+        assert_eq!(reference.repository, "library/ubuntu");
+        assert_eq!(reference.tag, None);
+        assert_eq!(reference.manifest_reference(), DEFAULT_TAG);
+        assert_eq!(reference.api_host(), DEFAULT_REGISTRY_HOST);
+        assert_eq!(reference.to_string(), "ubuntu");
+
+        let reference = parse("bitnami/redis:7.2");
+        assert_eq!(reference.registry, DEFAULT_REGISTRY);
+        assert_eq!(reference.repository, "bitnami/redis");
+        assert_eq!(reference.tag.as_deref(), Some("7.2"));
+        assert_eq!(reference.to_string(), "bitnami/redis:7.2");
+    }
+
+    #[test]
+    fn tells_registries_with_ports_from_tags() {
+        let reference = parse("localhost:5000/tools/jq");
+        assert_eq!(reference.registry, "localhost:5000");
+        assert_eq!(reference.repository, "tools/jq");
+        assert_eq!(reference.tag, None);
+        assert!(reference.is_local());
+
+        let reference = parse("registry.example.com:5000/ubuntu:22.04");
+        assert_eq!(reference.registry, "registry.example.com:5000");
+        assert_eq!(reference.repository, "ubuntu");
+        assert_eq!(reference.tag.as_deref(), Some("22.04"));
+        assert!(!reference.is_local());
+        assert_eq!(reference.api_host(), "registry.example.com:5000");
+        assert_eq!(
+            reference.to_string(),
+            "registry.example.com:5000/ubuntu:22.04"
+        );
+    }
+
+    #[test]
+    fn prefers_digests_to_tags() {
+        let digest = format!("sha256:{}", "ab".repeat(32));
+        let reference = parse(&format!("127.0.0.1:5000/app:1.0@{digest}"));
+        assert_eq!(reference.registry, "127.0.0.1:5000");
+        assert_eq!(reference.tag.as_deref(), Some("1.0"));
+        assert_eq!(reference.digest.as_deref(), Some(digest.as_str()));
+        assert_eq!(reference.manifest_reference(), digest);
+        assert_eq!(reference.clone().or_tag("latest"), reference);
+
+        let pinned = parse("ubuntu:22.04").with_digest(&digest);
+        assert_eq!(pinned.to_string(), format!("ubuntu:22.04@{digest}"));
+    }
+
+    #[test]
+    fn rejects_invalid_references() {
+        for invalid in [
+            "Ubuntu",
+            "tools//jq",
+            "ubuntu:-latest",
+            "ubuntu:22.04!",
+            "ubuntu@sha256",
+            "ubuntu@sha256:xyz",
+        ] {
+            assert!(invalid.parse::<Reference>().is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use crate::reference::Reference;
+use anyhow::{bail, Context, Result};
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::{header, Method, StatusCode};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Environment variables holding credentials for registries requiring a login
+pub const USERNAME_ENV: &str = "ORCA_REGISTRY_USERNAME";
+pub const PASSWORD_ENV: &str = "ORCA_REGISTRY_PASSWORD";
+
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    /// Reads credentials from ORCA_REGISTRY_USERNAME and ORCA_REGISTRY_PASSWORD
+    pub fn from_env() -> Option<Self> {
+        Some(Self {
+            username: std::env::var(USERNAME_ENV).ok()?,
+            password: std::env::var(PASSWORD_ENV).unwrap_or_default(),
+        })
+    }
+}
+
+/// How requests are authorized, learned from the first challenge of the registry
+#[derive(Clone)]
+enum Authorization {
+    Anonymous,
+    Basic,
+    Bearer(String),
+}
+
+#[derive(Deserialize)]
+struct Token {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+/// Client of a registry implementing the OCI Distribution API, for one repository.
+///
+/// Requests are sent anonymously first. If the registry answers 401, the
+/// `WWW-Authenticate` challenge is followed: Basic sends the credentials,
+/// and Bearer gets a token from the realm of the challenge.
+/// The authorization is kept for later requests, so that a client can be shared between threads.
+pub struct RegistryClient {
+    client: Client,
+    base_url: String,
+    repository: String,
+    credentials: Option<Credentials>,
+    authorization: Mutex<Authorization>,
+    /// Scopes asked for tokens besides the one in the challenge
+    scopes: Vec<String>,
+}
+
+impl RegistryClient {
+    /// Client for the repository of `reference`, with credentials from the environment
+    pub fn new(reference: &Reference) -> Self {
+        let scheme = if reference.is_local() {
+            "http"
+        } else {
+            "https"
+        };
+        Self {
+            client: Client::builder()
+                .timeout(None)
+                .build()
+                .expect("HTTP client should be built without TLS configuration"),
+            base_url: format!("{scheme}://{}", reference.api_host()),
+            repository: reference.repository.clone(),
+            credentials: Credentials::from_env(),
+            authorization: Mutex::new(Authorization::Anonymous),
+            scopes: vec![],
+        }
+    }
+
+    /// Asks tokens for pushing to the repository too, and for pulling from `sources`
+    /// (repositories of the same registry) whose blobs may be mounted
+    pub fn for_push(mut self, sources: &[&str]) -> Self {
+        self.scopes = vec![format!("repository:{}:pull,push", self.repository)];
+        self.scopes.extend(
+            sources
+                .iter()
+                .map(|source| format!("repository:{source}:pull")),
+        );
+        self
+    }
+
+    /// Logs in with `credentials` instead of the ones from the environment
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Sends GET (or HEAD if `head` is set) for `/v2/<repository>/manifests/<reference>`
+    pub fn manifest(&self, reference: &str, accept: &[&str], head: bool) -> Result<Response> {
+        let method = if head { Method::HEAD } else { Method::GET };
+        let url = format!(
+            "{}/v2/{}/manifests/{reference}",
+            self.base_url, self.repository
+        );
+        let response = self.send(|client| {
+            client
+                .request(method.clone(), &url)
+                .header(header::ACCEPT, accept.join(", "))
+        })?;
+        if response.status() == StatusCode::NOT_FOUND {
+            let separator = if reference.contains(':') { '@' } else { ':' };
+            bail!(
+                "Image '{}{separator}{reference}' is not found",
+                self.repository
+            );
+        }
+        check(response)
+    }
+
+    /// Sends GET for `/v2/<repository>/blobs/<digest>`
+    pub fn blob(&self, digest: &str) -> Result<Response> {
+        let url = format!("{}/v2/{}/blobs/{digest}", self.base_url, self.repository);
+        check(self.send(|client| client.get(&url))?)
+    }
+
+    /// Whether the repository has blob `digest`, by HEAD for `/v2/<repository>/blobs/<digest>`
+    pub fn has_blob(&self, digest: &str) -> Result<bool> {
+        let url = format!("{}/v2/{}/blobs/{digest}", self.base_url, self.repository);
+        let response = self.send(|client| client.head(&url))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        check(response)?;
+        Ok(true)
+    }
+
+    /// Starts an upload by POST for `/v2/<repository>/blobs/uploads/`, which mounts blob
+    /// `digest` from repository `source` instead if `mount` is given and the registry can.
+    /// Returns the location to upload to, or None if the blob has been mounted.
+    pub fn start_upload(&self, mount: Option<(&str, &str)>) -> Result<Option<String>> {
+        let url = format!("{}/v2/{}/blobs/uploads/", self.base_url, self.repository);
+        let response = self.send(|client| {
+            let request = client.post(&url).header(header::CONTENT_LENGTH, 0);
+            match mount {
+                Some((digest, source)) => request.query(&[("mount", digest), ("from", source)]),
+                None => request,
+            }
+        })?;
+        if response.status() == StatusCode::CREATED && mount.is_some() {
+            return Ok(None);
+        }
+        let response = check(response)?;
+        Ok(Some(self.location(&response)?))
+    }
+
+    /// Sends `chunk` of an upload at `offset` by PATCH, and returns the location to go on with
+    pub fn upload_chunk(&self, location: &str, offset: u64, chunk: &[u8]) -> Result<String> {
+        let range = format!("{offset}-{}", offset + chunk.len() as u64 - 1);
+        let response = check(self.send(|client| {
+            client
+                .patch(location)
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .header(header::CONTENT_RANGE, &range)
+                .body(chunk.to_vec())
+        })?)?;
+        self.location(&response)
+    }
+
+    /// Completes an upload by PUT with the rest of the blob, which is the whole blob
+    /// for monolithic uploads. The registry verifies the blob against `digest`.
+    pub fn finish_upload(&self, location: &str, digest: &str, rest: &[u8]) -> Result<()> {
+        check(self.send(|client| {
+            client
+                .put(location)
+                .query(&[("digest", digest)])
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .body(rest.to_vec())
+        })?)?;
+        Ok(())
+    }
+
+    /// Sends PUT for `/v2/<repository>/manifests/<reference>`,
+    /// and returns the digest of the manifest the registry has stored if it tells
+    pub fn put_manifest(
+        &self,
+        reference: &str,
+        media_type: &str,
+        manifest: &[u8],
+    ) -> Result<Option<String>> {
+        let url = format!(
+            "{}/v2/{}/manifests/{reference}",
+            self.base_url, self.repository
+        );
+        let response = check(self.send(|client| {
+            client
+                .put(&url)
+                .header(header::CONTENT_TYPE, media_type)
+                .body(manifest.to_vec())
+        })?)?;
+        Ok(response
+            .headers()
+            .get("Docker-Content-Digest")
+            .and_then(|value| value.to_str().ok())
+            .map(|digest| digest.to_string()))
+    }
+
+    /// Location of an upload told by `response`, which may be relative to the registry
+    fn location(&self, response: &Response) -> Result<String> {
+        let location = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .context("Registry did not tell where to upload the blob")?;
+        if location.starts_with("http://") || location.starts_with("https://") {
+            Ok(location.to_string())
+        } else {
+            Ok(format!(
+                "{}/{}",
+                self.base_url,
+                location.trim_start_matches('/')
+            ))
+        }
+    }
+
+    /// Sends a request built by `build`, authorizing it again if the registry challenges it
+    fn send<F>(&self, build: F) -> Result<Response>
+    where
+        F: Fn(&Client) -> RequestBuilder,
+    {
+        let authorization = self.authorization.lock().unwrap().clone();
+        let response = self
+            .authorize(build(&self.client), &authorization)
+            .send()
+            .with_context(|| format!("Failed to connect to '{}'", self.base_url))?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        let challenge = response
+            .headers()
+            .get(header::WWW_AUTHENTICATE)
+            .and_then(|value| value.to_str().ok())
+            .context("Registry requires authentication but did not send a challenge")?;
+        let authorization = self.answer(challenge)?;
+        *self.authorization.lock().unwrap() = authorization.clone();
+        let response = self
+            .authorize(build(&self.client), &authorization)
+            .send()
+            .with_context(|| format!("Failed to connect to '{}'", self.base_url))?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            bail!(
+                "Registry '{}' denied access to '{}', check {USERNAME_ENV} and {PASSWORD_ENV}",
+                self.base_url,
+                self.repository
+            );
+        }
+        Ok(response)
+    }
+
+    fn authorize(&self, request: RequestBuilder, authorization: &Authorization) -> RequestBuilder {
+        match (authorization, &self.credentials) {
+            (Authorization::Basic, Some(credentials)) => {
+                request.basic_auth(&credentials.username, Some(&credentials.password))
+            }
+            (Authorization::Bearer(token), _) => request.bearer_auth(token),
+            _ => request,
+        }
+    }
+
+    fn answer(&self, challenge: &str) -> Result<Authorization> {
+        let (scheme, params) = parse_challenge(challenge);
+        if scheme.eq_ignore_ascii_case("basic") {
+            if self.credentials.is_none() {
+                bail!(
+                    "Registry '{}' requires a login, set {USERNAME_ENV} and {PASSWORD_ENV}",
+                    self.base_url
+                );
+            }
+            return Ok(Authorization::Basic);
+        }
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            bail!("Unsupported authentication scheme of registry: '{scheme}'");
+        }
+
+        let realm = params
+            .get("realm")
+            .context("Bearer challenge of registry has no realm")?;
+        let mut scopes: Vec<String> = params.get("scope").cloned().into_iter().collect();
+        for scope in self.scopes.iter() {
+            if !scopes.contains(scope) {
+                scopes.push(scope.clone());
+            }
+        }
+        if scopes.is_empty() {
+            scopes.push(format!("repository:{}:pull", self.repository));
+        }
+        let mut query: Vec<(&str, &str)> = scopes
+            .iter()
+            .map(|scope| ("scope", scope.as_str()))
+            .collect();
+        if let Some(service) = params.get("service") {
+            query.push(("service", service));
+        }
+        let mut request = self.client.get(realm).query(&query);
+        if let Some(credentials) = &self.credentials {
+            request = request.basic_auth(&credentials.username, Some(&credentials.password));
+        }
+        let response = request
+            .send()
+            .with_context(|| format!("Failed to get token from '{realm}'"))?;
+        if matches!(
+            response.status(),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+        ) {
+            bail!(
+                "Registry '{}' denied access to '{}', check {USERNAME_ENV} and {PASSWORD_ENV}",
+                self.base_url,
+                self.repository
+            );
+        }
+        let token: Token = check(response)?
+            .json()
+            .context("Failed to parse token of registry")?;
+        match token.token.or(token.access_token) {
+            Some(token) => Ok(Authorization::Bearer(token)),
+            None => bail!("Token server '{realm}' did not return a token"),
+        }
+    }
+}
+
+/// Splits `Bearer realm="...",service="..."` into the scheme and its parameters
+fn parse_challenge(challenge: &str) -> (String, HashMap<String, String>) {
+    let challenge = challenge.trim();
+    let (scheme, mut rest) = challenge.split_once(' ').unwrap_or((challenge, ""));
+    let mut params = HashMap::new();
+    loop {
+        rest = rest.trim_start_matches([' ', ',']);
+        let Some((key, after)) = rest.split_once('=') else {
+            break;
+        };
+        let (value, after) = match after.strip_prefix('"') {
+            // Quoted values may contain commas, e.g. scope="repository:a:pull,push"
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        params.insert(key.trim().to_ascii_lowercase(), value.to_string());
+        rest = after;
+    }
+    (scheme.to_string(), params)
+}
+
+/// Turns error responses into errors carrying the message from the registry
+fn check(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let url = response.url().clone();
+    let message = response.text().unwrap_or_default();
+    bail!("Registry '{url}' responded {status}: {}", message.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bearer_challenges() {
+        let (scheme, params) = parse_challenge(concat!(
+            r#"Bearer realm="https://auth.docker.io/token","#,
+            r#"service="registry.docker.io","#,
+            r#"scope="repository:library/ubuntu:pull,push""#,
+        ));
+        assert_eq!(scheme, "Bearer");
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["service"], "registry.docker.io");
+        assert_eq!(params["scope"], "repository:library/ubuntu:pull,push");
+    }
+
+    #[test]
+    fn parses_challenges_loosely() {
+        let (scheme, params) = parse_challenge(" Basic Realm=\"stand-in\", charset=UTF-8 ");
+        assert_eq!(scheme, "Basic");
+        assert_eq!(params["realm"], "stand-in");
+        assert_eq!(params["charset"], "UTF-8");
+
+        let (scheme, params) = parse_challenge("Basic");
+        assert_eq!(scheme, "Basic");
+        assert!(params.is_empty());
+    }
+}
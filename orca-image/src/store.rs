@@ -0,0 +1,303 @@
+use crate::config::ImageConfig;
+use crate::digest::VerifyingReader;
+use crate::layer::{extract_layer, Compression, ExtractPolicy, ExtractReport};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use walkdir::WalkDir;
+
+const INDEX_FILE_NAME: &str = "index.toml";
+const LOCK_FILE_NAME: &str = "index.lock";
+const BLOBS_DIR_NAME: &str = "blobs";
+const LAYERS_DIR_NAME: &str = "layers";
+const TMP_DIR_NAME: &str = "tmp";
+/// How long to wait for another process updating the index
+const LOCK_RETRIES: usize = 100;
+const LOCK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Blobs of images shared by all environments, addressed by their digest.
+///
+/// Each layer is kept both as the compressed blob from the registry (`blobs/<algorithm>/<hex>`)
+/// and extracted into a directory usable as a lowerdir (`layers/<algorithm>/<hex>`).
+/// The number of environments using each blob is counted in `index.toml`,
+/// and blobs nobody uses are removed by [`BlobStore::prune`].
+#[derive(Clone)]
+pub struct BlobStore {
+    root: PathBuf,
+    policy: ExtractPolicy,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct StoreIndex {
+    /// Digest to the number of environments using the blob
+    #[serde(default)]
+    refs: BTreeMap<String, u64>,
+}
+
+/// Held while the index is being updated
+struct StoreLock {
+    path: PathBuf,
+}
+
+/// Image whose layers have been added to a [`BlobStore`].
+///
+/// Its layers are already counted as used by one more environment,
+/// which [`BlobStore::release`] undoes if the image ends up unused.
+pub struct StoredImage {
+    /// Digests of the layers from the bottom one
+    pub layers: Vec<String>,
+    pub config: ImageConfig,
+    /// What has been extracted from each layer added to the store, by its digest
+    pub reports: Vec<(String, ExtractReport)>,
+}
+
+/// What [`BlobStore::prune`] has removed
+#[derive(Default)]
+pub struct PruneStats {
+    pub blobs: usize,
+    pub bytes: u64,
+}
+
+impl BlobStore {
+    /// Store extracting layers with the default [`ExtractPolicy`]
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            policy: ExtractPolicy::default(),
+        }
+    }
+
+    pub fn extract_policy(mut self, policy: ExtractPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn blob_path(&self, digest: &str) -> PathBuf {
+        self.root.join(BLOBS_DIR_NAME).join(digest_path(digest))
+    }
+
+    /// Directory the layer of `digest` is extracted into
+    pub fn layer_dir(&self, digest: &str) -> PathBuf {
+        self.root.join(LAYERS_DIR_NAME).join(digest_path(digest))
+    }
+
+    pub fn has_layer(&self, digest: &str) -> bool {
+        self.blob_path(digest).is_file() && self.layer_dir(digest).is_dir()
+    }
+
+    /// Stores a layer blob read from `reader` and extracts it, decompressed by `compression`.
+    /// The blob is verified against `digest` and `size` before anything is extracted.
+    pub fn add_layer<R: Read>(
+        &self,
+        digest: &str,
+        size: u64,
+        compression: Compression,
+        reader: R,
+    ) -> Result<ExtractReport> {
+        let blob_path = self.blob_path(digest);
+        let tmp_path = self.tmp_path(digest)?;
+        let mut blob = VerifyingReader::new(reader, digest, size)?;
+        let mut file = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create: '{}'", tmp_path.display()))?;
+        let copied = io::copy(&mut blob, &mut file);
+        // A broken blob makes copying fail too, but its digest tells why
+        blob.finish()?;
+        copied?;
+        file.sync_all()?;
+        create_parent(&blob_path)?;
+        fs::rename(&tmp_path, &blob_path)?;
+
+        let layer_dir = self.layer_dir(digest);
+        let tmp_dir = self.tmp_path(digest)?;
+        let file = File::open(&blob_path)
+            .with_context(|| format!("Failed to open: '{}'", blob_path.display()))?;
+        let report = extract_layer(
+            compression.decoder(BufReader::new(file))?,
+            &tmp_dir,
+            &self.policy,
+        )?;
+        create_parent(&layer_dir)?;
+        if let Err(e) = fs::rename(&tmp_dir, &layer_dir) {
+            // Someone else has extracted the same layer meanwhile
+            fs::remove_dir_all(&tmp_dir)?;
+            if !layer_dir.is_dir() {
+                Err(e).with_context(|| format!("Failed to add: '{}'", layer_dir.display()))?;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Counts one more environment using each of `digests`
+    pub fn add_refs(&self, digests: &[String]) -> Result<()> {
+        let lock = self.lock()?;
+        let mut index = self.read_index()?;
+        for digest in digests {
+            *index.refs.entry(digest.clone()).or_default() += 1;
+        }
+        self.write_index(&index, &lock)
+    }
+
+    /// Counts one less environment using each of `digests`
+    pub fn release(&self, digests: &[String]) -> Result<()> {
+        let lock = self.lock()?;
+        let mut index = self.read_index()?;
+        for digest in digests {
+            if let Some(refs) = index.refs.get_mut(digest) {
+                *refs = refs.saturating_sub(1);
+            }
+        }
+        self.write_index(&index, &lock)
+    }
+
+    /// Removes blobs no environment uses, including ones left by interrupted downloads
+    pub fn prune(&self) -> Result<PruneStats> {
+        let lock = self.lock()?;
+        let mut index = self.read_index()?;
+        index.refs.retain(|_, refs| *refs > 0);
+
+        let mut stats = PruneStats::default();
+        for dir_name in [BLOBS_DIR_NAME, LAYERS_DIR_NAME] {
+            let dir = self.root.join(dir_name);
+            for algorithm in read_dir_or_empty(&dir)? {
+                for entry in read_dir_or_empty(&algorithm)? {
+                    let digest = format!(
+                        "{}:{}",
+                        algorithm.file_name().unwrap().to_string_lossy(),
+                        entry.file_name().unwrap().to_string_lossy()
+                    );
+                    if index.refs.contains_key(&digest) {
+                        continue;
+                    }
+                    stats.bytes += disk_usage(&entry)?;
+                    if dir_name == BLOBS_DIR_NAME {
+                        stats.blobs += 1;
+                    }
+                    remove(&entry)?;
+                }
+            }
+        }
+        // Files of downloads still running are kept
+        for entry in read_dir_or_empty(&self.root.join(TMP_DIR_NAME))? {
+            let pid = entry
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.rsplit('.').nth(1));
+            if pid.is_some_and(|pid| Path::new("/proc").join(pid).exists()) {
+                continue;
+            }
+            stats.bytes += disk_usage(&entry)?;
+            remove(&entry)?;
+        }
+
+        self.write_index(&index, &lock)?;
+        Ok(stats)
+    }
+
+    /// Unique path under tmp, on the same filesystem as the blobs
+    fn tmp_path(&self, digest: &str) -> Result<PathBuf> {
+        let tmp_dir = self.root.join(TMP_DIR_NAME);
+        fs::create_dir_all(&tmp_dir)
+            .with_context(|| format!("Failed to create dir: '{}'", tmp_dir.display()))?;
+        let name = digest.replace(':', "-");
+        for i in 0.. {
+            let path = tmp_dir.join(format!("{name}.{}.{i}", std::process::id()));
+            if !path.exists() {
+                return Ok(path);
+            }
+        }
+        unreachable!()
+    }
+
+    fn read_index(&self) -> Result<StoreIndex> {
+        let index_file = self.root.join(INDEX_FILE_NAME);
+        match fs::read_to_string(&index_file) {
+            Ok(content) => toml::from_str(&content)
+                .with_context(|| format!("Invalid blob index: '{}'", index_file.display())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(StoreIndex::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read: '{}'", index_file.display())),
+        }
+    }
+
+    /// Replaces the index atomically so that readers never see a partial one
+    fn write_index(&self, index: &StoreIndex, _lock: &StoreLock) -> Result<()> {
+        let index_file = self.root.join(INDEX_FILE_NAME);
+        let tmp_file = self.root.join(format!("{INDEX_FILE_NAME}.tmp"));
+        let mut file = File::create(&tmp_file)
+            .with_context(|| format!("Failed to write: '{}'", tmp_file.display()))?;
+        file.write_all(toml::to_string(index)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_file, &index_file)?;
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<StoreLock> {
+        fs::create_dir_all(&self.root)
+            .with_context(|| format!("Failed to create dir: '{}'", self.root.display()))?;
+        let path = self.root.join(LOCK_FILE_NAME);
+        for _ in 0..LOCK_RETRIES {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(StoreLock { path }),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => thread::sleep(LOCK_INTERVAL),
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Failed to create: '{}'", path.display()))
+                }
+            }
+        }
+        bail!(
+            "Blob store is being updated by someone else. If no orca is running, remove '{}'",
+            path.display()
+        )
+    }
+}
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// 'sha256:abc...' is stored at 'sha256/abc...'
+fn digest_path(digest: &str) -> PathBuf {
+    let (algorithm, hex) = digest.split_once(':').unwrap_or(("unknown", digest));
+    Path::new(algorithm).join(hex)
+}
+
+fn create_parent(path: &Path) -> Result<()> {
+    let parent = path.parent().unwrap();
+    fs::create_dir_all(parent)
+        .with_context(|| format!("Failed to create dir: '{}'", parent.display()))
+}
+
+fn read_dir_or_empty(dir: &Path) -> Result<Vec<PathBuf>> {
+    match fs::read_dir(dir) {
+        Ok(entries) => Ok(entries
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<_>>()?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e).with_context(|| format!("Failed to read: '{}'", dir.display())),
+    }
+}
+
+fn disk_usage(path: &Path) -> Result<u64> {
+    let mut total = 0;
+    for entry in WalkDir::new(path) {
+        total += entry?.metadata()?.blocks() * 512;
+    }
+    Ok(total)
+}
+
+fn remove(path: &Path) -> Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+    .with_context(|| format!("Failed to remove: '{}'", path.display()))
+}
@@ -0,0 +1,117 @@
+//! Round trips of the registry client through the stand-in registry of `examples/registry.rs`
+
+#[allow(dead_code)]
+#[path = "../examples/registry.rs"]
+mod stand_in;
+
+use orca_image::config::ImageConfig;
+use orca_image::downloader::{resolve_digest, ImageDownloader};
+use orca_image::exporter::{ExportLayer, ImageExporter};
+use orca_image::platform::Platform;
+use orca_image::pusher::ImagePusher;
+use orca_image::reference::Reference;
+use orca_image::registry::{Credentials, RegistryClient};
+use orca_image::store::BlobStore;
+use sha2::{Digest, Sha256};
+use stand_in::{Auth, Registry};
+use std::fs;
+use std::path::Path;
+use std::thread;
+
+/// Starts a stand-in registry serving `root`, and returns its address
+fn start_registry(root: &Path, auth: Auth) -> String {
+    let (server, registry) = Registry::bind(root.to_path_buf(), "127.0.0.1:0", auth).unwrap();
+    let addr = registry.addr.clone();
+    thread::spawn(move || registry.serve(&server));
+    addr
+}
+
+fn credentials(password: &str) -> Credentials {
+    Credentials {
+        username: "alice".to_string(),
+        password: password.to_string(),
+    }
+}
+
+#[test]
+fn pushes_and_pulls_images() {
+    let work = tempfile::tempdir().unwrap();
+    let addr = start_registry(&work.path().join("registry"), Auth::Anonymous);
+    let layer = work.path().join("layer");
+    fs::create_dir_all(layer.join("etc")).unwrap();
+    fs::write(layer.join("etc/greeting"), "hello\n").unwrap();
+    let layout = work.path().join("layout");
+    let image = ImageExporter::new(ImageConfig::default(), Platform::host())
+        .add_layer(ExportLayer::Dir(layer))
+        .write_oci_layout(&layout)
+        .unwrap();
+
+    // Blobs larger than a chunk are uploaded in chunks
+    let reference: Reference = format!("{addr}/tools/app:1.0").parse().unwrap();
+    let mut pusher = ImagePusher::new(&reference);
+    pusher.chunk_size(64);
+    let report = pusher.push(&layout, &image).unwrap();
+    assert_eq!(report.uploaded, 2);
+    assert_eq!(resolve_digest(&reference).unwrap(), image.digest);
+
+    let retagged: Reference = format!("{addr}/tools/app:2.0").parse().unwrap();
+    let report = ImagePusher::new(&retagged).push(&layout, &image).unwrap();
+    assert_eq!(report.existing, 2);
+
+    let copy: Reference = format!("{addr}/tools/copy:1.0").parse().unwrap();
+    let mut pusher = ImagePusher::new(&copy);
+    pusher.mount_from(&reference);
+    assert_eq!(pusher.push(&layout, &image).unwrap().mounted, 2);
+
+    let store = BlobStore::new(work.path().join("store"));
+    let stored = ImageDownloader::new(&copy, &store).download().unwrap();
+    assert_eq!(stored.layers, [image.manifest.layers[0].digest.clone()]);
+    let greeting = store.layer_dir(&stored.layers[0]).join("etc/greeting");
+    assert_eq!(fs::read_to_string(greeting).unwrap(), "hello\n");
+
+    // Downloaded layers are already counted as used, so that pruning keeps them
+    store.prune().unwrap();
+    assert!(store.has_layer(&stored.layers[0]));
+    store.release(&stored.layers).unwrap();
+    assert_eq!(store.prune().unwrap().blobs, 1);
+    assert!(!store.has_layer(&stored.layers[0]));
+}
+
+#[test]
+fn logs_in_by_challenges() {
+    let blob = b"blob";
+    let digest = format!("sha256:{:x}", Sha256::digest(blob));
+    for auth in [
+        Auth::Basic("alice:s3cret".to_string()),
+        Auth::Bearer("alice:s3cret".to_string()),
+    ] {
+        let root = tempfile::tempdir().unwrap();
+        let addr = start_registry(root.path(), auth);
+        let reference: Reference = format!("{addr}/tools/app").parse().unwrap();
+
+        let denied = RegistryClient::new(&reference).with_credentials(credentials("wrong"));
+        assert!(denied.has_blob(&digest).is_err());
+
+        let client = RegistryClient::new(&reference)
+            .for_push(&[])
+            .with_credentials(credentials("s3cret"));
+        assert!(!client.has_blob(&digest).unwrap());
+        let location = client.start_upload(None).unwrap().unwrap();
+        client.finish_upload(&location, &digest, blob).unwrap();
+        assert!(client.has_blob(&digest).unwrap());
+        let pulled = client.blob(&digest).unwrap().bytes().unwrap();
+        assert_eq!(pulled.as_ref(), blob);
+    }
+}
+
+#[test]
+fn rejects_blobs_not_matching_digests() {
+    let root = tempfile::tempdir().unwrap();
+    let addr = start_registry(root.path(), Auth::Anonymous);
+    let reference: Reference = format!("{addr}/tools/app").parse().unwrap();
+    let client = RegistryClient::new(&reference);
+    let digest = format!("sha256:{:x}", Sha256::digest(b"blob"));
+    let location = client.start_upload(None).unwrap().unwrap();
+    assert!(client.finish_upload(&location, &digest, b"other").is_err());
+    assert!(!client.has_blob(&digest).unwrap());
+}
@@ -1,5 +1,5 @@
 use chrono::prelude::*;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use sha1::{Digest, Sha1};
 use std::fs::{self, File};
 use std::io::{self, BufWriter, Read, Write};
@@ -17,19 +17,56 @@ struct CommitsData {
     commits: Vec<Commit>,
     head: Head,
     branches: Vec<Branch>,
+    #[serde(default)]
+    reflog: Vec<RefLogEntry>,
+    #[serde(default)]
+    remotes: Vec<Remote>,
+    #[serde(default)]
+    remote_branches: Vec<RemoteBranch>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Head {
     branch_name: String,
-    commit_id: String,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_commit_id",
+        skip_serializing_if = "Option::is_none"
+    )]
+    commit_id: Option<String>,
     detached: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Branch {
     name: String,
-    commit_id: String,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_commit_id",
+        skip_serializing_if = "Option::is_none"
+    )]
+    commit_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Remote {
+    pub name: String,
+    pub url: String,
+}
+
+/// Last known position of a branch in a remote
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemoteBranch {
+    pub remote: String,
+    pub name: String,
+    pub commit_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RefLogEntry {
+    pub commit_id: String,
+    pub date: String,
+    pub message: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -51,8 +88,8 @@ pub struct CommitsIter<T> {
 enum CommitQuery<T> {
     HEAD,
     Branch(T),
+    RemoteBranch(T),
     CommitID(T),
-    Other(T),
 }
 
 #[derive(Error, Debug)]
@@ -66,17 +103,35 @@ pub enum Error {
     #[error("Commits files is invalid format")]
     InvalidFormat,
 
-    #[error("Cannot commit with detached HEAD")]
-    DetachedHEAD,
-
     #[error("Specified branch name already exists")]
     BranchAllreadyExits,
 
+    #[error("Specified branch was not found")]
+    BranchNotFound,
+
+    #[error("Specified branch name is invalid")]
+    InvalidBranchName,
+
     #[error("Specified commit was not found")]
     CommitNotFound,
 
     #[error("Specified commit id matches more than one commits")]
     AmbigousQuery,
+
+    #[error("Specified commit is referenced by other branches or commits")]
+    CommitReferenced,
+
+    #[error("Specified remote name already exists")]
+    RemoteAlreadyExists,
+
+    #[error("Specified remote was not found")]
+    RemoteNotFound,
+
+    #[error("Specified remote name is invalid")]
+    InvalidRemoteName,
+
+    #[error("Specified commit is not a descendant of HEAD")]
+    NotFastForward,
 }
 
 const DEFAULT_BRANCH: &str = "main";
@@ -104,10 +159,10 @@ impl VCS {
         let commits_data = CommitsData::new();
 
         let commits_file_dir = commits_file_path.parent();
-        if let Some(commits_file_dir) = commits_file_dir {
-            if !commits_file_dir.exists() {
-                fs::create_dir_all(commits_file_dir)?;
-            }
+        if let Some(commits_file_dir) = commits_file_dir
+            && !commits_file_dir.exists()
+        {
+            fs::create_dir_all(commits_file_dir)?;
         }
         write_commit_data_to_file(&commits_file_path, &commits_data)?;
 
@@ -128,16 +183,62 @@ impl VCS {
         };
         let new_commit = Commit::new(parent_id, message);
 
-        // TODO: DB
-        // let mut current_tag = self.commits_data.get_current_tag().ok_or(VCSError::DetachedHEAD)?;
-        // current_tag.commit_id = new_commit.id.clone();
-        // self.commits_data.update_tag(current_tag);
-        //
+        if let Some(branch) = self.commits_data.get_current_branch_mut() {
+            branch.commit_id = Some(new_commit.id.clone());
+        }
+        self.commits_data.head.commit_id = Some(new_commit.id.clone());
+        self.commits_data
+            .add_reflog(&new_commit.id, reflog_message("commit", &new_commit));
+        self.commits_data.add_commit(new_commit);
+
+        write_commit_data_to_file(&self.commits_file_path, &self.commits_data)?;
+
+        Ok(self
+            .commits_data
+            .get_commit_by(CommitQuery::<&str>::HEAD)
+            .unwrap())
+    }
+
+    /// Builds a commit to replace HEAD commit with, which has the same parent.
+    /// Nothing is written until it is given to [`VCS::amend`], so that the caller can
+    /// prepare the layer of the new commit first.
+    /// Returns the ID of the commit to be replaced and the new commit.
+    pub fn prepare_amend<S>(&self, message: Option<S>) -> Result<(String, Commit)>
+    where
+        S: ToString,
+    {
+        let head_commit = self.commits_data.get_commit_by(CommitQuery::<&str>::HEAD)?;
+        if self.commits_data.is_referenced(&head_commit.id) {
+            Err(Error::CommitReferenced)?;
+        }
+        let message = message
+            .map(|s| s.to_string())
+            .or(head_commit.message.clone());
+        let new_commit = Commit::new(head_commit.parent_id.as_ref(), message);
+        Ok((head_commit.id.clone(), new_commit))
+    }
+
+    /// Replaces HEAD commit with `new_commit` built by [`VCS::prepare_amend`]
+    pub fn amend(&mut self, new_commit: Commit) -> Result<&Commit> {
+        let head_commit = self
+            .commits_data
+            .get_commit_by(CommitQuery::<&str>::HEAD)?
+            .clone();
+        if self.commits_data.is_referenced(&head_commit.id) {
+            Err(Error::CommitReferenced)?;
+        }
+
+        if let Some(branch) = self.commits_data.get_current_branch_mut() {
+            branch.commit_id = Some(new_commit.id.clone());
+        }
+        self.commits_data.head.commit_id = Some(new_commit.id.clone());
+        self.commits_data.add_reflog(
+            &new_commit.id,
+            reflog_message("commit (amend)", &new_commit),
+        );
         self.commits_data
-            .get_current_branch_mut()
-            .ok_or(Error::DetachedHEAD)?
-            .commit_id = new_commit.id.clone();
-        self.commits_data.head.commit_id = new_commit.id.clone();
+            .commits
+            .retain(|commit| commit.id != head_commit.id);
         self.commits_data.add_commit(new_commit);
 
         write_commit_data_to_file(&self.commits_file_path, &self.commits_data)?;
@@ -152,12 +253,167 @@ impl VCS {
         self.commits_data.get_commits_by(CommitQuery::<&str>::HEAD)
     }
 
-    pub fn get_current_branch(&self) -> &str {
+    pub fn get_commits<S: ToString>(&self, query: S) -> Result<CommitsIter<&Commit>> {
+        let commit_query = create_commit_query_from(query, &self.commits_data);
+        self.commits_data.get_commits_by(commit_query)
+    }
+
+    pub fn get_head_commit(&self) -> Result<&Commit> {
+        self.commits_data.get_commit_by(CommitQuery::<&str>::HEAD)
+    }
+
+    pub fn get_commit<S: ToString>(&self, query: S) -> Result<&Commit> {
+        let commit_query = create_commit_query_from(query, &self.commits_data);
+        self.commits_data.get_commit_by(commit_query)
+    }
+
+    /// Returns true if `ancestor` is `descendant` itself or one of its ancestors
+    pub fn is_ancestor<S1, S2>(&self, ancestor: S1, descendant: S2) -> Result<bool>
+    where
+        S1: ToString,
+        S2: ToString,
+    {
+        let ancestor_id = self.get_commit(ancestor)?.id.clone();
+        Ok(self
+            .get_commits(descendant)?
+            .any(|commit| commit.id == ancestor_id))
+    }
+
+    /// Adds commits made in another environment, ordered from the oldest one.
+    /// Commits which already exist are skipped. Returns the number of added commits.
+    pub fn import_commits(&mut self, commits: Vec<Commit>) -> Result<usize> {
+        let mut added = 0;
+        for commit in commits {
+            if self.commits_data.has_commit(&commit.id) {
+                continue;
+            }
+            if let Some(parent_id) = commit.parent_id.as_deref()
+                && !self.commits_data.has_commit(parent_id)
+            {
+                Err(Error::CommitNotFound)?;
+            }
+            self.commits_data.add_commit(commit);
+            added += 1;
+        }
+        write_commit_data_to_file(&self.commits_file_path, &self.commits_data)?;
+        Ok(added)
+    }
+
+    /// Moves the current branch (or detached HEAD) forward to `query`,
+    /// which must be a descendant of HEAD
+    pub fn fast_forward<S: ToString>(&mut self, query: S) -> Result<()> {
+        let query = query.to_string();
+        let commit_id = self.get_commit(&query)?.id.clone();
+        if let Some(head_id) = self.commits_data.head.commit_id.as_deref()
+            && !self.is_ancestor(head_id, &commit_id)?
+        {
+            Err(Error::NotFastForward)?;
+        }
+        if let Some(branch) = self.commits_data.get_current_branch_mut() {
+            branch.commit_id = Some(commit_id.clone());
+        }
+        self.commits_data.head.commit_id = Some(commit_id.clone());
+        self.commits_data
+            .add_reflog(commit_id, format!("merge {query}: Fast-forward"));
+        write_commit_data_to_file(&self.commits_file_path, &self.commits_data)?;
+        Ok(())
+    }
+
+    pub fn add_remote<S1, S2>(&mut self, name: S1, url: S2) -> Result<()>
+    where
+        S1: ToString,
+        S2: ToString,
+    {
+        let name = name.to_string();
+        if name.is_empty() || name.contains('/') || name.contains(char::is_whitespace) {
+            Err(Error::InvalidRemoteName)?;
+        }
+        if self.get_remote(&name).is_ok() {
+            Err(Error::RemoteAlreadyExists)?;
+        }
+        self.commits_data.remotes.push(Remote {
+            name,
+            url: url.to_string(),
+        });
+        write_commit_data_to_file(&self.commits_file_path, &self.commits_data)?;
+        Ok(())
+    }
+
+    /// Removes a remote and its remote-tracking branches
+    pub fn remove_remote(&mut self, name: &str) -> Result<()> {
+        self.get_remote(name)?;
+        self.commits_data
+            .remotes
+            .retain(|remote| remote.name != name);
+        self.commits_data
+            .remote_branches
+            .retain(|branch| branch.remote != name);
+        write_commit_data_to_file(&self.commits_file_path, &self.commits_data)?;
+        Ok(())
+    }
+
+    pub fn get_remote(&self, name: &str) -> Result<&Remote> {
+        self.commits_data
+            .remotes
+            .iter()
+            .find(|remote| remote.name == name)
+            .ok_or(Error::RemoteNotFound)
+    }
+
+    pub fn get_remotes(&self) -> &[Remote] {
+        &self.commits_data.remotes
+    }
+
+    /// Returns remote-tracking branches of all remotes
+    pub fn get_remote_branches(&self) -> &[RemoteBranch] {
+        &self.commits_data.remote_branches
+    }
+
+    /// Records that `branch` of `remote` points to `commit_id`
+    pub fn update_remote_branch<S1, S2, S3>(
+        &mut self,
+        remote: S1,
+        branch: S2,
+        commit_id: S3,
+    ) -> Result<()>
+    where
+        S1: ToString,
+        S2: ToString,
+        S3: ToString,
+    {
+        let (remote, name) = (remote.to_string(), branch.to_string());
+        let commit_id = commit_id.to_string();
+        match self
+            .commits_data
+            .remote_branches
+            .iter_mut()
+            .find(|branch| branch.remote == remote && branch.name == name)
+        {
+            Some(branch) => branch.commit_id = commit_id,
+            None => self.commits_data.remote_branches.push(RemoteBranch {
+                remote,
+                name,
+                commit_id,
+            }),
+        }
+        write_commit_data_to_file(&self.commits_file_path, &self.commits_data)?;
+        Ok(())
+    }
+
+    pub fn is_detached(&self) -> bool {
+        self.commits_data.head.detached
+    }
+
+    /// Returns HEAD history, from the newest entry
+    pub fn get_reflog(&self) -> impl Iterator<Item = &RefLogEntry> {
+        self.commits_data.reflog.iter().rev()
+    }
+
+    /// Returns `None` if HEAD is detached
+    pub fn get_current_branch(&self) -> Option<&str> {
         self.commits_data
             .get_current_branch()
-            .unwrap()
-            .name
-            .as_str()
+            .map(|branch| branch.name.as_str())
     }
 
     pub fn get_all_branches(&self) -> Vec<&str> {
@@ -168,21 +424,63 @@ impl VCS {
             .collect()
     }
 
-    pub fn create_branch<S: ToString>(&mut self, name: S) -> anyhow::Result<()> {
-        if self
-            .commits_data
-            .get_all_branches()
-            .iter()
-            .any(|tag| tag.name == name.to_string())
-        {
-            Err(Error::BranchAllreadyExits)?;
-        }
+    /// Creates a branch pointing to `start_point` (HEAD if `None`)
+    pub fn create_branch<S1, S2>(&mut self, name: S1, start_point: Option<S2>) -> Result<()>
+    where
+        S1: ToString,
+        S2: ToString,
+    {
+        let name = name.to_string();
+        self.validate_new_branch_name(&name)?;
+
+        let start_commit_id = match start_point {
+            Some(start_point) => {
+                let query = create_commit_query_from(start_point, &self.commits_data);
+                match query {
+                    CommitQuery::Branch(branch_name) => self
+                        .commits_data
+                        .get_branch(&branch_name)
+                        .and_then(|branch| branch.commit_id.clone()),
+                    query => Some(self.commits_data.get_commit_by(query)?.id.clone()),
+                }
+            }
+            None => self.commits_data.head.commit_id.clone(),
+        };
+        self.commits_data.add_branch(name, start_commit_id);
+
+        write_commit_data_to_file(&self.commits_file_path, &self.commits_data)?;
+        Ok(())
+    }
+
+    /// Creates a branch which does not have any commits
+    pub fn create_orphan_branch<S: ToString>(&mut self, name: S) -> Result<()> {
+        let name = name.to_string();
+        self.validate_new_branch_name(&name)?;
+        self.commits_data.add_branch(name, None::<String>);
+
+        write_commit_data_to_file(&self.commits_file_path, &self.commits_data)?;
+        Ok(())
+    }
+
+    pub fn rename_branch<S1, S2>(&mut self, old_name: S1, new_name: S2) -> Result<()>
+    where
+        S1: ToString,
+        S2: ToString,
+    {
+        let old_name = old_name.to_string();
+        let new_name = new_name.to_string();
+        self.validate_new_branch_name(&new_name)?;
 
-        let latest_commit_id = self
+        let branch = self
             .commits_data
-            .get_commit_by(CommitQuery::<&str>::HEAD)
-            .map_or(String::from("none"), |commit| commit.id.clone());
-        self.commits_data.add_branch(name, latest_commit_id);
+            .branches
+            .iter_mut()
+            .find(|branch| branch.name == old_name)
+            .ok_or(Error::BranchNotFound)?;
+        branch.name = new_name.clone();
+        if self.commits_data.head.branch_name == old_name {
+            self.commits_data.head.branch_name = new_name;
+        }
 
         write_commit_data_to_file(&self.commits_file_path, &self.commits_data)?;
         Ok(())
@@ -193,20 +491,43 @@ impl VCS {
     }
 
     pub fn checkout<S: ToString>(&mut self, query: S) -> Result<()> {
-        let commit_query = create_commit_query_from(query, &self.commits_data);
-        let commit = self.commits_data.get_commit_by(commit_query.clone())?;
-        self.commits_data.head.commit_id = commit.id.clone();
+        let query = query.to_string();
+        let commit_query = create_commit_query_from(&query, &self.commits_data);
         match commit_query {
-            CommitQuery::Branch(tag) => {
-                self.commits_data.head.branch_name = tag;
+            CommitQuery::Branch(branch_name) => {
+                let branch = self
+                    .commits_data
+                    .get_branch(&branch_name)
+                    .ok_or(Error::BranchNotFound)?;
+                self.commits_data.head.commit_id = branch.commit_id.clone();
+                self.commits_data.head.branch_name = branch_name;
                 self.commits_data.head.detached = false;
             }
-            CommitQuery::CommitID(_) => self.commits_data.head.detached = true,
-            _ => {}
+            commit_query => {
+                let commit = self.commits_data.get_commit_by(commit_query.clone())?;
+                self.commits_data.head.commit_id = Some(commit.id.clone());
+                if commit_query.is_commit() || commit_query.is_remote_branch() {
+                    self.commits_data.head.detached = true;
+                }
+            }
+        }
+        if let Some(commit_id) = self.commits_data.head.commit_id.clone() {
+            self.commits_data
+                .add_reflog(commit_id, format!("checkout: moving to {}", query));
         }
         write_commit_data_to_file(&self.commits_file_path, &self.commits_data)?;
         Ok(())
     }
+
+    fn validate_new_branch_name(&self, name: &str) -> Result<()> {
+        if name.is_empty() || name == "HEAD" || name.contains(char::is_whitespace) {
+            Err(Error::InvalidBranchName)?;
+        }
+        if self.commits_data.get_branch(name).is_some() {
+            Err(Error::BranchAllreadyExits)?;
+        }
+        Ok(())
+    }
 }
 
 fn write_commit_data_to_file<P: AsRef<Path>>(
@@ -220,6 +541,13 @@ fn write_commit_data_to_file<P: AsRef<Path>>(
     Ok(())
 }
 
+fn reflog_message(action: &str, commit: &Commit) -> String {
+    match commit.message.as_ref() {
+        Some(message) => format!("{action}: {message}"),
+        None => action.to_string(),
+    }
+}
+
 fn create_commit_query_from<S: ToString>(
     query: S,
     commits_data: &CommitsData,
@@ -233,6 +561,8 @@ fn create_commit_query_from<S: ToString>(
         .any(|tag| tag.name.as_str() == query)
     {
         CommitQuery::Branch(query)
+    } else if commits_data.get_remote_branch(&query).is_some() {
+        CommitQuery::RemoteBranch(query)
     } else {
         CommitQuery::CommitID(query)
     }
@@ -242,35 +572,78 @@ impl CommitsData {
     fn new() -> Self {
         let head = Head {
             branch_name: DEFAULT_BRANCH.to_string(),
-            commit_id: "None".to_string(),
+            commit_id: None,
             detached: false,
         };
         let tags = vec![Branch {
             name: DEFAULT_BRANCH.to_string(),
-            commit_id: "None".to_string(),
+            commit_id: None,
         }];
         Self {
             commits: vec![],
             head,
             branches: tags,
+            reflog: vec![],
+            remotes: vec![],
+            remote_branches: vec![],
         }
     }
 
+    fn add_reflog<S1, S2>(&mut self, commit_id: S1, message: S2)
+    where
+        S1: ToString,
+        S2: ToString,
+    {
+        self.reflog.push(RefLogEntry {
+            commit_id: commit_id.to_string(),
+            date: Local::now().to_string(),
+            message: message.to_string(),
+        })
+    }
+
+    /// Returns true if any commit or branch other than the current one refers to `commit_id`
+    fn is_referenced(&self, commit_id: &str) -> bool {
+        let current_branch = self.get_current_branch().map(|branch| branch.name.as_str());
+        self.commits
+            .iter()
+            .any(|commit| commit.parent_id.as_deref() == Some(commit_id))
+            || self.branches.iter().any(|branch| {
+                branch.commit_id.as_deref() == Some(commit_id)
+                    && Some(branch.name.as_str()) != current_branch
+            })
+    }
+
+    fn has_commit(&self, commit_id: &str) -> bool {
+        self.commits.iter().any(|commit| commit.id == commit_id)
+    }
+
     fn add_commit(&mut self, commit: Commit) {
         self.commits.push(commit);
     }
 
-    fn add_branch<S1, S2>(&mut self, name: S1, commit_id: S2)
+    fn add_branch<S1, S2>(&mut self, name: S1, commit_id: Option<S2>)
     where
         S1: ToString,
         S2: ToString,
     {
         self.branches.push(Branch {
             name: name.to_string(),
-            commit_id: commit_id.to_string(),
+            commit_id: commit_id.map(|id| id.to_string()),
         })
     }
 
+    fn get_branch(&self, name: &str) -> Option<&Branch> {
+        self.branches.iter().find(|branch| branch.name == name)
+    }
+
+    /// Finds a remote-tracking branch by '<remote>/<branch>'
+    fn get_remote_branch(&self, query: &str) -> Option<&RemoteBranch> {
+        let (remote, name) = query.split_once('/')?;
+        self.remote_branches
+            .iter()
+            .find(|branch| branch.remote == remote && branch.name == name)
+    }
+
     fn get_current_branch(&self) -> Option<&Branch> {
         if self.head.detached {
             return None;
@@ -322,24 +695,26 @@ fn get_commit_id_from_query<S: AsRef<str>>(
     query: CommitQuery<S>,
 ) -> Option<String> {
     match query {
-        CommitQuery::HEAD => Some(commits_data.head.commit_id.clone()),
+        CommitQuery::HEAD => commits_data.head.commit_id.clone(),
         CommitQuery::Branch(tag_name) => commits_data
-            .branches
-            .iter()
-            .find(|tag| tag.name.as_str() == tag_name.as_ref())
+            .get_branch(tag_name.as_ref())
+            .and_then(|branch| branch.commit_id.clone()),
+        CommitQuery::RemoteBranch(query) => commits_data
+            .get_remote_branch(query.as_ref())
             .map(|branch| branch.commit_id.clone()),
         CommitQuery::CommitID(id) => Some(id.as_ref().to_string()),
-        CommitQuery::Other(query) => {
-            match get_commit_id_from_query(commits_data, CommitQuery::Branch(query.as_ref())) {
-                Some(commit_id) => Some(commit_id),
-                None => {
-                    get_commit_id_from_query(commits_data, CommitQuery::CommitID(query.as_ref()))
-                }
-            }
-        }
     }
 }
 
+/// Reads a commit ID which older versions wrote as "None" or "none" when there was no commit
+fn deserialize_commit_id<'de, D>(deserializer: D) -> std::result::Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let commit_id = Option::<String>::deserialize(deserializer)?;
+    Ok(commit_id.filter(|id| !id.eq_ignore_ascii_case("none")))
+}
+
 impl Commit {
     fn new<S1, S2>(parent_id: Option<S1>, message: Option<S2>) -> Self
     where
@@ -364,6 +739,18 @@ impl Commit {
     }
 }
 
+impl Commit {
+    pub fn parent_id(&self) -> Option<&str> {
+        self.parent_id.as_deref()
+    }
+
+    /// Whether `id` has the format of commit IDs (40 lowercase hex digits).
+    /// IDs name layer directories, so ones read from outside must be checked with this.
+    pub fn is_valid_id(id: &str) -> bool {
+        id.len() == 40 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    }
+}
+
 impl AsRef<Commit> for Commit {
     fn as_ref(&self) -> &Commit {
         self
@@ -400,6 +787,10 @@ impl<T> CommitQuery<T> {
     pub fn is_commit(&self) -> bool {
         matches!(self, CommitQuery::CommitID(_))
     }
+
+    pub fn is_remote_branch(&self) -> bool {
+        matches!(self, CommitQuery::RemoteBranch(_))
+    }
 }
 
 impl<T: Clone> Clone for CommitQuery<T> {
@@ -407,8 +798,8 @@ impl<T: Clone> Clone for CommitQuery<T> {
         match self {
             CommitQuery::HEAD => CommitQuery::HEAD,
             CommitQuery::Branch(t) => CommitQuery::Branch(t.clone()),
+            CommitQuery::RemoteBranch(t) => CommitQuery::RemoteBranch(t.clone()),
             CommitQuery::CommitID(c) => CommitQuery::CommitID(c.clone()),
-            CommitQuery::Other(q) => CommitQuery::Other(q.clone()),
         }
     }
 }
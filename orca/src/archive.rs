@@ -0,0 +1,229 @@
+use anyhow::{bail, Context, Result};
+use orca_image::layer::{ExtractPolicy, LayerExtractor};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use tar::{Archive, Builder, EntryType, Header, HeaderMode};
+use walkdir::WalkDir;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+/// Top directory of entries in archives written by `pack_layer`
+const LAYER_PREFIX: &str = "layer";
+
+/// Creates a tar builder writing to `writer`, compressed with zstd if `zstd` is true
+pub fn new_builder<'a, W: Write + 'a>(
+    writer: W,
+    zstd: bool,
+) -> Result<Builder<Box<dyn Write + 'a>>> {
+    let writer: Box<dyn Write> = if zstd {
+        Box::new(zstd::Encoder::new(writer, 0)?.auto_finish())
+    } else {
+        Box::new(writer)
+    };
+    let mut builder = Builder::new(writer);
+    builder.follow_symlinks(false);
+    builder.mode(HeaderMode::Complete);
+    Ok(builder)
+}
+
+/// Opens a tar archive at `file_path`, which may be compressed with zstd
+pub fn open<P: AsRef<Path>>(file_path: P) -> Result<Archive<Box<dyn Read>>> {
+    let file_path = file_path.as_ref();
+    let mut file = File::open(file_path)
+        .with_context(|| format!("Failed to open '{}'", file_path.display()))?;
+    let mut magic = [0u8; 4];
+    let is_zstd = file.read_exact(&mut magic).is_ok() && magic == ZSTD_MAGIC;
+    file.rewind()?;
+    let reader: Box<dyn Read> = if is_zstd {
+        Box::new(zstd::Decoder::new(file)?)
+    } else {
+        Box::new(file)
+    };
+
+    let mut archive = Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(true);
+    archive.set_preserve_mtime(true);
+    // Xattrs are set by `LayerExtractor`, which keeps them inside the layer
+    archive.set_unpack_xattrs(false);
+    Ok(archive)
+}
+
+/// Adds the whole `layer_dir` to `builder` under `prefix`, keeping ownership, devices and xattrs
+pub fn append_layer<W, P, Q>(builder: &mut Builder<W>, layer_dir: P, prefix: Q) -> Result<()>
+where
+    W: Write,
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let layer_dir = layer_dir.as_ref();
+    for entry in WalkDir::new(layer_dir).sort_by_file_name() {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(layer_dir).unwrap();
+        let name = prefix.as_ref().join(relative);
+        append_xattrs(builder, entry.path())?;
+        append_entry(builder, entry.path(), &name)
+            .with_context(|| format!("Failed to add '{}'", entry.path().display()))?;
+    }
+    Ok(())
+}
+
+/// Writes `layer_dir` into `file_path` as a zstd compressed tar
+pub fn pack_layer<P, Q>(layer_dir: P, file_path: Q) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let file_path = file_path.as_ref();
+    let file = File::create(file_path)
+        .with_context(|| format!("Failed to create '{}'", file_path.display()))?;
+    let mut builder = new_builder(file, true)?;
+    append_layer(&mut builder, layer_dir, LAYER_PREFIX)?;
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+/// Extracts a tar written by [`pack_layer`] into `dest`, which must not exist
+pub fn unpack_layer<P, Q>(file_path: P, dest: Q) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let mut archive = open(file_path)?;
+    let mut extractor = layer_extractor(dest)?;
+    for entry in archive.entries()? {
+        let mut entry = entry.context("Broken layer archive")?;
+        let path = entry.path()?.into_owned();
+        let Ok(relative) = path.strip_prefix(LAYER_PREFIX) else {
+            bail!("Unexpected entry in layer archive: '{}'", path.display());
+        };
+        extractor.extract(&mut entry, relative)?;
+    }
+    extractor.finish()?;
+    Ok(())
+}
+
+/// Extractor of entries written by [`append_layer`] into `dest`.
+/// Layers are kept as they are, with their whiteouts, devices and opaque dirs,
+/// but nothing is written outside of `dest`.
+pub fn layer_extractor<P: AsRef<Path>>(dest: P) -> Result<LayerExtractor> {
+    let policy = ExtractPolicy {
+        allow_devices: true,
+        overlay_format: true,
+    };
+    LayerExtractor::new(dest, &policy)
+}
+
+fn append_entry<W: Write>(builder: &mut Builder<W>, path: &Path, name: &Path) -> Result<()> {
+    let metadata = path.symlink_metadata()?;
+    let file_type = metadata.file_type();
+    let entry_type = if file_type.is_char_device() {
+        EntryType::Char
+    } else if file_type.is_block_device() {
+        EntryType::Block
+    } else if file_type.is_fifo() {
+        EntryType::Fifo
+    } else {
+        builder.append_path_with_name(path, name)?;
+        return Ok(());
+    };
+
+    // Devices (e.g. whiteouts) are written by hand, tar cannot add them from paths
+    let mut header = Header::new_gnu();
+    header.set_metadata_in_mode(&metadata, HeaderMode::Complete);
+    header.set_entry_type(entry_type);
+    header.set_size(0);
+    header.set_device_major(nix::sys::stat::major(metadata.rdev()) as u32)?;
+    header.set_device_minor(nix::sys::stat::minor(metadata.rdev()) as u32)?;
+    builder.append_data(&mut header, name, io::empty())?;
+    Ok(())
+}
+
+/// Adds a PAX header carrying xattrs of `path` for the entry appended next
+fn append_xattrs<W: Write>(builder: &mut Builder<W>, path: &Path) -> Result<()> {
+    let mut records = Vec::new();
+    let mut names: Vec<_> = xattr::list(path)?.collect();
+    names.sort();
+    for name in names {
+        let Some(value) = xattr::get(path, &name)? else {
+            continue;
+        };
+        let key = [PAX_XATTR_PREFIX.as_bytes(), name.as_bytes()].concat();
+        // "<length> <key>=<value>\n", where length includes its own digits
+        let body_len = key.len() + value.len() + 3;
+        let mut len = body_len + 1;
+        while len != body_len + len.to_string().len() {
+            len = body_len + len.to_string().len();
+        }
+        records.extend_from_slice(format!("{len} ").as_bytes());
+        records.extend_from_slice(&key);
+        records.push(b'=');
+        records.extend_from_slice(&value);
+        records.push(b'\n');
+    }
+    if records.is_empty() {
+        return Ok(());
+    }
+    let mut header = Header::new_ustar();
+    header.set_entry_type(EntryType::XHeader);
+    header.set_size(records.len() as u64);
+    header.set_mode(0o644);
+    header.set_path("././@PaxHeader")?;
+    header.set_cksum();
+    builder.append(&header, records.as_slice())?;
+    Ok(())
+}
+
+/// Digest of a layer covering paths, file types, ownership, modes, devices, xattrs and contents
+pub fn layer_digest<P: AsRef<Path>>(layer_dir: P) -> Result<String> {
+    let layer_dir = layer_dir.as_ref();
+    let mut hasher = Sha256::new();
+    for entry in WalkDir::new(layer_dir).sort_by_file_name() {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        let relative = path.strip_prefix(layer_dir).unwrap();
+        hasher.update(relative.as_os_str().as_bytes());
+        hasher.update(
+            format!(
+                "\0{:o} {} {} {}\0",
+                metadata.mode(),
+                metadata.uid(),
+                metadata.gid(),
+                metadata.rdev()
+            )
+            .as_bytes(),
+        );
+
+        let mut names: Vec<_> = xattr::list(path)?.collect();
+        names.sort();
+        for name in names {
+            if let Some(value) = xattr::get(path, &name)? {
+                hasher.update(name.as_bytes());
+                hasher.update(b"=");
+                hasher.update(&value);
+                hasher.update(b"\0");
+            }
+        }
+
+        let file_type = metadata.file_type();
+        if file_type.is_file() {
+            let mut file = File::open(path)?;
+            io::copy(&mut file, &mut hasher)?;
+        } else if file_type.is_symlink() {
+            hasher.update(fs::read_link(path)?.as_os_str().as_bytes());
+        } else if !(file_type.is_dir()
+            || file_type.is_char_device()
+            || file_type.is_block_device()
+            || file_type.is_fifo())
+        {
+            bail!("Unsupported file type: '{}'", path.display());
+        }
+        hasher.update(b"\0");
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -1,4 +1,4 @@
-use clap::{Args as ArgsDerive, Parser, Subcommand};
+use clap::{Args as ArgsDerive, Parser, Subcommand, ValueEnum};
 use std::{
     env,
     path::{Path, PathBuf},
@@ -24,7 +24,7 @@ pub struct Args {
     pub root: String,
 
     #[command(subcommand)]
-    pub action: Action,
+    pub action: Option<Action>,
 }
 
 #[derive(Debug, Subcommand)]
@@ -39,6 +39,9 @@ pub enum Action {
     /// Show commit logs
     Log(LogArgs),
 
+    /// Show history of HEAD
+    Reflog,
+
     /// Record changes
     Commit(CommitArgs),
 
@@ -51,29 +54,83 @@ pub enum Action {
     /// Show changes between commits
     Diff,
 
+    /// Show current branch and uncommitted changes
+    Status,
+
     /// Delete uncommited chenges
-    Clean,
+    Clean(CleanArgs),
 
     /// Reset current branch to specified commit
     Reset(TargetArgs),
 
     /// Switch branches
-    Checkout(TargetArgs),
+    Checkout(CheckoutArgs),
+
+    /// Restore specified files from a commit
+    Restore(RestoreArgs),
+
+    /// Find the commit which introduced a change by binary search
+    Bisect(BisectArgs),
+
+    /// Show which commits changed a path, newest first
+    Blame(BlameArgs),
+
+    /// Create or import a bundle file which carries commits and their layers
+    Bundle(BundleArgs),
+
+    /// Write a commit as an OCI image, the layers of the base image followed by a layer per commit
+    Export(ExportArgs),
+
+    /// Create a new environment sharing the history of an existing one
+    Clone(CloneArgs),
+
+    /// List, inspect, rename or remove environments
+    Env(EnvArgs),
+
+    /// Manage container images shared by environments
+    Image(ImageArgs),
+
+    /// Manage remotes to share environments through
+    Remote(RemoteArgs),
+
+    /// Send a branch and the layers a remote does not have,
+    /// or push a revision as a container image to a registry
+    Push(PushArgs),
+
+    /// Download branches and missing layers from a remote
+    Fetch(FetchArgs),
+
+    /// Fetch from a remote and fast-forward the current branch
+    Pull(FetchArgs),
 }
 
 #[derive(Debug, ArgsDerive)]
 pub struct InitArgs {
     /// Use specified container image instead of Host image
-    #[arg(short, long)]
+    /// ([registry[:port]/]name[:tag][@digest], e.g. 'ubuntu:22.04', 'localhost:5000/tools/jq')
+    #[arg(short, long, conflicts_with_all = ["from_archive", "from_oci_layout"])]
     pub image: Option<String>,
 
-    /// Tag name of specified container image
+    /// Use image in a tarball of 'docker save' or of an OCI image layout, without registry
+    #[arg(long, value_name = "FILE", conflicts_with = "from_oci_layout")]
+    pub from_archive: Option<PathBuf>,
+
+    /// Use image in an OCI image layout directory, without registry
+    #[arg(long, value_name = "DIR")]
+    pub from_oci_layout: Option<PathBuf>,
+
+    /// Tag name of specified container image, unless --image has a tag or a digest
+    /// (for archives and layouts, chooses the image if they have several)
     #[arg(short, long, default_value = "latest")]
     pub tag: String,
 
-    /// Assign a name to created environment
-    #[arg(short, long, default_value = "_default")]
-    pub name: String,
+    /// Platform chosen from multi-platform images, as os/arch[/variant] [default: host]
+    #[arg(long)]
+    pub platform: Option<String>,
+
+    /// Assign a name to created environment [default: --name given before 'init']
+    #[arg(short, long)]
+    pub name: Option<String>,
 }
 
 #[derive(Debug, ArgsDerive)]
@@ -82,7 +139,7 @@ pub struct RunArgs {
     #[arg(long, default_value_t = false)]
     pub netns: bool,
 
-    /// Command to execute
+    /// Command to execute [default: $SHELL, or Entrypoint and Cmd of the image]
     pub command: Option<String>,
 
     /// Arguments of command
@@ -102,6 +159,29 @@ pub struct CommitArgs {
     /// Commit message
     #[arg(short, long)]
     pub message: Option<String>,
+
+    /// Commit only changes under specified path (can be repeated)
+    #[arg(short, long = "path", value_name = "PATH")]
+    pub paths: Vec<String>,
+
+    /// Leave changes under specified path uncommitted (can be repeated)
+    #[arg(short, long = "exclude", value_name = "PATH")]
+    pub excludes: Vec<String>,
+
+    /// Delete changes ignored by .orcaignore after commit
+    #[arg(long, default_value_t = false)]
+    pub clean_ignored: bool,
+
+    /// Merge changes into the last commit instead of creating a new one
+    #[arg(long, default_value_t = false)]
+    pub amend: bool,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct CleanArgs {
+    /// Delete only changes ignored by .orcaignore
+    #[arg(short, long, default_value_t = false)]
+    pub ignored: bool,
 }
 
 #[derive(Debug, ArgsDerive)]
@@ -109,15 +189,312 @@ pub struct BranchArgs {
     /// Branch name you want to create or delete
     pub branch_name: Option<String>,
 
+    /// Commit ID or branch where created branch starts [default: HEAD]
+    /// (With --move, new branch name)
+    #[arg(requires = "branch_name")]
+    pub start_point: Option<String>,
+
     /// Delete specified branch
     #[arg(short, long, requires = "branch_name")]
     pub delete: bool,
 
+    /// Rename branch ('branch -m <new>' renames current branch)
+    #[arg(short = 'm', long = "move", requires = "branch_name")]
+    pub rename: bool,
+
     /// Show all branches
     #[arg(short, long, exclusive = true)]
     pub all: bool,
 }
 
+#[derive(Debug, ArgsDerive)]
+pub struct CheckoutArgs {
+    /// Commit ID or branch (With -b, start point of new branch [default: HEAD])
+    #[arg(
+        required_unless_present_any = ["new_branch", "orphan"],
+        conflicts_with = "orphan"
+    )]
+    pub query: Option<String>,
+
+    /// Create new branch and switch to it
+    #[arg(short = 'b', value_name = "NEW_BRANCH", conflicts_with = "orphan")]
+    pub new_branch: Option<String>,
+
+    /// Create new branch without any commits and switch to it, which takes no start point
+    #[arg(long, value_name = "NEW_BRANCH")]
+    pub orphan: Option<String>,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct RestoreArgs {
+    /// Commit ID or branch to restore files from
+    #[arg(short, long, default_value = "HEAD")]
+    pub source: String,
+
+    /// Paths in the environment to restore (e.g. /etc/apt/sources.list)
+    #[arg(required = true)]
+    pub paths: Vec<String>,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct BisectArgs {
+    #[command(subcommand)]
+    pub command: BisectCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum BisectCommand {
+    /// Start bisecting, optionally marking bad and good commits
+    Start(BisectStartArgs),
+
+    /// Mark a commit as good (defaults to HEAD)
+    Good(BisectMarkArgs),
+
+    /// Mark a commit as bad (defaults to HEAD)
+    Bad(BisectMarkArgs),
+
+    /// Mark a commit as untestable (defaults to HEAD)
+    Skip(BisectMarkArgs),
+
+    /// Test each commit with a command: exit 0 is good, 125 is skip, 1-127 is bad
+    Run(BisectRunArgs),
+
+    /// Finish bisecting and go back to the original HEAD
+    Reset,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct BisectStartArgs {
+    /// Bad commit ID or branch
+    pub bad: Option<String>,
+
+    /// Good commit IDs or branches
+    #[arg(requires = "bad")]
+    pub good: Vec<String>,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct BisectMarkArgs {
+    /// Commit ID or branch
+    #[arg(default_value = "HEAD")]
+    pub query: String,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct BisectRunArgs {
+    /// Command to execute in the environment
+    pub command: String,
+
+    /// Arguments of command
+    #[arg(allow_hyphen_values = true)]
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct BlameArgs {
+    /// Path in the environment (e.g. /usr/lib/x86_64-linux-gnu/libc.so.6)
+    pub path: String,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct BundleArgs {
+    #[command(subcommand)]
+    pub command: BundleCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum BundleCommand {
+    /// Write commits and their layers into a bundle file
+    Create(BundleCreateArgs),
+
+    /// Verify a bundle file and add its commits to this environment
+    Import(BundleImportArgs),
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct BundleCreateArgs {
+    /// Bundle file to write
+    pub file: String,
+
+    /// Commits to bundle: '<rev>' for all its ancestors, or '<from>..<to>'
+    #[arg(default_value = "HEAD")]
+    pub range: String,
+
+    /// Compress the bundle with zstd
+    #[arg(short, long)]
+    pub zstd: bool,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct BundleImportArgs {
+    /// Bundle file to import
+    pub file: String,
+
+    /// Create a branch pointing to the newest imported commit
+    #[arg(short, long)]
+    pub branch: Option<String>,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct ExportArgs {
+    /// Commit ID or branch to export (uncommitted changes are not exported)
+    pub rev: String,
+
+    /// Directory of the OCI image layout, or file of the tarball, to write
+    pub dest: PathBuf,
+
+    /// Format to write the image in
+    #[arg(short, long, value_enum, default_value_t = ExportFormat::OciLayout)]
+    pub format: ExportFormat,
+
+    /// Name of the image (e.g. 'tools/app:1.0'), which 'docker load' tags the image with
+    #[arg(short, long)]
+    pub tag: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ExportFormat {
+    /// OCI image layout directory
+    OciLayout,
+    /// Tarball for 'docker load', which also is an OCI image layout
+    DockerArchive,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct CloneArgs {
+    /// Name of the environment to clone
+    pub source: String,
+
+    /// Name of the new environment
+    pub destination: String,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct EnvArgs {
+    #[command(subcommand)]
+    pub command: EnvCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum EnvCommand {
+    /// List environments with their type, branch, commit count and disk usage
+    List,
+
+    /// Show details of an environment [default: environment given by --name]
+    Info(EnvInfoArgs),
+
+    /// Remove an environment and all of its commits
+    Rm(EnvRmArgs),
+
+    /// Rename an environment
+    Rename(EnvRenameArgs),
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct EnvInfoArgs {
+    pub name: Option<String>,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct EnvRmArgs {
+    pub name: String,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct EnvRenameArgs {
+    pub old_name: String,
+    pub new_name: String,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct ImageArgs {
+    #[command(subcommand)]
+    pub command: ImageCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ImageCommand {
+    /// Remove image layers no environment uses
+    Prune,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct RemoteArgs {
+    #[command(subcommand)]
+    pub command: Option<RemoteCommand>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RemoteCommand {
+    /// Add a remote
+    Add(RemoteAddArgs),
+
+    /// Remove a remote and its remote-tracking branches
+    Remove(RemoteRemoveArgs),
+
+    /// Serve a remote directory over HTTP (a minimal server for trying HTTP remotes)
+    Serve(RemoteServeArgs),
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct RemoteAddArgs {
+    /// Name of the remote (e.g. origin)
+    pub name: String,
+
+    /// Directory of the remote, such as one on a shared NFS mount
+    pub url: String,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct RemoteRemoveArgs {
+    /// Name of the remote
+    pub name: String,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct RemoteServeArgs {
+    /// Directory to store pushed commits and layers
+    pub dir: String,
+
+    /// Address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:7878")]
+    pub listen: String,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct PushArgs {
+    /// Remote to push to [default: origin or the only remote]
+    #[arg(conflicts_with = "image")]
+    pub remote: Option<String>,
+
+    /// Branch to push [default: current branch]
+    #[arg(conflicts_with = "image")]
+    pub branch: Option<String>,
+
+    /// Overwrite the remote branch even if it is not an ancestor of the local one
+    #[arg(short, long, conflicts_with = "image")]
+    pub force: bool,
+
+    /// Push as a container image to this reference (e.g. 'localhost:5000/tools/app:1.0')
+    /// instead of to a remote
+    #[arg(long, value_name = "REF")]
+    pub image: Option<String>,
+
+    /// Commit ID or branch to push with '--image'
+    #[arg(long, value_name = "REV", default_value = "HEAD", requires = "image")]
+    pub rev: String,
+
+    /// Upload image blobs larger than this in chunks of this size [default: 16MiB]
+    #[arg(long, value_name = "BYTES", requires = "image")]
+    pub chunk_size: Option<u64>,
+}
+
+#[derive(Debug, ArgsDerive)]
+pub struct FetchArgs {
+    /// Remote to fetch from [default: origin or the only remote]
+    pub remote: Option<String>,
+}
+
 #[derive(Debug, ArgsDerive)]
 pub struct TargetArgs {
     /// Commit ID or branch
@@ -0,0 +1,182 @@
+use anyhow::{bail, Context, Result};
+use orca_vcs::{Commit, VCS};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+/// Exit status of 'bisect run' command which means the commit cannot be tested
+pub const SKIP_EXIT_CODE: i32 = 125;
+
+/// State of an ongoing bisection, saved in the environment between invocations
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct BisectState {
+    /// Branch name or commit ID which was checked out at 'bisect start'
+    pub original_head: String,
+    #[serde(default)]
+    pub bad: Option<String>,
+    #[serde(default)]
+    pub good: Vec<String>,
+    #[serde(default)]
+    pub skipped: Vec<String>,
+}
+
+pub enum BisectStep {
+    /// Both good and bad commits are needed to start
+    NeedMarks,
+    /// `commit` should be tested next, `remaining` commits are left to test
+    Test { commit: Commit, remaining: usize },
+    /// The first bad commit has been found
+    Found(Commit),
+    /// Only skipped commits are left, one of them is the first bad commit
+    OnlySkipped(Vec<Commit>),
+}
+
+impl BisectState {
+    /// Returns None if no bisection is in progress
+    pub fn load<P: AsRef<Path>>(file_path: P) -> Result<Option<Self>> {
+        let file_path = file_path.as_ref();
+        if !file_path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(file_path)
+            .with_context(|| format!("Failed to read '{}'", file_path.display()))?;
+        let state = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse '{}'", file_path.display()))?;
+        Ok(Some(state))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, file_path: P) -> Result<()> {
+        let file_path = file_path.as_ref();
+        fs::write(file_path, toml::to_string(self)?)
+            .with_context(|| format!("Failed to write '{}'", file_path.display()))
+    }
+
+    /// Decides what to do next from the commits marked so far.
+    /// Candidates are ancestors of the bad commit (inclusive) which are not ancestors of good ones.
+    pub fn next_step(&self, vcs: &VCS) -> Result<BisectStep> {
+        let Some(bad) = self.bad.as_ref() else {
+            return Ok(BisectStep::NeedMarks);
+        };
+        if self.good.is_empty() {
+            return Ok(BisectStep::NeedMarks);
+        }
+
+        let mut good_ancestors = HashSet::new();
+        for good in self.good.iter() {
+            good_ancestors.extend(vcs.get_commits(good)?.map(|commit| commit.id.as_str()));
+        }
+        let candidates: Vec<&Commit> = vcs
+            .get_commits(bad)?
+            .take_while(|commit| !good_ancestors.contains(commit.id.as_str()))
+            .collect();
+
+        match candidates.len() {
+            0 => bail!("Bad commit {bad} is an ancestor of a good commit"),
+            1 => return Ok(BisectStep::Found(candidates[0].clone())),
+            _ => {}
+        }
+
+        // candidates[0] is known to be bad, the others are untested
+        let middle = candidates.len() / 2;
+        let next = candidates
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter(|(_, commit)| !self.skipped.contains(&commit.id))
+            .min_by_key(|(i, _)| i.abs_diff(middle));
+        match next {
+            Some((_, commit)) => Ok(BisectStep::Test {
+                commit: (*commit).clone(),
+                remaining: candidates.len() - 1,
+            }),
+            None => Ok(BisectStep::OnlySkipped(
+                candidates.into_iter().cloned().collect(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Linear history of `n` commits, returned from the oldest one
+    fn history(n: usize) -> (TempDir, VCS, Vec<String>) {
+        let dir = tempfile::tempdir().unwrap();
+        let commits_file = dir.path().join("commits.toml");
+        VCS::init(&commits_file).unwrap();
+        let mut vcs = VCS::new(&commits_file).unwrap();
+        let ids = (0..n)
+            .map(|i| vcs.commit(Some(format!("c{i}"))).unwrap().id.clone())
+            .collect();
+        (dir, vcs, ids)
+    }
+
+    fn state(bad: Option<&str>, good: &[&str], skipped: &[&str]) -> BisectState {
+        BisectState {
+            original_head: "main".to_string(),
+            bad: bad.map(|id| id.to_string()),
+            good: good.iter().map(|id| id.to_string()).collect(),
+            skipped: skipped.iter().map(|id| id.to_string()).collect(),
+        }
+    }
+
+    fn tested(step: BisectStep) -> (String, usize) {
+        match step {
+            BisectStep::Test { commit, remaining } => (commit.id, remaining),
+            _ => panic!("a commit to test is expected"),
+        }
+    }
+
+    #[test]
+    fn needs_both_marks() {
+        let (_dir, vcs, ids) = history(3);
+        let step = state(Some(&ids[2]), &[], &[]).next_step(&vcs).unwrap();
+        assert!(matches!(step, BisectStep::NeedMarks));
+        let step = state(None, &[&ids[0]], &[]).next_step(&vcs).unwrap();
+        assert!(matches!(step, BisectStep::NeedMarks));
+    }
+
+    #[test]
+    fn halves_candidates_until_found() {
+        let (_dir, vcs, ids) = history(8);
+        let mut state = state(Some(&ids[7]), &[&ids[0]], &[]);
+        assert_eq!(tested(state.next_step(&vcs).unwrap()), (ids[4].clone(), 6));
+
+        state.good.push(ids[4].clone());
+        assert_eq!(tested(state.next_step(&vcs).unwrap()), (ids[6].clone(), 2));
+
+        state.bad = Some(ids[6].clone());
+        assert_eq!(tested(state.next_step(&vcs).unwrap()), (ids[5].clone(), 1));
+
+        state.bad = Some(ids[5].clone());
+        match state.next_step(&vcs).unwrap() {
+            BisectStep::Found(commit) => assert_eq!(commit.id, ids[5]),
+            _ => panic!("the first bad commit is expected to be found"),
+        }
+    }
+
+    #[test]
+    fn avoids_skipped_commits() {
+        let (_dir, vcs, ids) = history(5);
+        let step = state(Some(&ids[4]), &[&ids[0]], &[&ids[2]]).next_step(&vcs);
+        let (next, _) = tested(step.unwrap());
+        assert_ne!(next, ids[2]);
+
+        let skipped = [ids[1].as_str(), &ids[2], &ids[3]];
+        match state(Some(&ids[4]), &[&ids[0]], &skipped).next_step(&vcs) {
+            Ok(BisectStep::OnlySkipped(commits)) => assert_eq!(commits.len(), 4),
+            _ => panic!("only skipped commits are expected to be left"),
+        }
+    }
+
+    #[test]
+    fn rejects_bad_commit_before_good_one() {
+        let (_dir, vcs, ids) = history(3);
+        assert!(state(Some(&ids[0]), &[&ids[2]], &[])
+            .next_step(&vcs)
+            .is_err());
+    }
+}
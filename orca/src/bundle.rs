@@ -0,0 +1,312 @@
+use crate::archive;
+use anyhow::{bail, Context, Result};
+use orca_vcs::{Commit, VCS};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use tar::Header;
+
+const MANIFEST_NAME: &str = "bundle.toml";
+const LAYERS_DIR_NAME: &str = "layers";
+const BUNDLE_VERSION: u32 = 1;
+
+/// First entry of a bundle, describing the commits it carries
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BundleManifest {
+    pub version: u32,
+    /// Commit which has to exist before importing (the parent of the oldest commit)
+    pub prerequisite: Option<String>,
+    /// Commits ordered from the oldest one
+    pub commits: Vec<Commit>,
+    /// Digests of layer contents keyed by commit ID
+    pub layers: BTreeMap<String, String>,
+}
+
+/// Returns commits of `range` ordered from the oldest one.
+/// `range` is either `<rev>` (all ancestors of rev) or `<from>..<to>` (ancestors of `to`
+/// which are not ancestors of `from`).
+pub fn collect_range(vcs: &VCS, range: &str) -> Result<Vec<Commit>> {
+    let (from, to) = match range.split_once("..") {
+        Some((from, to)) => (Some(from).filter(|from| !from.is_empty()), to),
+        None => (None, range),
+    };
+    let to = if to.is_empty() { "HEAD" } else { to };
+    let excluded: HashSet<&str> = match from {
+        Some(from) => vcs.get_commits(from)?.map(|c| c.id.as_str()).collect(),
+        None => HashSet::new(),
+    };
+    let mut commits: Vec<Commit> = vcs
+        .get_commits(to)?
+        .take_while(|commit| !excluded.contains(commit.id.as_str()))
+        .cloned()
+        .collect();
+    if commits.is_empty() {
+        bail!("No commits in '{range}'");
+    }
+    commits.reverse();
+    Ok(commits)
+}
+
+/// Writes `commits` (ordered from the oldest one) and their layers into `file_path`
+pub fn create<P, Q>(file_path: P, commits: Vec<Commit>, lower_root: Q, zstd: bool) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let file_path = file_path.as_ref();
+    let lower_root = lower_root.as_ref();
+
+    let mut layers = BTreeMap::new();
+    for commit in commits.iter() {
+        let digest = archive::layer_digest(lower_root.join(&commit.id))?;
+        layers.insert(commit.id.clone(), digest);
+    }
+    let manifest = BundleManifest {
+        version: BUNDLE_VERSION,
+        prerequisite: commits[0].parent_id().map(|id| id.to_string()),
+        commits,
+        layers,
+    };
+    let manifest_toml = toml::to_string(&manifest)?;
+
+    let file = File::create(file_path)
+        .with_context(|| format!("Failed to create '{}'", file_path.display()))?;
+    let mut builder = archive::new_builder(file, zstd)?;
+
+    let mut header = Header::new_gnu();
+    header.set_size(manifest_toml.len() as u64);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, MANIFEST_NAME, manifest_toml.as_bytes())?;
+
+    for commit in manifest.commits.iter() {
+        let prefix = Path::new(LAYERS_DIR_NAME).join(&commit.id);
+        archive::append_layer(&mut builder, lower_root.join(&commit.id), prefix)?;
+    }
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+/// Extracts the bundle at `file_path` into `dest` and verifies its layers.
+/// Layers are placed at `dest/layers/<commit id>`.
+pub fn unpack<P, Q>(file_path: P, dest: Q) -> Result<BundleManifest>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let dest = dest.as_ref();
+    let mut archive = archive::open(file_path)?;
+
+    fs::create_dir_all(dest.join(LAYERS_DIR_NAME))?;
+    let mut manifest: Option<BundleManifest> = None;
+    let mut extractors = HashMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry.context("Invalid bundle: broken archive")?;
+        let path = entry.path()?.into_owned();
+
+        let Some(manifest) = manifest.as_ref() else {
+            if path != Path::new(MANIFEST_NAME) {
+                bail!("Invalid bundle: '{MANIFEST_NAME}' is not the first entry");
+            }
+            let mut content = String::new();
+            entry.read_to_string(&mut content)?;
+            let parsed: BundleManifest =
+                toml::from_str(&content).context("Invalid bundle: broken manifest")?;
+            if parsed.version != BUNDLE_VERSION {
+                bail!("Unsupported bundle version: {}", parsed.version);
+            }
+            check_ids(&parsed)?;
+            manifest = Some(parsed);
+            continue;
+        };
+
+        let Some((id, relative)) = split_layer_entry(&path, manifest) else {
+            bail!("Invalid bundle: unexpected entry '{}'", path.display());
+        };
+        let extractor = match extractors.entry(id.to_string()) {
+            Entry::Occupied(slot) => slot.into_mut(),
+            Entry::Vacant(slot) => slot.insert(archive::layer_extractor(unpacked_layer(dest, id))?),
+        };
+        extractor.extract(&mut entry, relative)?;
+    }
+    for extractor in extractors.into_values() {
+        extractor.finish()?;
+    }
+
+    let Some(manifest) = manifest else {
+        bail!("Invalid bundle: empty archive");
+    };
+    verify(&manifest, dest.join(LAYERS_DIR_NAME))?;
+    Ok(manifest)
+}
+
+/// Checks that commits form a chain and that every layer matches its digest
+fn verify<P: AsRef<Path>>(manifest: &BundleManifest, layers_dir: P) -> Result<()> {
+    let layers_dir = layers_dir.as_ref();
+    if manifest.commits.is_empty() {
+        bail!("Invalid bundle: no commits");
+    }
+    let mut parent_id = manifest.prerequisite.as_deref();
+    for commit in manifest.commits.iter() {
+        if commit.parent_id() != parent_id {
+            bail!(
+                "Invalid bundle: commit {} is not a child of the previous one",
+                commit.id
+            );
+        }
+        let Some(expected) = manifest.layers.get(&commit.id) else {
+            bail!("Invalid bundle: no digest for commit {}", commit.id);
+        };
+        let layer_dir = layers_dir.join(&commit.id);
+        if !layer_dir.is_dir() {
+            bail!("Invalid bundle: layer of commit {} is missing", commit.id);
+        }
+        if archive::layer_digest(&layer_dir)? != *expected {
+            bail!("Invalid bundle: layer of commit {} is corrupted", commit.id);
+        }
+        parent_id = Some(commit.id.as_str());
+    }
+    Ok(())
+}
+
+/// Checks that every commit ID in `manifest` is one, since they are joined to paths of layers
+fn check_ids(manifest: &BundleManifest) -> Result<()> {
+    let ids = manifest
+        .commits
+        .iter()
+        .flat_map(|commit| [Some(commit.id.as_str()), commit.parent_id()])
+        .flatten()
+        .chain(manifest.prerequisite.as_deref())
+        .chain(manifest.layers.keys().map(|id| id.as_str()));
+    for id in ids {
+        if !Commit::is_valid_id(id) {
+            bail!("Invalid bundle: invalid commit ID '{id}'");
+        }
+    }
+    Ok(())
+}
+
+/// Splits `path` of 'layers/<commit id>/...' into the ID of a commit in `manifest`
+/// and the path in its layer
+fn split_layer_entry<'a>(path: &'a Path, manifest: &BundleManifest) -> Option<(&'a str, &'a Path)> {
+    let mut components = path.components();
+    let (Some(Component::Normal(layers)), Some(Component::Normal(id))) =
+        (components.next(), components.next())
+    else {
+        return None;
+    };
+    let id = id.to_str()?;
+    (layers == LAYERS_DIR_NAME && manifest.layers.contains_key(id))
+        .then_some((id, components.as_path()))
+}
+
+/// Path of the extracted layer of `commit_id` under the `dest` given to [`unpack`]
+pub fn unpacked_layer<P: AsRef<Path>>(dest: P, commit_id: &str) -> PathBuf {
+    dest.as_ref().join(LAYERS_DIR_NAME).join(commit_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use tar::{Builder, EntryType};
+
+    const ID: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    fn manifest_toml(id: &str) -> String {
+        format!(
+            "version = {BUNDLE_VERSION}\n\
+            [[commits]]\nid = \"{id}\"\ndate = \"2024-01-01\"\n\
+            [layers]\n\"{id}\" = \"0\"\n"
+        )
+    }
+
+    fn header(entry_type: EntryType, size: u64) -> Header {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(size);
+        header.set_mode(if entry_type == EntryType::Directory {
+            0o755
+        } else {
+            0o644
+        });
+        header.set_uid(nix::unistd::getuid().as_raw() as u64);
+        header.set_gid(nix::unistd::getgid().as_raw() as u64);
+        header
+    }
+
+    /// Writes a bundle whose layer has a symlink `a` to `target`, then a file `a/passwd`
+    fn write_symlink_bundle(path: &Path, target: &Path) -> Result<()> {
+        let mut builder = Builder::new(File::create(path)?);
+        let manifest = manifest_toml(ID);
+        let mut manifest_header = header(EntryType::Regular, manifest.len() as u64);
+        builder.append_data(&mut manifest_header, MANIFEST_NAME, manifest.as_bytes())?;
+
+        let layer = Path::new(LAYERS_DIR_NAME).join(ID);
+        builder.append_data(&mut header(EntryType::Directory, 0), &layer, io::empty())?;
+        let mut symlink = header(EntryType::Symlink, 0);
+        builder.append_link(&mut symlink, layer.join("a"), target)?;
+        let content = b"pwned\n";
+        let mut file = header(EntryType::Regular, content.len() as u64);
+        builder.append_data(&mut file, layer.join("a/passwd"), &content[..])?;
+        builder.into_inner()?.flush()?;
+        Ok(())
+    }
+
+    #[test]
+    fn symlinks_do_not_lead_out_of_layers() -> Result<()> {
+        let outside = tempfile::tempdir()?;
+        let work = tempfile::tempdir()?;
+        let escapes = [outside.path().to_path_buf(), PathBuf::from("../../../..")];
+        for (i, target) in escapes.iter().enumerate() {
+            let bundle = work.path().join(format!("{i}.bundle"));
+            write_symlink_bundle(&bundle, target)?;
+            // The layer does not match its digest, but nothing may be written outside first
+            assert!(unpack(&bundle, work.path().join(i.to_string())).is_err());
+            assert!(!outside.path().join("passwd").exists());
+            assert!(!work.path().join("passwd").exists());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_commit_ids() -> Result<()> {
+        let work = tempfile::tempdir()?;
+        let bundle = work.path().join("bad.bundle");
+        let mut builder = Builder::new(File::create(&bundle)?);
+        let manifest = manifest_toml("../../escape");
+        let mut manifest_header = header(EntryType::Regular, manifest.len() as u64);
+        builder.append_data(&mut manifest_header, MANIFEST_NAME, manifest.as_bytes())?;
+        builder.into_inner()?.flush()?;
+
+        let error = unpack(&bundle, work.path().join("dest")).unwrap_err();
+        assert!(error.to_string().contains("invalid commit ID"));
+        Ok(())
+    }
+
+    #[test]
+    fn unpacks_created_bundles() -> Result<()> {
+        let work = tempfile::tempdir()?;
+        let lower_root = work.path().join("lower");
+        let layer = lower_root.join(ID);
+        fs::create_dir_all(layer.join("etc"))?;
+        fs::write(layer.join("etc/hostname"), "orca\n")?;
+        std::os::unix::fs::symlink("/etc/hostname", layer.join("hostname"))?;
+        let commits: Vec<Commit> = toml::from_str::<BundleManifest>(&manifest_toml(ID))?.commits;
+
+        let bundle = work.path().join("ok.bundle");
+        create(&bundle, commits, &lower_root, true)?;
+        let dest = work.path().join("dest");
+        let manifest = unpack(&bundle, &dest)?;
+        assert_eq!(manifest.commits[0].id, ID);
+        let unpacked = unpacked_layer(&dest, ID);
+        assert_eq!(fs::read_to_string(unpacked.join("etc/hostname"))?, "orca\n");
+        assert_eq!(
+            fs::read_link(unpacked.join("hostname"))?,
+            Path::new("/etc/hostname")
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,322 @@
+use crate::{BLOB_STORE_DIR_NAME, COMMITS_FILE_NAME, ENV_FILE_NAME, SESSION_LOCK_FILE_NAME};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local};
+use orca_image::config::ImageConfig;
+use orca_image::store::BlobStore;
+use orca_vcs::{Error, VCS};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// What an environment was created from, saved as env.toml at 'init'
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EnvConfig {
+    pub base: Base,
+    pub created: String,
+    pub orca_version: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Base {
+    /// The root filesystem of the host is the bottom layer
+    Host,
+    /// A container image is the bottom layer
+    Image {
+        /// Reference given at 'init' (e.g. 'ubuntu:22.04'), or where the image was imported from
+        /// (e.g. 'archive:/path/to/image.tar', 'oci-layout:/path/to/dir')
+        reference: String,
+        /// Digest of the manifest the reference pointed at (e.g. 'sha256:...'),
+        /// or of the config for images imported from 'docker save'
+        digest: String,
+        /// Platform chosen if the image is multi-platform (e.g. 'linux/amd64'),
+        /// or the one imported images are built for
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        platform: Option<String>,
+        /// Digests of the layers of the image from the bottom one, kept in the blob store
+        #[serde(default)]
+        layers: Vec<String>,
+        /// Env, Cmd, Entrypoint, WorkingDir and User of the image, used by 'run'
+        #[serde(default)]
+        config: Box<ImageConfig>,
+    },
+}
+
+impl EnvConfig {
+    pub fn new(base: Base) -> Self {
+        Self {
+            base,
+            created: Local::now().to_string(),
+            orca_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// Returns None for environments created before env.toml existed, which are host ones
+    pub fn load<P: AsRef<Path>>(file_path: P) -> Result<Option<Self>> {
+        let file_path = file_path.as_ref();
+        if !file_path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(file_path)
+            .with_context(|| format!("Failed to read '{}'", file_path.display()))?;
+        let config = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse '{}'", file_path.display()))?;
+        Ok(Some(config))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, file_path: P) -> Result<()> {
+        let file_path = file_path.as_ref();
+        fs::write(file_path, toml::to_string(self)?)
+            .with_context(|| format!("Failed to write '{}'", file_path.display()))
+    }
+}
+
+/// An environment, which is a subdirectory of the orca root initialized by 'init'
+pub struct Environment {
+    pub name: String,
+    pub root: PathBuf,
+}
+
+/// Marks an environment as in use by a running container or by a command changing its upper
+/// layer, removed when dropped
+pub struct SessionLock {
+    path: PathBuf,
+}
+
+impl Environment {
+    /// Fails if `name` has not been initialized under `rootdir`
+    pub fn open<P: AsRef<Path>>(rootdir: P, name: &str) -> Result<Self> {
+        let env = Self {
+            name: name.to_string(),
+            root: rootdir.as_ref().join(name),
+        };
+        if !env.is_initialized() {
+            bail!("Environment '{name}' does not exist");
+        }
+        Ok(env)
+    }
+
+    /// Returns all environments under `rootdir` sorted by name
+    pub fn list<P: AsRef<Path>>(rootdir: P) -> Result<Vec<Self>> {
+        let rootdir = rootdir.as_ref();
+        let entries = match fs::read_dir(rootdir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => Err(e).with_context(|| format!("Failed to read '{}'", rootdir.display()))?,
+        };
+        let mut envs = vec![];
+        for entry in entries {
+            let entry = entry?;
+            let env = Self {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                root: entry.path(),
+            };
+            if env.is_initialized() {
+                envs.push(env);
+            }
+        }
+        envs.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(envs)
+    }
+
+    pub fn vcs(&self) -> Result<VCS> {
+        Ok(VCS::new(self.root.join(COMMITS_FILE_NAME))?)
+    }
+
+    pub fn config(&self) -> Result<Option<EnvConfig>> {
+        EnvConfig::load(self.root.join(ENV_FILE_NAME))
+    }
+
+    /// "host", or the image reference for environments created from a container image
+    pub fn kind(&self) -> Result<String> {
+        match self.config()? {
+            Some(EnvConfig {
+                base: Base::Image { reference, .. },
+                ..
+            }) => Ok(reference),
+            _ => Ok("host".to_string()),
+        }
+    }
+
+    /// Current branch, or the commit ID if HEAD is detached
+    pub fn head(&self, vcs: &VCS) -> String {
+        match vcs.get_current_branch() {
+            Some(branch) => branch.to_string(),
+            None => match vcs.get_head_commit() {
+                Ok(commit) => format!("({})", &commit.id[..7]),
+                Err(_) => "(detached)".to_string(),
+            },
+        }
+    }
+
+    /// Number of commits reachable from HEAD
+    pub fn commit_count(&self, vcs: &VCS) -> Result<usize> {
+        match vcs.get_current_commits() {
+            Ok(commits) => Ok(commits.count()),
+            Err(Error::CommitNotFound) => Ok(0),
+            Err(e) => Err(e)?,
+        }
+    }
+
+    /// Bytes used on disk. Files hardlinked within the environment are counted once.
+    pub fn disk_usage(&self) -> Result<u64> {
+        let mut seen = HashSet::new();
+        let mut total = 0;
+        for entry in WalkDir::new(&self.root).same_file_system(true) {
+            let metadata = entry?.metadata()?;
+            if metadata.nlink() > 1 && !seen.insert((metadata.dev(), metadata.ino())) {
+                continue;
+            }
+            total += metadata.blocks() * 512;
+        }
+        Ok(total)
+    }
+
+    /// Time when the environment was created.
+    /// Environments without env.toml fall back to the time of their directory.
+    pub fn created(&self) -> Result<String> {
+        if let Some(config) = self.config()? {
+            return Ok(config.created);
+        }
+        let metadata = fs::metadata(&self.root)?;
+        let time = metadata.created().or_else(|_| metadata.modified())?;
+        Ok(DateTime::<Local>::from(time).to_string())
+    }
+
+    /// PID of the process running a container in this environment
+    pub fn session_holder(&self) -> Option<u32> {
+        let content = fs::read_to_string(self.root.join(SESSION_LOCK_FILE_NAME)).ok()?;
+        let pid: u32 = content.trim().parse().ok()?;
+        // A lock left by a killed process is ignored
+        Path::new("/proc")
+            .join(pid.to_string())
+            .exists()
+            .then_some(pid)
+    }
+
+    /// Fails while a container is running in this environment
+    pub fn ensure_unused(&self) -> Result<()> {
+        if let Some(pid) = self.session_holder() {
+            bail!(
+                "Environment '{}' is in use by process {pid}, exit the session first",
+                self.name
+            );
+        }
+        Ok(())
+    }
+
+    /// Marks the environment as in use by this process.
+    /// The lock file is created exclusively, so that two processes cannot both take it.
+    pub fn lock_session(&self) -> Result<SessionLock> {
+        let path = self.root.join(SESSION_LOCK_FILE_NAME);
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(std::process::id().to_string().as_bytes())
+                        .with_context(|| format!("Failed to write '{}'", path.display()))?;
+                    return Ok(SessionLock { path });
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    self.ensure_unused()?;
+                    // The holder may have created the file without writing its PID yet
+                    let content = fs::read_to_string(&path).unwrap_or_default();
+                    if content.trim().parse::<u32>().is_err() {
+                        break;
+                    }
+                    // A lock left by a killed process
+                    match fs::remove_file(&path) {
+                        Err(e) if e.kind() != ErrorKind::NotFound => Err(e)
+                            .with_context(|| format!("Failed to remove '{}'", path.display()))?,
+                        _ => {}
+                    }
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to create '{}'", path.display()))
+                }
+            }
+        }
+        bail!(
+            "Environment '{}' is being locked by someone else. If no orca is running, remove '{}'",
+            self.name,
+            path.display()
+        )
+    }
+
+    /// Removes the environment. Its image layers stay in the blob store until 'image prune'.
+    pub fn remove(self) -> Result<()> {
+        self.ensure_unused()?;
+        let config = self.config()?;
+        fs::remove_dir_all(&self.root)
+            .with_context(|| format!("Failed to remove '{}'", self.root.display()))?;
+        if let Some(EnvConfig {
+            base: Base::Image { layers, .. },
+            ..
+        }) = config
+        {
+            self.blob_store().release(&layers)?;
+        }
+        Ok(())
+    }
+
+    /// Blob store shared by all environments under the same orca root
+    pub fn blob_store(&self) -> BlobStore {
+        BlobStore::new(self.root.with_file_name(BLOB_STORE_DIR_NAME))
+    }
+
+    pub fn rename(self, new_name: &str) -> Result<Self> {
+        self.ensure_unused()?;
+        check_name(new_name)?;
+        let new_root = self.root.with_file_name(new_name);
+        if new_root.exists() {
+            bail!("Environment '{new_name}' already exists");
+        }
+        fs::rename(&self.root, &new_root)?;
+        Ok(Self {
+            name: new_name.to_string(),
+            root: new_root,
+        })
+    }
+
+    fn is_initialized(&self) -> bool {
+        self.root.join(COMMITS_FILE_NAME).is_file()
+    }
+}
+
+impl Drop for SessionLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Environment names become directory names under the orca root
+pub fn check_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name == BLOB_STORE_DIR_NAME
+        || name.contains('/')
+    {
+        bail!("Invalid environment name: '{name}'");
+    }
+    Ok(())
+}
+
+/// Formats `bytes` with a binary unit, e.g. "1.5 GiB"
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
@@ -0,0 +1,772 @@
+use anyhow::{bail, Context, Result};
+use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+use nix::sys::time::TimeVal;
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs::{self, Metadata};
+use std::os::unix::fs::{lchown, symlink, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use walkdir::WalkDir;
+
+const OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+const OVERLAY_XATTR_PREFIX: &str = "trusted.overlay.";
+
+/// Stack of overlay layers, ordered from the top layer to the bottom one
+pub struct LayerStack {
+    layers: Vec<PathBuf>,
+}
+
+impl LayerStack {
+    pub fn new<P: Into<PathBuf>>(layers: Vec<P>) -> Self {
+        Self {
+            layers: layers.into_iter().map(|p| p.into()).collect(),
+        }
+    }
+
+    pub fn exists<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.resolve(path).is_some()
+    }
+
+    /// Returns real paths which make up `path` in the merged tree, from top to bottom.
+    /// Non-directory entries are always made up of exactly one path.
+    pub fn resolve<P: AsRef<Path>>(&self, path: P) -> Option<Vec<PathBuf>> {
+        let relative = to_relative(path)?;
+        let mut candidates: Vec<&Path> = self.layers.iter().map(|p| p.as_path()).collect();
+        let mut found: Vec<PathBuf> = candidates.iter().map(|p| p.to_path_buf()).collect();
+        let mut current = PathBuf::new();
+        let mut components = relative.iter().peekable();
+
+        while let Some(component) = components.next() {
+            current.push(component);
+            found.clear();
+            let mut contributors: Vec<&Path> = vec![];
+            let mut top_is_dir = false;
+            for layer in candidates {
+                let real_path = layer.join(&current);
+                let Ok(metadata) = real_path.symlink_metadata() else {
+                    continue;
+                };
+                if is_whiteout(&metadata) {
+                    break;
+                }
+                if !metadata.is_dir() {
+                    if found.is_empty() {
+                        found.push(real_path);
+                    }
+                    break;
+                }
+                if found.is_empty() {
+                    top_is_dir = true;
+                }
+                let opaque = is_opaque(&real_path);
+                found.push(real_path);
+                contributors.push(layer);
+                if opaque {
+                    break;
+                }
+            }
+            if found.is_empty() || (!top_is_dir && components.peek().is_some()) {
+                return None;
+            }
+            candidates = contributors;
+        }
+
+        Some(found)
+    }
+
+    /// Returns names of entries in the merged directory `path`
+    pub fn read_dir<P: AsRef<Path>>(&self, path: P) -> Result<Vec<OsString>> {
+        let path = path.as_ref();
+        let Some(real_paths) = self.resolve(path) else {
+            bail!("No such directory: '{}'", path.display());
+        };
+        let mut names = BTreeSet::new();
+        for real_path in real_paths.iter().filter(|p| p.is_dir()) {
+            for entry in fs::read_dir(real_path)
+                .with_context(|| format!("Failed to read dir: '{}'", real_path.display()))?
+            {
+                names.insert(entry?.file_name());
+            }
+        }
+        Ok(names
+            .into_iter()
+            .filter(|name| self.exists(path.join(name)))
+            .collect())
+    }
+
+    /// Copies the merged tree at `path` to `dest` preserving ownership, modes and xattrs
+    pub fn copy_to<P, Q>(&self, path: P, dest: Q) -> Result<()>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let dest = dest.as_ref();
+        let Some(real_paths) = self.resolve(path) else {
+            bail!("No such file or directory: '{}'", path.display());
+        };
+        let top = &real_paths[0];
+        let metadata = top
+            .symlink_metadata()
+            .with_context(|| format!("Failed to read metadata: '{}'", top.display()))?;
+
+        if metadata.is_dir() {
+            fs::create_dir(dest)
+                .with_context(|| format!("Failed to create dir: '{}'", dest.display()))?;
+            for name in self.read_dir(path)? {
+                self.copy_to(path.join(&name), dest.join(&name))?;
+            }
+            copy_metadata(top, dest)
+        } else {
+            copy_node(top, dest)
+        }
+    }
+
+    /// Returns how the top layer changed `path` from the layers under it,
+    /// or `None` if the top layer leaves `path` as it is
+    pub fn top_layer_change<P: AsRef<Path>>(&self, path: P) -> Option<Change> {
+        let path = path.as_ref();
+        let top_layer = self.layers.first()?;
+        let below = LayerStack::new(self.layers[1..].to_vec());
+        match (self.resolve(path), below.resolve(path)) {
+            (None, None) => None,
+            (None, Some(_)) => Some(Change::Deleted),
+            (Some(real_paths), below_paths) if real_paths[0].starts_with(top_layer) => {
+                let Some(below_paths) = below_paths else {
+                    return Some(Change::Added);
+                };
+                let top = real_paths[0].symlink_metadata().ok()?;
+                let old = below_paths[0].symlink_metadata().ok()?;
+                // A directory shows up in a layer whenever something under it changes
+                let same_dir = top.is_dir()
+                    && old.is_dir()
+                    && !is_opaque(&real_paths[0])
+                    && top.mode() == old.mode()
+                    && top.uid() == old.uid()
+                    && top.gid() == old.gid();
+                (!same_dir).then_some(Change::Modified)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// How a layer changed an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Modified,
+    Deleted,
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Change::Added => "added",
+            Change::Modified => "modified",
+            Change::Deleted => "deleted",
+        };
+        f.pad(name)
+    }
+}
+
+pub fn is_whiteout(metadata: &Metadata) -> bool {
+    metadata.file_type().is_char_device() && metadata.rdev() == 0
+}
+
+pub fn is_opaque<P: AsRef<Path>>(path: P) -> bool {
+    matches!(xattr::get(path, OPAQUE_XATTR), Ok(Some(value)) if value == b"y")
+}
+
+pub fn create_whiteout<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    mknod(path, SFlag::S_IFCHR, Mode::empty(), makedev(0, 0))
+        .with_context(|| format!("Failed to create whiteout: '{}'", path.display()))
+}
+
+pub fn set_opaque<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    xattr::set(path, OPAQUE_XATTR, b"y")
+        .with_context(|| format!("Failed to make dir opaque: '{}'", path.display()))
+}
+
+/// Removes a file, a symlink or a whole directory at `path` if it exists
+pub fn remove_entry<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(_) => return Ok(()),
+    }
+    .with_context(|| format!("Failed to remove: '{}'", path.display()))
+}
+
+/// Copies a single non-directory entry preserving its metadata
+pub fn copy_node<P, Q>(src: P, dest: Q) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let src = src.as_ref();
+    let dest = dest.as_ref();
+    let metadata = src
+        .symlink_metadata()
+        .with_context(|| format!("Failed to read metadata: '{}'", src.display()))?;
+    let file_type = metadata.file_type();
+
+    if file_type.is_symlink() {
+        let original = fs::read_link(src)
+            .with_context(|| format!("Failed to read link: '{}'", src.display()))?;
+        symlink(original, dest)
+            .with_context(|| format!("Failed to create symlink: '{}'", dest.display()))?;
+    } else if file_type.is_file() {
+        fs::copy(src, dest).with_context(|| {
+            format!(
+                "Failed to copy: '{}' to '{}'",
+                src.display(),
+                dest.display()
+            )
+        })?;
+    } else if file_type.is_char_device() || file_type.is_block_device() || file_type.is_fifo() {
+        let kind = if file_type.is_char_device() {
+            SFlag::S_IFCHR
+        } else if file_type.is_block_device() {
+            SFlag::S_IFBLK
+        } else {
+            SFlag::S_IFIFO
+        };
+        mknod(dest, kind, Mode::empty(), metadata.rdev())
+            .with_context(|| format!("Failed to create node: '{}'", dest.display()))?;
+    } else {
+        bail!("Unsupported file type: '{}'", src.display());
+    }
+
+    copy_metadata(src, dest)
+}
+
+/// Makes `path` in upperdir the same as `path` in `source`.
+/// `lowers` is the stack under upperdir, used to decide whether a whiteout is needed.
+pub fn restore_path<P: AsRef<Path>>(
+    source: &LayerStack,
+    lowers: &LayerStack,
+    upperdir: P,
+    path: &str,
+) -> Result<()> {
+    let Some(relative) = to_relative(path) else {
+        bail!("Invalid path: '{path}'");
+    };
+    if relative.as_os_str().is_empty() {
+        bail!("Cannot restore the root directory");
+    }
+    let upperdir = upperdir.as_ref();
+    let dest = upperdir.join(&relative);
+    let hidden_by_upper = relative
+        .ancestors()
+        .skip(1)
+        .any(|parent| matches!(upperdir.join(parent).symlink_metadata(), Ok(m) if !m.is_dir()));
+    // Nothing of the lower layers shows through an opaque ancestor in upperdir
+    let opaque_in_upper = relative
+        .ancestors()
+        .skip(1)
+        .any(|parent| !parent.as_os_str().is_empty() && is_opaque(upperdir.join(parent)));
+    let in_source = source.resolve(&relative);
+    let in_lowers = if hidden_by_upper || opaque_in_upper {
+        None
+    } else {
+        lowers.resolve(&relative)
+    };
+    if in_source.is_none() && in_lowers.is_none() && dest.symlink_metadata().is_err() {
+        bail!("'{path}' does not exist in the source commit");
+    }
+
+    remove_entry(&dest)?;
+    if in_source == in_lowers && !hidden_by_upper {
+        // upperdir no longer hides the lower entry, which is the same as the source one
+        return Ok(());
+    }
+
+    let parents: Vec<&Path> = relative.ancestors().skip(1).collect();
+    for parent in parents.into_iter().rev().skip(1) {
+        let upper_parent = upperdir.join(parent);
+        match upper_parent.symlink_metadata() {
+            Ok(metadata) if metadata.is_dir() => continue,
+            Ok(metadata) if is_whiteout(&metadata) => {
+                remove_entry(&upper_parent)?;
+                fs::create_dir(&upper_parent).with_context(|| {
+                    format!("Failed to create dir: '{}'", upper_parent.display())
+                })?;
+                set_opaque(&upper_parent)?;
+            }
+            Ok(_) => bail!("Not a directory: '/{}'", parent.display()),
+            Err(_) => fs::create_dir(&upper_parent)
+                .with_context(|| format!("Failed to create dir: '{}'", upper_parent.display()))?,
+        }
+        let template = source.resolve(parent).or_else(|| lowers.resolve(parent));
+        if let Some(template) = template {
+            copy_metadata(&template[0], &upper_parent)?;
+        }
+    }
+
+    if in_source.is_some() {
+        source.copy_to(&relative, &dest)?;
+        if dest.symlink_metadata()?.is_dir() {
+            set_opaque(&dest)?;
+        }
+    } else {
+        create_whiteout(&dest)?;
+    }
+    Ok(())
+}
+
+/// Recreates the layer `src` at `dest`, which must not exist, sharing file contents.
+/// Files are hardlinked, which is safe because layers are only changed by replacing entries.
+/// Where that fails, they are copied; `fs::copy` reflinks on filesystems such as btrfs and XFS.
+pub fn link_tree<P, Q>(src: P, dest: Q) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let src = src.as_ref();
+    let dest = dest.as_ref();
+    let mut dirs = vec![];
+    for entry in WalkDir::new(src) {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(src).unwrap();
+        let target = dest.join(relative);
+        if entry.file_type().is_dir() {
+            fs::create_dir(&target)
+                .with_context(|| format!("Failed to create dir: '{}'", target.display()))?;
+            dirs.push((entry.into_path(), target));
+        } else if fs::hard_link(entry.path(), &target).is_err() {
+            copy_node(entry.path(), &target)?;
+        }
+    }
+    // Metadata of directories is set at last, since adding children changes their mtimes
+    for (src_dir, dest_dir) in dirs.into_iter().rev() {
+        if is_opaque(&src_dir) {
+            set_opaque(&dest_dir)?;
+        }
+        copy_metadata(&src_dir, &dest_dir)?;
+    }
+    Ok(())
+}
+
+/// Copies ownership, permissions, mtime and xattrs (except overlayfs ones) from `src` to `dest`
+pub fn copy_metadata<P, Q>(src: P, dest: Q) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let src = src.as_ref();
+    let dest = dest.as_ref();
+    let metadata = src
+        .symlink_metadata()
+        .with_context(|| format!("Failed to read metadata: '{}'", src.display()))?;
+
+    lchown(dest, Some(metadata.uid()), Some(metadata.gid()))
+        .with_context(|| format!("Failed to change owner: '{}'", dest.display()))?;
+    if !metadata.is_symlink() {
+        fs::set_permissions(dest, fs::Permissions::from_mode(metadata.mode()))
+            .with_context(|| format!("Failed to set permissions: '{}'", dest.display()))?;
+    }
+    if let Ok(names) = xattr::list(src) {
+        for name in names {
+            if name.to_string_lossy().starts_with(OVERLAY_XATTR_PREFIX) {
+                continue;
+            }
+            if let Ok(Some(value)) = xattr::get(src, &name) {
+                xattr::set(dest, &name, &value)
+                    .with_context(|| format!("Failed to set xattr: '{}'", dest.display()))?;
+            }
+        }
+    }
+    let atime = TimeVal::new(metadata.atime(), metadata.atime_nsec() / 1000);
+    let mtime = TimeVal::new(metadata.mtime(), metadata.mtime_nsec() / 1000);
+    nix::sys::stat::lutimes(dest, &atime, &mtime)
+        .with_context(|| format!("Failed to set timestamps: '{}'", dest.display()))?;
+
+    Ok(())
+}
+
+/// Converts a path inside the environment (e.g. '/etc/hosts') into a path relative to its root.
+/// Returns `None` if the path tries to escape from the root.
+pub fn to_relative<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in path.as_ref().components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(relative)
+}
+
+/// How much of an entry (and its descendants) is selected by a filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selection {
+    All,
+    Partial,
+    Nothing,
+}
+
+/// Returns relative paths of the topmost entries under `root` which are selected entirely
+pub fn collect_selected<P, F>(root: P, select: F) -> Result<Vec<PathBuf>>
+where
+    P: AsRef<Path>,
+    F: Fn(&Path) -> Selection,
+{
+    let mut selected = vec![];
+    collect_selected_in(root.as_ref(), Path::new(""), &select, &mut selected)?;
+    Ok(selected)
+}
+
+fn collect_selected_in<F>(
+    root: &Path,
+    dir: &Path,
+    select: &F,
+    selected: &mut Vec<PathBuf>,
+) -> Result<()>
+where
+    F: Fn(&Path) -> Selection,
+{
+    let real_dir = root.join(dir);
+    let mut entries = fs::read_dir(&real_dir)
+        .with_context(|| format!("Failed to read dir: '{}'", real_dir.display()))?
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = dir.join(entry.file_name());
+        match select(&path) {
+            Selection::All => selected.push(path),
+            Selection::Partial if entry.file_type()?.is_dir() => {
+                collect_selected_in(root, &path, select, selected)?
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Moves `paths` (relative to `src_root`) into `dest_root`, creating their parents on the way.
+/// Parents left empty in `src_root` are removed unless they are opaque.
+pub fn move_entries<P, Q>(src_root: P, dest_root: Q, paths: &[PathBuf]) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let src_root = src_root.as_ref();
+    let dest_root = dest_root.as_ref();
+
+    for path in paths {
+        let parents: Vec<&Path> = path.ancestors().skip(1).collect();
+        for parent in parents.iter().rev().skip(1) {
+            let dest_parent = dest_root.join(parent);
+            if !dest_parent.exists() {
+                fs::create_dir(&dest_parent).with_context(|| {
+                    format!("Failed to create dir: '{}'", dest_parent.display())
+                })?;
+                // copy_metadata leaves out overlayfs xattrs, but an opaque parent must stay
+                // opaque so that lower layers do not show through it again
+                let src_parent = src_root.join(parent);
+                if is_opaque(&src_parent) {
+                    set_opaque(&dest_parent)?;
+                }
+                copy_metadata(&src_parent, &dest_parent)?;
+            }
+        }
+        let src = src_root.join(path);
+        let dest = dest_root.join(path);
+        fs::rename(&src, &dest).with_context(|| {
+            format!(
+                "Failed to move: '{}' to '{}'",
+                src.display(),
+                dest.display()
+            )
+        })?;
+    }
+
+    for path in paths {
+        prune_empty_parents(src_root, path)?;
+    }
+    Ok(())
+}
+
+/// Merges `paths` (relative to `src_root`) into the layer `dest_root` as overlayfs would show
+/// `src_root` stacked on `dest_root`. Entries are moved, so nothing in `dest_root` is modified in place.
+pub fn merge_entries<P, Q>(src_root: P, dest_root: Q, paths: &[PathBuf]) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let src_root = src_root.as_ref();
+    let dest_root = dest_root.as_ref();
+    let mut opaque_parents = BTreeSet::new();
+
+    for path in paths {
+        let parents: Vec<&Path> = path.ancestors().skip(1).collect();
+        for parent in parents.iter().rev().skip(1) {
+            let src_parent = src_root.join(parent);
+            let dest_parent = dest_root.join(parent);
+            // An opaque parent hides the old children in `dest_root`, so they are dropped
+            // the first time, before anything selected under it is merged
+            let opaque = is_opaque(&src_parent) && opaque_parents.insert(parent.to_path_buf());
+            match dest_parent.symlink_metadata() {
+                Ok(metadata) if metadata.is_dir() && !opaque => continue,
+                Ok(_) => {
+                    remove_entry(&dest_parent)?;
+                    fs::create_dir(&dest_parent).with_context(|| {
+                        format!("Failed to create dir: '{}'", dest_parent.display())
+                    })?;
+                    set_opaque(&dest_parent)?;
+                }
+                Err(_) => {
+                    fs::create_dir(&dest_parent).with_context(|| {
+                        format!("Failed to create dir: '{}'", dest_parent.display())
+                    })?;
+                    if opaque {
+                        set_opaque(&dest_parent)?;
+                    }
+                }
+            }
+            copy_metadata(&src_parent, &dest_parent)?;
+        }
+        merge_entry(&src_root.join(path), &dest_root.join(path))?;
+    }
+
+    for path in paths {
+        prune_empty_parents(src_root, path)?;
+    }
+    Ok(())
+}
+
+fn merge_entry(src: &Path, dest: &Path) -> Result<()> {
+    let src_metadata = src
+        .symlink_metadata()
+        .with_context(|| format!("Failed to read metadata: '{}'", src.display()))?;
+    let dest_is_dir = dest.symlink_metadata().is_ok_and(|m| m.is_dir());
+
+    if src_metadata.is_dir() && dest_is_dir && !is_opaque(src) {
+        for entry in
+            fs::read_dir(src).with_context(|| format!("Failed to read dir: '{}'", src.display()))?
+        {
+            let name = entry?.file_name();
+            merge_entry(&src.join(&name), &dest.join(&name))?;
+        }
+        copy_metadata(src, dest)?;
+        fs::remove_dir(src).with_context(|| format!("Failed to remove: '{}'", src.display()))
+    } else {
+        // A file or a whiteout in `dest` hid the lower layers under the directory `src`,
+        // which the merged directory has to keep hiding
+        let hides_lowers = dest.symlink_metadata().is_ok() && !dest_is_dir;
+        remove_entry(dest)?;
+        fs::rename(src, dest).with_context(|| {
+            format!(
+                "Failed to move: '{}' to '{}'",
+                src.display(),
+                dest.display()
+            )
+        })?;
+        if src_metadata.is_dir() && hides_lowers {
+            set_opaque(dest)?;
+        }
+        Ok(())
+    }
+}
+
+/// Removes `paths` (relative to `root`) and their parents left empty unless they are opaque
+pub fn remove_entries<P: AsRef<Path>>(root: P, paths: &[PathBuf]) -> Result<()> {
+    let root = root.as_ref();
+    for path in paths {
+        remove_entry(root.join(path))?;
+    }
+    for path in paths {
+        prune_empty_parents(root, path)?;
+    }
+    Ok(())
+}
+
+fn prune_empty_parents(root: &Path, path: &Path) -> Result<()> {
+    for parent in path.ancestors().skip(1) {
+        if parent.as_os_str().is_empty() {
+            break;
+        }
+        let real_parent = root.join(parent);
+        let is_empty = fs::read_dir(&real_parent).is_ok_and(|mut dir| dir.next().is_none());
+        if !is_empty || is_opaque(&real_parent) {
+            break;
+        }
+        fs::remove_dir(&real_parent)
+            .with_context(|| format!("Failed to remove: '{}'", real_parent.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Creates `path` under `root` with its parents; a trailing '/' makes a directory
+    fn create(root: &Path, path: &str) {
+        let real_path = root.join(path.trim_end_matches('/'));
+        fs::create_dir_all(real_path.parent().unwrap()).unwrap();
+        if path.ends_with('/') {
+            fs::create_dir_all(&real_path).unwrap();
+        } else {
+            fs::write(&real_path, path).unwrap();
+        }
+    }
+
+    /// Layers 'bottom', 'middle' and 'top' under a new directory, each made of `paths`
+    fn layers(bottom: &[&str], middle: &[&str], top: &[&str]) -> (TempDir, [PathBuf; 3]) {
+        let dir = tempfile::tempdir().unwrap();
+        let roots = ["bottom", "middle", "top"].map(|name| dir.path().join(name));
+        for (root, paths) in roots.iter().zip([bottom, middle, top]) {
+            fs::create_dir(root).unwrap();
+            for path in paths {
+                create(root, path);
+            }
+        }
+        (dir, roots)
+    }
+
+    fn stack(layers: &[&PathBuf]) -> LayerStack {
+        LayerStack::new(layers.iter().map(|layer| layer.to_path_buf()).collect())
+    }
+
+    #[test]
+    fn merges_dirs_over_files() {
+        let (_dir, [layer, _, upper]) = layers(&["d", "e"], &[], &["d/f", "e/f", "e/g"]);
+        merge_entries(&upper, &layer, &[PathBuf::from("d"), PathBuf::from("e/f")]).unwrap();
+
+        // Directories replacing files hide the layers under them
+        assert!(is_opaque(layer.join("d")) && is_opaque(layer.join("e")));
+        assert_eq!(fs::read_to_string(layer.join("d/f")).unwrap(), "d/f");
+        assert!(layer.join("e/f").is_file() && !layer.join("e/g").exists());
+        assert!(!upper.join("d").exists() && !upper.join("e/f").exists());
+        assert!(upper.join("e/g").is_file());
+    }
+
+    #[test]
+    fn merges_whiteouts() {
+        let (_dir, [layer, _, upper]) = layers(&["d/f"], &[], &["d/"]);
+        create_whiteout(layer.join("d/g")).unwrap();
+        create_whiteout(upper.join("d/f")).unwrap();
+        create(&upper, "d/g");
+        merge_entries(&upper, &layer, &[PathBuf::from("d")]).unwrap();
+
+        assert!(is_whiteout(&layer.join("d/f").symlink_metadata().unwrap()));
+        assert_eq!(fs::read_to_string(layer.join("d/g")).unwrap(), "d/g");
+        assert!(!is_opaque(layer.join("d")));
+        assert!(!upper.join("d").exists());
+    }
+
+    #[test]
+    fn merges_under_opaque_parents() {
+        let (_dir, [layer, _, upper]) = layers(
+            &["a/old", "a/b/old", "c/old"],
+            &[],
+            &["a/b/new", "a/c", "c/new"],
+        );
+        set_opaque(upper.join("a")).unwrap();
+        let selected = [PathBuf::from("a/b/new"), PathBuf::from("c/new")];
+        merge_entries(&upper, &layer, &selected).unwrap();
+
+        // Nothing of the old 'a' comes back, while the unselected 'a/c' stays in upperdir
+        assert!(is_opaque(layer.join("a")));
+        assert_eq!(stack(&[&layer]).read_dir("a").unwrap(), ["b"]);
+        assert_eq!(stack(&[&layer]).read_dir("a/b").unwrap(), ["new"]);
+        assert!(is_opaque(upper.join("a")) && upper.join("a/c").is_file());
+        assert!(!is_opaque(layer.join("c")));
+        assert_eq!(stack(&[&layer]).read_dir("c").unwrap(), ["new", "old"]);
+    }
+
+    #[test]
+    fn resolves_through_whiteouts_and_opaque_dirs() {
+        let (_dir, [bottom, middle, top]) =
+            layers(&["d/old", "d/kept", "f"], &["d/new"], &["d/top"]);
+        set_opaque(middle.join("d")).unwrap();
+        create_whiteout(middle.join("f")).unwrap();
+        let layers = stack(&[&top, &middle, &bottom]);
+
+        assert_eq!(
+            layers.resolve("/d"),
+            Some(vec![top.join("d"), middle.join("d")])
+        );
+        assert_eq!(layers.resolve("d/new"), Some(vec![middle.join("d/new")]));
+        assert_eq!(layers.resolve("d/old"), None);
+        assert_eq!(layers.resolve("f"), None);
+        assert_eq!(layers.resolve("f/under"), None);
+        assert_eq!(layers.read_dir("d").unwrap(), ["new", "top"]);
+
+        // Without the opaque layer, the bottom one shows through
+        let layers = stack(&[&top, &bottom]);
+        assert_eq!(layers.read_dir("d").unwrap(), ["kept", "old", "top"]);
+        assert_eq!(layers.resolve("f"), Some(vec![bottom.join("f")]));
+    }
+
+    #[test]
+    fn restores_by_whiteouts_what_the_source_lacks() {
+        let (_dir, [source, current, upper]) = layers(&["f"], &["new"], &[]);
+        let lowers = stack(&[&current, &source]);
+        let source = stack(&[&source]);
+
+        restore_path(&source, &lowers, &upper, "/new").unwrap();
+        assert!(is_whiteout(&upper.join("new").symlink_metadata().unwrap()));
+        assert!(restore_path(&source, &lowers, &upper, "missing").is_err());
+        assert!(restore_path(&source, &lowers, &upper, "../f").is_err());
+        assert!(restore_path(&source, &lowers, &upper, "/").is_err());
+    }
+
+    #[test]
+    fn restores_by_removing_upper_entries_hiding_the_source() {
+        let (_dir, [source, _, upper]) = layers(&["d/f"], &[], &["d/f"]);
+        fs::write(upper.join("d/f"), "changed").unwrap();
+        let source = stack(&[&source]);
+
+        // The lower entry is the source one, so upperdir needs nothing
+        restore_path(&source, &source, &upper, "d/f").unwrap();
+        assert!(upper.join("d/f").symlink_metadata().is_err());
+    }
+
+    #[test]
+    fn restores_under_opaque_and_whiteout_ancestors() {
+        let (_dir, [source, current, upper]) = layers(
+            &["d/f", "d/g", "w/f"],
+            &["d/f", "d/g", "w/f", "w/g"],
+            &["d/"],
+        );
+        set_opaque(upper.join("d")).unwrap();
+        create_whiteout(upper.join("w")).unwrap();
+        let source_layers = stack(&[&source]);
+        let lowers = stack(&[&current]);
+
+        // The lower 'd/f' is the same as the source one but hidden by the opaque 'd'
+        restore_path(&source_layers, &lowers, &upper, "d/f").unwrap();
+        assert_eq!(fs::read_to_string(upper.join("d/f")).unwrap(), "d/f");
+        assert!(is_opaque(upper.join("d")));
+
+        // The whiteout of 'w' becomes an opaque dir showing nothing but the restored path
+        restore_path(&source_layers, &lowers, &upper, "w/f").unwrap();
+        assert!(upper.join("w").is_dir() && is_opaque(upper.join("w")));
+        let merged = stack(&[&upper, &current]);
+        assert_eq!(merged.read_dir("w").unwrap(), ["f"]);
+        assert_eq!(merged.read_dir("d").unwrap(), ["f"]);
+    }
+
+    #[test]
+    fn restores_dirs_as_opaque_copies() {
+        let (_dir, [source, current, upper]) = layers(&["d/f"], &["d/f", "d/extra"], &[]);
+        fs::write(current.join("d/f"), "changed").unwrap();
+        let source_layers = stack(&[&source]);
+        let lowers = stack(&[&current]);
+
+        restore_path(&source_layers, &lowers, &upper, "d").unwrap();
+        assert!(is_opaque(upper.join("d")));
+        let merged = stack(&[&upper, &current]);
+        assert_eq!(merged.read_dir("d").unwrap(), ["f"]);
+        assert_eq!(fs::read_to_string(upper.join("d/f")).unwrap(), "d/f");
+    }
+}
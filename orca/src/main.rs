@@ -1,24 +1,55 @@
+mod archive;
 mod args;
+mod bisect;
+mod bundle;
+mod environment;
+mod layer;
+mod orcaignore;
+mod pathspec;
+mod remote;
 
 use anyhow::{bail, Result};
-use args::{Action, Args, RunArgs};
-use clap::Parser;
+use args::{
+    Action, Args, BisectCommand, BundleCommand, EnvCommand, ExportFormat, ImageCommand, RemoteArgs,
+    RemoteCommand, RunArgs,
+};
+use bisect::{BisectState, BisectStep};
+use clap::{CommandFactory, Parser};
+use environment::{Base, EnvConfig, Environment};
+use layer::{Change, LayerStack, Selection};
 use nix::unistd::{getegid, geteuid};
 use orca_container::container::Container;
-use orca_container::image::HostImage;
-use orca_vcs::{Commit, CommitsIter, Error, VCS};
+use orca_container::image::{ContainerImage, GuestImage, HostImage};
+use orca_container::process::Process;
+use orca_image::exporter::{ExportLayer, ImageExporter};
+use orca_image::importer::ImageImporter;
+use orca_image::platform::Platform;
+use orca_image::pusher::ImagePusher;
+use orca_image::reference::Reference;
+use orca_image::store::BlobStore;
+use orca_vcs::{Commit, CommitsIter, Error, RefLogEntry, VCS};
+use orcaignore::IgnoreRules;
+use pathspec::Pathspec;
+use remote::{HttpServer, LocalStore, RefUpdate};
 use std::env;
-use std::fs::{create_dir_all, rename};
+use std::fs::{copy, create_dir, create_dir_all, remove_dir_all, rename};
 use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 
 const COMMITS_FILE_NAME: &str = "commits.toml";
+const IGNORE_FILE_NAME: &str = ".orcaignore";
+const BISECT_FILE_NAME: &str = "bisect.toml";
+const SESSION_LOCK_FILE_NAME: &str = "session.lock";
+const ENV_FILE_NAME: &str = "env.toml";
 const MOUNTPOINT_DIR_NAME: &str = "rootfs";
 const UPPER_DIR_NAME: &str = "upper";
 const WORK_DIR_NAME: &str = "work";
 const LOWER_DIR_NAME: &str = "layers";
 const TMP_DIR_NAME: &str = "tmp";
+const BLOB_STORE_DIR_NAME: &str = ".blobs";
+const HOST_ROOT: &str = "/";
+const DEFAULT_IMAGE_SHELL: &str = "/bin/sh";
 
 fn main() -> Result<()> {
     let args = Args::parse();
@@ -27,33 +58,145 @@ fn main() -> Result<()> {
 }
 
 fn run(args: Args) -> Result<()> {
-    let rootdir = args.root;
-    let env_root = PathBuf::from(rootdir).join(args.name);
+    let rootdir = PathBuf::from(args.root);
+    if args.list {
+        return print_environments(&rootdir, &args.name);
+    }
+    let Some(action) = args.action else {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingSubcommand,
+                "a subcommand is required unless '--list' is given",
+            )
+            .exit();
+    };
+    let args_name = match &action {
+        Action::Init(init_args) => init_args.name.clone().unwrap_or(args.name),
+        _ => args.name,
+    };
+    let env_root = rootdir.join(&args_name);
     let commits_file = env_root.join(COMMITS_FILE_NAME);
     let mount_point = env_root.join(MOUNTPOINT_DIR_NAME);
     let upperdir = env_root.join(UPPER_DIR_NAME);
     let workdir = env_root.join(WORK_DIR_NAME);
     let lower_root = env_root.join(LOWER_DIR_NAME);
     let tmpdir = env_root.join(TMP_DIR_NAME);
+    let ignore_file = env_root.join(IGNORE_FILE_NAME);
+    let bisect_file = env_root.join(BISECT_FILE_NAME);
+    let env_file = env_root.join(ENV_FILE_NAME);
 
-    if matches!(args.action, Action::Init(_)) {
-        create_dir_all(&mount_point)?;
-        create_dir_all(&upperdir)?;
-        create_dir_all(&workdir)?;
-        create_dir_all(&lower_root)?;
-        create_dir_all(&tmpdir)?;
+    if let Action::Init(args) = &action {
+        environment::check_name(&args_name)?;
+        if commits_file.exists() {
+            bail!("Environment '{args_name}' is already initialized");
+        }
+        let base = match &args.image {
+            Some(image) => {
+                // A tag in the image name (e.g. 'ubuntu:22.04') takes precedence over --tag
+                let reference = image.parse::<Reference>()?.or_tag(&args.tag);
+                let platform = match &args.platform {
+                    Some(platform) => platform.parse()?,
+                    None => Platform::host(),
+                };
+                let digest = orca_image::downloader::resolve_digest(&reference)?;
+                // The image is pinned so that it is the one whose digest is recorded
+                let pinned = reference.with_digest(&digest);
+                let store = BlobStore::new(rootdir.join(BLOB_STORE_DIR_NAME));
+                let image = GuestImage::download(&store, &pinned, platform.clone())?;
+                Base::Image {
+                    reference: reference.to_string(),
+                    digest,
+                    platform: Some(platform.to_string()),
+                    layers: image.layers,
+                    config: Box::new(image.config),
+                }
+            }
+            None => match (&args.from_archive, &args.from_oci_layout) {
+                (Some(path), _) | (_, Some(path)) => {
+                    let store = BlobStore::new(rootdir.join(BLOB_STORE_DIR_NAME));
+                    let mut importer = match &args.from_archive {
+                        Some(_) => ImageImporter::from_archive(path, &store)?,
+                        None => ImageImporter::from_oci_layout(path, &store)?,
+                    };
+                    importer.tag(&args.tag);
+                    if let Some(platform) = &args.platform {
+                        importer.platform(platform.parse()?);
+                    }
+                    let imported = GuestImage::import(importer, path)?;
+                    // Recorded with the absolute path, as the image is not in any registry
+                    let scheme = match &args.from_archive {
+                        Some(_) => "archive",
+                        None => "oci-layout",
+                    };
+                    Base::Image {
+                        reference: format!("{scheme}:{}", path.canonicalize()?.display()),
+                        digest: imported.digest,
+                        platform: imported.platform.map(|platform| platform.to_string()),
+                        layers: imported.image.layers,
+                        config: Box::new(imported.image.config),
+                    }
+                }
+                (None, None) => Base::Host,
+            },
+        };
+        // Layers of the image are already counted as used by the environment,
+        // which releases them on removal once its config is saved
+        let layers = match &base {
+            Base::Image { layers, .. } => layers.clone(),
+            _ => vec![],
+        };
+        let saved = (|| -> Result<()> {
+            for dir in [&mount_point, &upperdir, &workdir, &lower_root, &tmpdir] {
+                create_dir_all(dir)?;
+            }
+            EnvConfig::new(base).save(&env_file)
+        })();
+        if let Err(e) = saved {
+            let _ = BlobStore::new(rootdir.join(BLOB_STORE_DIR_NAME)).release(&layers);
+            return Err(e);
+        }
         VCS::init(&commits_file)?;
+        if !ignore_file.exists() {
+            IgnoreRules::create_default(&ignore_file)?;
+        }
         return Ok(());
     }
 
+    if let Action::Clone(args) = &action {
+        if !is_root() {
+            bail!(
+                "'Clone' needs root priviledge!
+                Execute with 'sudo' or setuid to binary!"
+            );
+        }
+        return clone_env(&rootdir, &args.source, &args.destination);
+    }
+
+    if let Action::Remote(RemoteArgs {
+        command: Some(RemoteCommand::Serve(args)),
+    }) = &action
+    {
+        let server = HttpServer::bind(&args.listen, &args.dir)?;
+        println!("Serving '{}' on {}", args.dir, server.url());
+        return server.serve();
+    }
+
+    if let Action::Env(args) = action {
+        return run_env_command(&rootdir, &args_name, args.command);
+    }
+
+    if let Action::Image(args) = action {
+        return run_image_command(&rootdir, args.command);
+    }
+
     let mut vcs = match VCS::new(&commits_file) {
         Ok(vcs) => vcs,
         Err(Error::NotInitialized) => bail!("You have to initialize with 'init'"),
         Err(e) => Err(e)?,
     };
 
-    match args.action {
-        Action::Init(_) => {
+    match action {
+        Action::Init(_) | Action::Clone(_) | Action::Env(_) | Action::Image(_) => {
             // do nothing
             Ok(())
         }
@@ -71,11 +214,16 @@ fn run(args: Args) -> Result<()> {
                 Err(Error::CommitNotFound) => vec![],
                 Err(e) => Err(e)?,
             };
-            let lowerdirs = create_lowerdirs_from_commits(commits, lower_root);
-            let argv = run_args_to_vec(args, env::var("SHELL").unwrap());
-            let image = HostImage::new(mount_point, upperdir, lowerdirs, workdir, &tmpdir);
-            let working_container = Container::new(image, argv, tmpdir)?;
-            working_container.wait()?;
+            let lowerdirs = create_lowerdirs_from_commits(commits, &lower_root);
+            let process = create_process(&env_root, run_args_to_vec(args))?;
+            let image = create_image(&env_root, lowerdirs)?;
+            let status = {
+                let _session = Environment::open(&rootdir, &args_name)?.lock_session()?;
+                run_container(image, process, tmpdir)?
+            };
+            if status != 0 {
+                std::process::exit(status);
+            }
 
             Ok(())
         }
@@ -88,34 +236,110 @@ fn run(args: Args) -> Result<()> {
             print_commits_info(commits);
             Ok(())
         }
+        Action::Reflog => {
+            print_reflog(vcs.get_reflog());
+            Ok(())
+        }
         Action::Commit(args) => {
-            let commit = vcs.commit(args.message)?;
-            rename(&upperdir, lower_root.join(commit.id.as_str()))?;
-            create_dir_all(&upperdir)?;
-            println!("{}", commit.id.as_str());
+            let _session = Environment::open(&rootdir, &args_name)?.lock_session()?;
+            let pathspec = Pathspec::new(&args.paths, &args.excludes)?;
+            let ignored = IgnoreRules::load(&ignore_file)?.scan(&upperdir)?;
+            let selected = layer::collect_selected(&upperdir, |path| {
+                ignored.filter(path, pathspec.select(path))
+            })?;
+            if selected.is_empty() && !pathspec.is_all() {
+                bail!("No changes match specified paths");
+            }
+
+            if vcs.is_detached() {
+                eprintln!(
+                    "Warning: committing on detached HEAD. \
+                    Create a branch with 'orca branch <name>' to keep this commit"
+                );
+            }
+            let commit_id = if args.amend {
+                let (old_id, new_commit) = match vcs.prepare_amend(args.message) {
+                    Ok(res) => res,
+                    Err(Error::CommitNotFound) => bail!("There is no commit to amend"),
+                    Err(Error::CommitReferenced) => {
+                        bail!("Cannot amend a commit which other branches or commits are based on")
+                    }
+                    Err(e) => Err(e)?,
+                };
+                // commits.toml must not point at a layer which has not been merged
+                // and renamed yet
+                let old_layer_dir = lower_root.join(&old_id);
+                let layer_dir = lower_root.join(new_commit.id.as_str());
+                layer::merge_entries(&upperdir, &old_layer_dir, &selected)?;
+                rename(&old_layer_dir, &layer_dir)?;
+                match vcs.amend(new_commit) {
+                    Ok(commit) => commit.id.clone(),
+                    Err(e) => {
+                        rename(&layer_dir, &old_layer_dir)?;
+                        Err(e)?
+                    }
+                }
+            } else {
+                let commit = vcs.commit(args.message)?;
+                let layer_dir = lower_root.join(commit.id.as_str());
+                create_dir(&layer_dir)?;
+                layer::copy_metadata(&upperdir, &layer_dir)?;
+                layer::move_entries(&upperdir, &layer_dir, &selected)?;
+                commit.id.clone()
+            };
+            if args.clean_ignored {
+                // Committing leaves ignored entries where they were
+                layer::remove_entries(&upperdir, &ignored.into_paths())?;
+            }
+            println!("{commit_id}");
             Ok(())
         }
         Action::Branch(args) => {
             if args.all {
                 let branches = vcs.get_all_branches();
                 print_all_branches(branches);
+                for branch in vcs.get_remote_branches() {
+                    println!("remotes/{}/{}", branch.remote, branch.name);
+                }
                 Ok(())
             } else if args.delete {
                 vcs.delete_branch(args.branch_name.unwrap());
                 Ok(())
+            } else if args.rename {
+                let branch_name = args.branch_name.unwrap();
+                match args.start_point {
+                    Some(new_name) => vcs.rename_branch(branch_name, new_name)?,
+                    None => match vcs.get_current_branch() {
+                        Some(current) => vcs.rename_branch(current.to_string(), branch_name)?,
+                        None => bail!("HEAD is detached, specify branch to rename"),
+                    },
+                }
+                Ok(())
             } else if let Some(branch_name) = args.branch_name {
-                vcs.create_branch(branch_name)
+                vcs.create_branch(branch_name, args.start_point)?;
+                Ok(())
             } else {
-                let branches = vcs.get_current_branch();
-                println!("{branches}");
+                match vcs.get_current_branch() {
+                    Some(branch) => println!("{branch}"),
+                    None => println!("(HEAD detached at {})", vcs.get_head_commit()?.id),
+                }
                 Ok(())
             }
         }
         Action::Checkout(args) => {
-            if upperdir.read_dir()?.next().is_some() {
+            let ignore = IgnoreRules::load(&ignore_file)?;
+            if has_changes(&upperdir, &ignore)? {
                 bail!("You have commit first");
             }
-            vcs.checkout(args.query)?;
+            if let Some(new_branch) = args.orphan {
+                vcs.create_orphan_branch(&new_branch)?;
+                vcs.checkout(new_branch)?;
+            } else if let Some(new_branch) = args.new_branch {
+                vcs.create_branch(&new_branch, args.query)?;
+                vcs.checkout(new_branch)?;
+            } else {
+                vcs.checkout(args.query.unwrap())?;
+            }
             Ok(())
         }
         Action::Merge(_) => {
@@ -125,15 +349,640 @@ fn run(args: Args) -> Result<()> {
             unimplemented!();
         }
         Action::Diff => {
-            print_dir_content_recursively(upperdir);
+            let ignore = IgnoreRules::load(&ignore_file)?;
+            print_dir_content_recursively(upperdir, &ignore);
+            Ok(())
+        }
+        Action::Status => {
+            let ignore = IgnoreRules::load(&ignore_file)?;
+            match vcs.get_current_branch() {
+                Some(branch) => println!("On branch {branch}"),
+                None => println!("HEAD detached at {}", vcs.get_head_commit()?.id),
+            }
+            if has_changes(&upperdir, &ignore)? {
+                println!("Changes not committed:");
+                print_dir_content_recursively(upperdir, &ignore);
+            } else {
+                println!("Nothing to commit");
+            }
+            Ok(())
+        }
+        Action::Clean(args) => {
+            let _session = Environment::open(&rootdir, &args_name)?.lock_session()?;
+            if args.ignored {
+                let ignore = IgnoreRules::load(&ignore_file)?;
+                clean_ignored(&upperdir, &ignore)?;
+            } else {
+                std::fs::remove_dir_all(&upperdir)?;
+                std::fs::create_dir_all(&upperdir)?;
+            }
+            Ok(())
+        }
+        Action::Restore(args) => {
+            if !is_root() {
+                bail!(
+                    "'Restore' needs root priviledge!
+                    Execute with 'sudo' or setuid to binary!"
+                );
+            }
+            let _session = Environment::open(&rootdir, &args_name)?.lock_session()?;
+
+            let source_commits: Vec<&Commit> = match vcs.get_commits(&args.source) {
+                Ok(commits) => commits.collect(),
+                Err(Error::CommitNotFound) if args.source == "HEAD" => vec![],
+                Err(e) => Err(e)?,
+            };
+            let current_commits: Vec<&Commit> = match vcs.get_current_commits() {
+                Ok(commits) => commits.collect(),
+                Err(Error::CommitNotFound) => vec![],
+                Err(e) => Err(e)?,
+            };
+            let base_layers = create_base_layers(&env_root)?;
+            let mut source_layers = create_lowerdirs_from_commits(source_commits, &lower_root);
+            source_layers.extend(base_layers.iter().cloned());
+            let mut current_layers = create_lowerdirs_from_commits(current_commits, &lower_root);
+            current_layers.extend(base_layers);
+
+            let source = LayerStack::new(source_layers);
+            let current = LayerStack::new(current_layers);
+            for path in args.paths {
+                layer::restore_path(&source, &current, &upperdir, &path)?;
+            }
             Ok(())
         }
-        Action::Clean => {
-            std::fs::remove_dir_all(&upperdir)?;
-            std::fs::create_dir_all(&upperdir)?;
+        Action::Bisect(args) => {
+            let ignore = IgnoreRules::load(&ignore_file)?;
+            let state = BisectState::load(&bisect_file)?;
+            match (args.command, state) {
+                (BisectCommand::Start(args), None) => {
+                    if has_changes(&upperdir, &ignore)? {
+                        bail!("You have commit first");
+                    }
+                    let original_head = match vcs.get_current_branch() {
+                        Some(branch) => branch.to_string(),
+                        None => vcs.get_head_commit()?.id.clone(),
+                    };
+                    let mut state = BisectState {
+                        original_head,
+                        ..Default::default()
+                    };
+                    if let Some(bad) = args.bad {
+                        state.bad = Some(vcs.get_commit(bad)?.id.clone());
+                    }
+                    for good in args.good {
+                        state.good.push(vcs.get_commit(good)?.id.clone());
+                    }
+                    state.save(&bisect_file)?;
+                    bisect_next(&mut vcs, &state)?;
+                }
+                (BisectCommand::Start(_), Some(_)) => {
+                    bail!("Already bisecting, run 'bisect reset' first")
+                }
+                (BisectCommand::Reset, Some(state)) => {
+                    if has_changes(&upperdir, &ignore)? {
+                        bail!("You have commit first");
+                    }
+                    vcs.checkout(&state.original_head)?;
+                    std::fs::remove_file(&bisect_file)?;
+                }
+                (BisectCommand::Run(args), Some(mut state)) => {
+                    if !is_root() {
+                        bail!(
+                            "'Bisect run' needs root priviledge!
+                            Execute with 'sudo' or setuid to binary!"
+                        );
+                    }
+                    if has_changes(&upperdir, &ignore)? {
+                        bail!("You have commit first");
+                    }
+                    // Each step resets upper, which would silently delete the ignored entries
+                    if std::fs::read_dir(&upperdir)?.next().is_some() {
+                        bail!(
+                            "Upper layer has ignored files, which 'bisect run' would delete. \
+                            Remove them with 'orca clean --ignored' first"
+                        );
+                    }
+                    let mut argv = vec![args.command];
+                    argv.extend(args.args);
+                    let process = create_process(&env_root, Some(argv))?;
+                    let _session = Environment::open(&rootdir, &args_name)?.lock_session()?;
+                    loop {
+                        let commit = match state.next_step(&vcs)? {
+                            BisectStep::Test { commit, .. } => commit,
+                            _ => break,
+                        };
+                        vcs.checkout(&commit.id)?;
+                        let commits: Vec<&Commit> = vcs.get_current_commits()?.collect();
+                        let lowerdirs = create_lowerdirs_from_commits(commits, &lower_root);
+                        let image = create_image(&env_root, lowerdirs)?;
+                        let status = run_container(image, process.clone(), &tmpdir)?;
+                        std::fs::remove_dir_all(&upperdir)?;
+                        create_dir_all(&upperdir)?;
+                        match status {
+                            0 => state.good.push(commit.id),
+                            bisect::SKIP_EXIT_CODE => state.skipped.push(commit.id),
+                            1..=127 => state.bad = Some(commit.id),
+                            _ => bail!("Bisect run aborted: command exited with {status}"),
+                        }
+                        state.save(&bisect_file)?;
+                    }
+                    bisect_next(&mut vcs, &state)?;
+                }
+                (BisectCommand::Good(args), Some(mut state)) => {
+                    if has_changes(&upperdir, &ignore)? {
+                        bail!("You have commit first");
+                    }
+                    state.good.push(vcs.get_commit(args.query)?.id.clone());
+                    state.save(&bisect_file)?;
+                    bisect_next(&mut vcs, &state)?;
+                }
+                (BisectCommand::Bad(args), Some(mut state)) => {
+                    if has_changes(&upperdir, &ignore)? {
+                        bail!("You have commit first");
+                    }
+                    state.bad = Some(vcs.get_commit(args.query)?.id.clone());
+                    state.save(&bisect_file)?;
+                    bisect_next(&mut vcs, &state)?;
+                }
+                (BisectCommand::Skip(args), Some(mut state)) => {
+                    if has_changes(&upperdir, &ignore)? {
+                        bail!("You have commit first");
+                    }
+                    state.skipped.push(vcs.get_commit(args.query)?.id.clone());
+                    state.save(&bisect_file)?;
+                    bisect_next(&mut vcs, &state)?;
+                }
+                (_, None) => bail!("Not bisecting, run 'bisect start' first"),
+            }
             Ok(())
         }
+        Action::Blame(args) => {
+            if layer::to_relative(&args.path).is_none() {
+                bail!("Invalid path: '{}'", args.path);
+            }
+            let commits: Vec<&Commit> = match vcs.get_current_commits() {
+                Ok(commits) => commits.collect(),
+                Err(Error::CommitNotFound) => vec![],
+                Err(e) => Err(e)?,
+            };
+            let mut layers = create_lowerdirs_from_commits(commits.clone(), &lower_root);
+            layers.extend(create_base_layers(&env_root)?);
+
+            let history: Vec<(&Commit, Change)> = commits
+                .into_iter()
+                .enumerate()
+                .filter_map(|(i, commit)| {
+                    let stack = LayerStack::new(layers[i..].to_vec());
+                    stack
+                        .top_layer_change(&args.path)
+                        .map(|change| (commit, change))
+                })
+                .collect();
+
+            let Some((last_commit, last_change)) = history.first() else {
+                if LayerStack::new(layers).exists(&args.path) {
+                    println!("'{}' has not been changed by any commit", args.path);
+                    return Ok(());
+                }
+                bail!("'{}' does not exist in any commit", args.path);
+            };
+            println!("commit: {}", last_commit.id);
+            println!("  date: {}", last_commit.date);
+            if let Some(message) = last_commit.message.as_ref() {
+                println!("  message: {}", message);
+            }
+            println!("  change: {}", last_change);
+            println!();
+            println!("History:");
+            for (commit, change) in history.iter() {
+                let short_id = commit.id.get(..7).unwrap_or(&commit.id);
+                let message = commit.message.as_deref().unwrap_or_default();
+                println!("{short_id} {change:<8} {}  {message}", commit.date);
+            }
+            Ok(())
+        }
+        Action::Bundle(args) => match args.command {
+            BundleCommand::Create(args) => {
+                let commits = bundle::collect_range(&vcs, &args.range)?;
+                let num_commits = commits.len();
+                bundle::create(&args.file, commits, &lower_root, args.zstd)?;
+                println!("Bundled {num_commits} commits into '{}'", args.file);
+                Ok(())
+            }
+            BundleCommand::Import(args) => {
+                if !is_root() {
+                    bail!(
+                        "'Bundle import' needs root priviledge!
+                        Execute with 'sudo' or setuid to binary!"
+                    );
+                }
+
+                let staging_dir = tmpdir.join("bundle");
+                if staging_dir.exists() {
+                    std::fs::remove_dir_all(&staging_dir)?;
+                }
+                create_dir_all(&staging_dir)?;
+                let manifest = bundle::unpack(&args.file, &staging_dir)?;
+                if let Some(prerequisite) = manifest.prerequisite.as_ref()
+                    && vcs.get_commit(prerequisite).is_err()
+                {
+                    bail!("Bundle requires commit {prerequisite} which is not in this environment");
+                }
+
+                for commit in manifest.commits.iter() {
+                    if vcs.get_commit(&commit.id).is_ok() {
+                        continue;
+                    }
+                    let layer_dir = lower_root.join(&commit.id);
+                    if layer_dir.exists() {
+                        bail!("Layer '{}' already exists", layer_dir.display());
+                    }
+                    rename(bundle::unpacked_layer(&staging_dir, &commit.id), layer_dir)?;
+                }
+                let tip = manifest.commits.last().unwrap().id.clone();
+                let num_imported = vcs.import_commits(manifest.commits)?;
+                std::fs::remove_dir_all(&staging_dir)?;
+                if let Some(branch) = args.branch {
+                    vcs.create_branch(branch, Some(&tip))?;
+                }
+                println!("Imported {num_imported} commits, newest is {tip}");
+                Ok(())
+            }
+        },
+        Action::Export(args) => {
+            if !is_root() {
+                bail!(
+                    "'Export' needs root priviledge!
+                    Execute with 'sudo' or setuid to binary!"
+                );
+            }
+
+            let commits: Vec<&Commit> = match vcs.get_commits(&args.rev) {
+                Ok(commits) => commits.collect(),
+                Err(Error::CommitNotFound) if args.rev == "HEAD" => vec![],
+                Err(e) => Err(e)?,
+            };
+            let mut exporter = create_exporter(&env_root, commits)?;
+            if let Some(tag) = &args.tag {
+                exporter.tag(tag);
+            }
+            let exported = match args.format {
+                ExportFormat::OciLayout => exporter.write_oci_layout(&args.dest)?,
+                ExportFormat::DockerArchive => exporter.write_docker_archive(&args.dest)?,
+            };
+            println!(
+                "Exported {} layers into '{}', manifest is {}",
+                exported.manifest.layers.len(),
+                args.dest.display(),
+                exported.digest
+            );
+            Ok(())
+        }
+        Action::Remote(args) => {
+            match args.command {
+                Some(RemoteCommand::Add(args)) => {
+                    vcs.add_remote(args.name, remote::normalize_url(&args.url)?)?;
+                }
+                Some(RemoteCommand::Remove(args)) => vcs.remove_remote(&args.name)?,
+                Some(RemoteCommand::Serve(_)) => unreachable!(),
+                None => {
+                    for remote in vcs.get_remotes() {
+                        println!("{}\t{}", remote.name, remote.url);
+                    }
+                }
+            }
+            Ok(())
+        }
+        Action::Push(args) => {
+            if let Some(image) = &args.image {
+                if !is_root() {
+                    bail!(
+                        "'Push' of an image needs root priviledge!
+                        Execute with 'sudo' or setuid to binary!"
+                    );
+                }
+                let reference: Reference = image.parse()?;
+                let rev = args.rev.as_str();
+                let commits: Vec<&Commit> = match vcs.get_commits(rev) {
+                    Ok(commits) => commits.collect(),
+                    Err(Error::CommitNotFound) if rev == "HEAD" => vec![],
+                    Err(e) => Err(e)?,
+                };
+                let exporter = create_exporter(&env_root, commits)?;
+                let layout = tmpdir.join("push");
+                if layout.exists() {
+                    remove_dir_all(&layout)?;
+                }
+                let exported = exporter.write_oci_layout(&layout)?;
+
+                println!("Push container image: {}", reference);
+                let mut pusher = ImagePusher::new(&reference);
+                if let Some(chunk_size) = args.chunk_size {
+                    pusher.chunk_size(chunk_size);
+                }
+                // Layers of the base image can be mounted from its repository
+                if let Some(EnvConfig {
+                    base:
+                        Base::Image {
+                            reference: base, ..
+                        },
+                    ..
+                }) = EnvConfig::load(env_root.join(ENV_FILE_NAME))?
+                    && let Ok(base) = base.parse::<Reference>()
+                {
+                    pusher.mount_from(&base);
+                }
+                pusher.post_push_display(display_post_push);
+                let pushed = pusher.push(&layout, &exported);
+                remove_dir_all(&layout)?;
+                let report = pushed?;
+                println!(
+                    "Pushed {}@{} ({} uploaded, {} mounted, {} already existed)",
+                    reference.name(),
+                    exported.digest,
+                    report.uploaded,
+                    report.mounted,
+                    report.existing
+                );
+                return Ok(());
+            }
+
+            let remote_name = match args.remote {
+                Some(remote_name) => remote_name,
+                None => remote::default_remote(&vcs)?,
+            };
+            let branch = match args.branch {
+                Some(branch) => branch,
+                None => match vcs.get_current_branch() {
+                    Some(branch) => branch.to_string(),
+                    None => bail!("HEAD is detached, specify branch to push"),
+                },
+            };
+            let local = LocalStore {
+                lower_root: &lower_root,
+                tmpdir: &tmpdir,
+            };
+            let update = remote::push(&mut vcs, &remote_name, &branch, args.force, &local)?;
+            println!("To {}", vcs.get_remote(&remote_name)?.url);
+            match update {
+                Some(update) => print_ref_update(&update, &remote_name),
+                None => println!("Everything up-to-date"),
+            }
+            Ok(())
+        }
+        Action::Fetch(args) => {
+            if !is_root() {
+                bail!(
+                    "'Fetch' needs root priviledge!
+                    Execute with 'sudo' or setuid to binary!"
+                );
+            }
+            let remote_name = match args.remote {
+                Some(remote_name) => remote_name,
+                None => remote::default_remote(&vcs)?,
+            };
+            let local = LocalStore {
+                lower_root: &lower_root,
+                tmpdir: &tmpdir,
+            };
+            let updates = remote::fetch(&mut vcs, &remote_name, &local)?;
+            if !updates.is_empty() {
+                println!("From {}", vcs.get_remote(&remote_name)?.url);
+            }
+            for update in updates.iter() {
+                print_ref_update(update, &remote_name);
+            }
+            Ok(())
+        }
+        Action::Pull(args) => {
+            if !is_root() {
+                bail!(
+                    "'Pull' needs root priviledge!
+                    Execute with 'sudo' or setuid to binary!"
+                );
+            }
+            let Some(branch) = vcs.get_current_branch().map(|branch| branch.to_string()) else {
+                bail!("HEAD is detached, checkout a branch to pull into");
+            };
+            let remote_name = match args.remote {
+                Some(remote_name) => remote_name,
+                None => remote::default_remote(&vcs)?,
+            };
+            let local = LocalStore {
+                lower_root: &lower_root,
+                tmpdir: &tmpdir,
+            };
+            let updates = remote::fetch(&mut vcs, &remote_name, &local)?;
+            if !updates.is_empty() {
+                println!("From {}", vcs.get_remote(&remote_name)?.url);
+            }
+            for update in updates.iter() {
+                print_ref_update(update, &remote_name);
+            }
+
+            let tracking = format!("{remote_name}/{branch}");
+            let Ok(target) = vcs.get_commit(&tracking).map(|commit| commit.id.clone()) else {
+                bail!("Remote '{remote_name}' does not have branch '{branch}'");
+            };
+            if vcs.get_head_commit().is_ok_and(|head| head.id == target) {
+                println!("Already up to date");
+                return Ok(());
+            }
+            let ignore = IgnoreRules::load(&ignore_file)?;
+            if has_changes(&upperdir, &ignore)? {
+                bail!("You have commit first");
+            }
+            match vcs.fast_forward(&tracking) {
+                Ok(()) => println!("Fast-forward to {target}"),
+                Err(Error::NotFastForward) => {
+                    bail!("'{branch}' and '{tracking}' have diverged, cannot fast-forward")
+                }
+                Err(e) => Err(e)?,
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Creates environment `destination` with the layers and commits of `source`.
+/// Only committed state is cloned, so a mounted session of `source` does not matter.
+fn clone_env(rootdir: &Path, source: &str, destination: &str) -> Result<()> {
+    let src_root = rootdir.join(source);
+    let dest_root = rootdir.join(destination);
+    if !src_root.join(COMMITS_FILE_NAME).exists() {
+        bail!("Environment '{source}' does not exist");
+    }
+    environment::check_name(destination)?;
+    if dest_root.exists() {
+        bail!("Environment '{destination}' already exists");
+    }
+
+    // Built in tmpdir of the source and moved at last, so that a failure leaves nothing behind
+    let staging = src_root.join(TMP_DIR_NAME).join("clone");
+    if staging.exists() {
+        remove_dir_all(&staging)?;
+    }
+    for dir_name in [
+        MOUNTPOINT_DIR_NAME,
+        UPPER_DIR_NAME,
+        WORK_DIR_NAME,
+        TMP_DIR_NAME,
+    ] {
+        create_dir_all(staging.join(dir_name))?;
+    }
+    layer::link_tree(src_root.join(LOWER_DIR_NAME), staging.join(LOWER_DIR_NAME))?;
+    copy(
+        src_root.join(COMMITS_FILE_NAME),
+        staging.join(COMMITS_FILE_NAME),
+    )?;
+    if let Some(config) = EnvConfig::load(src_root.join(ENV_FILE_NAME))? {
+        if let Base::Image { layers, .. } = &config.base {
+            BlobStore::new(rootdir.join(BLOB_STORE_DIR_NAME)).add_refs(layers)?;
+        }
+        EnvConfig::new(config.base).save(staging.join(ENV_FILE_NAME))?;
+    }
+    if src_root.join(IGNORE_FILE_NAME).exists() {
+        copy(
+            src_root.join(IGNORE_FILE_NAME),
+            staging.join(IGNORE_FILE_NAME),
+        )?;
+    }
+    rename(&staging, &dest_root)?;
+
+    let ignore = IgnoreRules::load(src_root.join(IGNORE_FILE_NAME))?;
+    if has_changes(src_root.join(UPPER_DIR_NAME), &ignore)? {
+        eprintln!("Warning: uncommitted changes of '{source}' are not cloned");
+    }
+    println!("Cloned '{source}' into '{destination}'");
+    Ok(())
+}
+
+fn run_env_command(rootdir: &Path, current: &str, command: EnvCommand) -> Result<()> {
+    match command {
+        EnvCommand::List => print_environments(rootdir, current)?,
+        EnvCommand::Info(args) => {
+            let env = Environment::open(rootdir, args.name.as_deref().unwrap_or(current))?;
+            let vcs = env.vcs()?;
+            println!("name: {}", env.name);
+            println!("  path: {}", env.root.display());
+            match env.config()? {
+                Some(config) => {
+                    match config.base {
+                        Base::Host => println!("  base: host"),
+                        Base::Image {
+                            reference,
+                            digest,
+                            platform,
+                            ..
+                        } => {
+                            println!("  base: image {reference}");
+                            println!("  digest: {digest}");
+                            if let Some(platform) = platform {
+                                println!("  platform: {platform}");
+                            }
+                        }
+                    }
+                    println!("  created: {}", config.created);
+                    println!("  orca version: {}", config.orca_version);
+                }
+                None => {
+                    println!("  base: host");
+                    println!("  created: {}", env.created()?);
+                }
+            }
+            println!("  head: {}", env.head(&vcs));
+            println!("  commits: {}", env.commit_count(&vcs)?);
+            println!("  branches: {}", vcs.get_all_branches().join(", "));
+            for remote in vcs.get_remotes() {
+                println!("  remote: {} {}", remote.name, remote.url);
+            }
+            println!("  size: {}", environment::format_size(env.disk_usage()?));
+            if let Some(pid) = env.session_holder() {
+                println!("  in use by: process {pid}");
+            }
+        }
+        EnvCommand::Rm(args) => {
+            if !is_root() {
+                bail!(
+                    "'Env rm' needs root priviledge!
+                    Execute with 'sudo' or setuid to binary!"
+                );
+            }
+            Environment::open(rootdir, &args.name)?.remove()?;
+            println!("Removed '{}'", args.name);
+        }
+        EnvCommand::Rename(args) => {
+            Environment::open(rootdir, &args.old_name)?.rename(&args.new_name)?;
+            println!("Renamed '{}' to '{}'", args.old_name, args.new_name);
+        }
+    }
+    Ok(())
+}
+
+fn run_image_command(rootdir: &Path, command: ImageCommand) -> Result<()> {
+    match command {
+        ImageCommand::Prune => {
+            if !is_root() {
+                bail!(
+                    "'Image prune' needs root priviledge!
+                    Execute with 'sudo' or setuid to binary!"
+                );
+            }
+            let stats = BlobStore::new(rootdir.join(BLOB_STORE_DIR_NAME)).prune()?;
+            println!(
+                "Deleted {} blobs, freed {}",
+                stats.blobs,
+                environment::format_size(stats.bytes)
+            );
+        }
+    }
+    Ok(())
+}
+
+fn print_environments(rootdir: &Path, current: &str) -> Result<()> {
+    let mut rows = vec![];
+    for env in Environment::list(rootdir)? {
+        let vcs = env.vcs()?;
+        rows.push([
+            format!(
+                "{} {}",
+                if env.name == current { "*" } else { " " },
+                env.name
+            ),
+            env.kind()?,
+            env.head(&vcs),
+            env.commit_count(&vcs)?.to_string(),
+            environment::format_size(env.disk_usage()?),
+        ]);
+    }
+    let header = ["  NAME", "TYPE", "BRANCH", "COMMITS", "SIZE"].map(|s| s.to_string());
+    let mut widths = header.clone().map(|column| column.len());
+    for row in rows.iter() {
+        for (width, column) in widths.iter_mut().zip(row) {
+            *width = (*width).max(column.len());
+        }
+    }
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(column, width)| format!("{column:<width$}"))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
     }
+    Ok(())
+}
+
+fn print_ref_update(update: &RefUpdate, remote_name: &str) {
+    let short = |id: &str| id.get(..7).unwrap_or(id).to_string();
+    let range = match update.old_id.as_deref() {
+        Some(old_id) => format!("{}..{}", short(old_id), short(&update.new_id)),
+        None => "[new branch]".to_string(),
+    };
+    println!(
+        "   {range:<16} {} -> {remote_name}/{}",
+        update.branch, update.branch
+    );
 }
 
 fn is_root() -> bool {
@@ -150,18 +999,186 @@ fn create_lowerdirs_from_commits<P: AsRef<Path>>(
         .collect()
 }
 
-fn run_args_to_vec<S: ToString>(args: RunArgs, default_cmd: S) -> Vec<String> {
-    let mut v: Vec<String> = vec![];
-    match args.command {
-        Some(cmd) => {
-            v.push(cmd);
-            if let Some(mut cmd_args) = args.args {
-                v.append(&mut cmd_args);
+/// Stacks `lowerdirs` on the base recorded in env.toml of the environment at `env_root`
+fn create_image(env_root: &Path, lowerdirs: Vec<PathBuf>) -> Result<Box<dyn ContainerImage>> {
+    let mount_point = env_root.join(MOUNTPOINT_DIR_NAME);
+    let upperdir = env_root.join(UPPER_DIR_NAME);
+    let workdir = env_root.join(WORK_DIR_NAME);
+    let tmpdir = env_root.join(TMP_DIR_NAME);
+    let config = EnvConfig::load(env_root.join(ENV_FILE_NAME))?;
+    match config.map(|config| config.base) {
+        None | Some(Base::Host) => Ok(Box::new(HostImage::new(
+            mount_point,
+            upperdir,
+            lowerdirs,
+            workdir,
+            tmpdir,
+        ))),
+        Some(Base::Image { layers, .. }) => Ok(Box::new(GuestImage::new(
+            mount_point,
+            upperdir,
+            lowerdirs,
+            workdir,
+            image_layer_dirs(env_root, &layers)?,
+        ))),
+    }
+}
+
+/// Layers under the ones of commits in the environment at `env_root`, from the top one:
+/// the layers of its base image, or the root of the host
+fn create_base_layers(env_root: &Path) -> Result<Vec<PathBuf>> {
+    let config = EnvConfig::load(env_root.join(ENV_FILE_NAME))?;
+    match config.map(|config| config.base) {
+        None | Some(Base::Host) => Ok(vec![PathBuf::from(HOST_ROOT)]),
+        Some(Base::Image { layers, .. }) => {
+            let mut layer_dirs = image_layer_dirs(env_root, &layers)?;
+            layer_dirs.reverse();
+            Ok(layer_dirs)
+        }
+    }
+}
+
+/// Directories of image `layers` (digests from the bottom one) in the blob store
+fn image_layer_dirs(env_root: &Path, layers: &[String]) -> Result<Vec<PathBuf>> {
+    if layers.is_empty() {
+        bail!("Image layers of the environment are not recorded, create it again with 'init'");
+    }
+    let store = BlobStore::new(env_root.with_file_name(BLOB_STORE_DIR_NAME));
+    Ok(layers
+        .iter()
+        .map(|digest| store.layer_dir(digest))
+        .collect())
+}
+
+/// Exporter of the image of the environment at `env_root` with `commits` (newest first):
+/// layers of its base image followed by the layer of each commit from the oldest one
+fn create_exporter(env_root: &Path, commits: Vec<&Commit>) -> Result<ImageExporter> {
+    let Some(EnvConfig {
+        base:
+            Base::Image {
+                platform,
+                layers,
+                config,
+                ..
+            },
+        ..
+    }) = EnvConfig::load(env_root.join(ENV_FILE_NAME))?
+    else {
+        bail!("Only environments created from a container image can be exported");
+    };
+    if layers.is_empty() {
+        bail!("Image layers of the environment are not recorded, create it again with 'init'");
+    }
+    let platform = match platform {
+        Some(platform) => platform.parse()?,
+        None => Platform::host(),
+    };
+
+    let store = BlobStore::new(env_root.with_file_name(BLOB_STORE_DIR_NAME));
+    let lower_root = env_root.join(LOWER_DIR_NAME);
+    let mut exporter = ImageExporter::new(*config, platform);
+    for digest in layers.iter() {
+        exporter.add_layer(ExportLayer::Blob(store.blob_path(digest)));
+    }
+    for commit in commits.into_iter().rev() {
+        exporter.add_layer(ExportLayer::Dir(lower_root.join(&commit.id)));
+    }
+    exporter.post_export_display(display_post_export);
+    Ok(exporter)
+}
+
+fn display_post_export(num_of_layer: usize, exported_layer: usize) {
+    if exported_layer == 0 {
+        println!();
+    }
+    print!("\r\x1b[1A\x1b[K");
+    println!("{}/{} layer has exported", exported_layer, num_of_layer);
+}
+
+fn display_post_push(num_of_blob: usize, pushed_blob: usize) {
+    if pushed_blob == 0 {
+        println!();
+    }
+    print!("\r\x1b[1A\x1b[K");
+    println!("{}/{} blob has pushed", pushed_blob, num_of_blob);
+}
+
+/// Process running `argv` in the environment at `env_root`. Environments created from
+/// a container image run it with the Env, WorkingDir and User of the image, and
+/// run Entrypoint and Cmd of the image if `argv` is not given.
+fn create_process(env_root: &Path, argv: Option<Vec<String>>) -> Result<Process> {
+    let config = match EnvConfig::load(env_root.join(ENV_FILE_NAME))? {
+        Some(EnvConfig {
+            base: Base::Image { config, .. },
+            ..
+        }) => config,
+        // The host has no defaults other than the shell of the user
+        _ => {
+            let argv = argv.unwrap_or_else(|| vec![env::var("SHELL").unwrap()]);
+            return Ok(Process::new(argv));
+        }
+    };
+    // $SHELL of the host may not exist in the image
+    let argv = argv
+        .or_else(|| Some(config.command()).filter(|command| !command.is_empty()))
+        .unwrap_or_else(|| vec![DEFAULT_IMAGE_SHELL.to_string()]);
+    let mut process = Process::new(argv).env(config.env.unwrap_or_default());
+    if let Some(cwd) = config.working_dir.filter(|cwd| !cwd.is_empty()) {
+        process = process.cwd(cwd);
+    }
+    if let Some(user) = config.user.filter(|user| !user.is_empty()) {
+        process = process.user(user);
+    }
+    Ok(process)
+}
+
+/// Runs `process` in a container on `image` and returns its exit status
+fn run_container<P: AsRef<Path>>(
+    image: Box<dyn ContainerImage>,
+    process: Process,
+    tmpdir: P,
+) -> Result<i32> {
+    let working_container = Container::new(image, process, tmpdir)?;
+    let (_, status) = working_container.wait()?;
+    Ok(status)
+}
+
+/// Checks out the next commit to test, or reports the result of bisection
+fn bisect_next(vcs: &mut VCS, state: &BisectState) -> Result<()> {
+    match state.next_step(vcs)? {
+        BisectStep::NeedMarks => {
+            println!("Waiting for both good and bad commits");
+        }
+        BisectStep::Test { commit, remaining } => {
+            vcs.checkout(&commit.id)?;
+            let steps = usize::BITS - remaining.leading_zeros();
+            println!("Bisecting: {remaining} commits left to test (roughly {steps} steps)");
+            println!("[{}] {}", commit.id, commit.message.unwrap_or_default());
+        }
+        BisectStep::Found(commit) => {
+            println!("{} is the first bad commit", commit.id);
+            println!("  date: {}", commit.date);
+            if let Some(message) = commit.message.as_ref() {
+                println!("  message: {}", message);
+            }
+        }
+        BisectStep::OnlySkipped(commits) => {
+            println!("There are only skipped commits left to test.");
+            println!("The first bad commit could be any of:");
+            for commit in commits {
+                println!("{}", commit.id);
             }
         }
-        None => v.push(default_cmd.to_string()),
     }
-    v
+    Ok(())
+}
+
+fn run_args_to_vec(args: RunArgs) -> Option<Vec<String>> {
+    let mut v: Vec<String> = vec![args.command?];
+    if let Some(mut cmd_args) = args.args {
+        v.append(&mut cmd_args);
+    }
+    Some(v)
 }
 
 fn print_commits_info(commits: CommitsIter<&Commit>) {
@@ -175,14 +1192,39 @@ fn print_commits_info(commits: CommitsIter<&Commit>) {
     }
 }
 
+fn print_reflog<'a, I: Iterator<Item = &'a RefLogEntry>>(entries: I) {
+    for (i, entry) in entries.enumerate() {
+        let short_id = entry.commit_id.get(..7).unwrap_or(&entry.commit_id);
+        println!("{short_id} HEAD@{{{i}}}: {}", entry.message);
+    }
+}
+
 fn print_all_branches(branches: Vec<&str>) {
     for branch in branches {
         println!("{branch}");
     }
 }
 
-fn print_dir_content_recursively<P: AsRef<Path>>(root_path: P) {
-    let mut walkdir = WalkDir::new(root_path.as_ref()).into_iter();
+fn has_changes<P: AsRef<Path>>(upperdir: P, ignore: &IgnoreRules) -> Result<bool> {
+    let upperdir = upperdir.as_ref();
+    let ignored = ignore.scan(upperdir)?;
+    let changes = layer::collect_selected(upperdir, |path| ignored.filter(path, Selection::All))?;
+    Ok(!changes.is_empty())
+}
+
+fn clean_ignored<P: AsRef<Path>>(upperdir: P, ignore: &IgnoreRules) -> Result<()> {
+    let upperdir = upperdir.as_ref();
+    let ignored = ignore.scan(upperdir)?.into_paths();
+    layer::remove_entries(upperdir, &ignored)
+}
+
+fn print_dir_content_recursively<P: AsRef<Path>>(root_path: P, ignore: &IgnoreRules) {
+    let mut walkdir = WalkDir::new(root_path.as_ref())
+        .into_iter()
+        .filter_entry(|entry| {
+            let path = entry.path().strip_prefix(root_path.as_ref()).unwrap();
+            !ignore.is_ignored(path, entry.file_type().is_dir())
+        });
     let _ = walkdir.next();
     let root = PathBuf::from("/");
     for entry in walkdir {
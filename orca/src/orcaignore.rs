@@ -0,0 +1,151 @@
+use crate::layer::{self, Selection};
+use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::Write;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+/// Written to '.orcaignore' by 'init'
+pub const DEFAULT_RULES: &str = "\
+# Paths which are never committed (gitignore-style patterns)
+/tmp/
+/var/tmp/
+/var/cache/apt/
+/var/log/
+/run/
+/root/.bash_history
+/root/.cache/
+/home/*/.bash_history
+/home/*/.cache/
+*.pid
+";
+
+/// Ignore rules of an environment, matched against paths relative to its root
+pub struct IgnoreRules {
+    matcher: Gitignore,
+}
+
+impl IgnoreRules {
+    /// Loads rules from `file_path`. Missing file means no rules.
+    pub fn load<P: AsRef<Path>>(file_path: P) -> Result<Self> {
+        let file_path = file_path.as_ref();
+        let mut builder = GitignoreBuilder::new("");
+        if file_path.exists() {
+            let rules = fs::read_to_string(file_path)
+                .with_context(|| format!("Failed to read '{}'", file_path.display()))?;
+            for line in rules.lines() {
+                builder.add_line(None, line).with_context(|| {
+                    format!("Invalid rule in '{}': {line}", file_path.display())
+                })?;
+            }
+        }
+        let matcher = builder.build().context("Failed to build ignore rules")?;
+        Ok(Self { matcher })
+    }
+
+    pub fn create_default<P: AsRef<Path>>(file_path: P) -> Result<()> {
+        let file_path = file_path.as_ref();
+        let mut file = File::create(file_path)
+            .with_context(|| format!("Failed to create '{}'", file_path.display()))?;
+        file.write_all(DEFAULT_RULES.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn is_ignored<P: AsRef<Path>>(&self, path: P, is_dir: bool) -> bool {
+        let path = path.as_ref();
+        !path.as_os_str().is_empty()
+            && self
+                .matcher
+                .matched_path_or_any_parents(path, is_dir)
+                .is_ignore()
+    }
+
+    /// Finds the ignored entries under `root` in one walk
+    pub fn scan<P: AsRef<Path>>(&self, root: P) -> Result<IgnoredEntries> {
+        let root = root.as_ref();
+        let paths = layer::collect_selected(root, |path| self.select_ignored(root, path))?;
+        Ok(IgnoredEntries {
+            paths: paths.into_iter().collect(),
+        })
+    }
+
+    /// Selects only ignored entries
+    fn select_ignored(&self, root: &Path, path: &Path) -> Selection {
+        let is_dir = root.join(path).symlink_metadata().is_ok_and(|m| m.is_dir());
+        if self.is_ignored(path, is_dir) {
+            Selection::All
+        } else {
+            Selection::Partial
+        }
+    }
+}
+
+/// Topmost ignored entries under a root, relative to it.
+/// Everything under them is ignored too, since ignored directories are never looked into.
+pub struct IgnoredEntries {
+    paths: BTreeSet<PathBuf>,
+}
+
+impl IgnoredEntries {
+    /// Narrows `selection` of `path` so that ignored entries are not selected
+    pub fn filter<P: AsRef<Path>>(&self, path: P, selection: Selection) -> Selection {
+        let path = path.as_ref();
+        match selection {
+            Selection::Nothing => Selection::Nothing,
+            _ if self.is_ignored(path) => Selection::Nothing,
+            Selection::All if self.contains_ignored(path) => Selection::Partial,
+            selection => selection,
+        }
+    }
+
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        path.ancestors()
+            .any(|ancestor| self.paths.contains(ancestor))
+    }
+
+    /// Descendants of a path sort right after it, so only the next entry has to be checked
+    fn contains_ignored(&self, dir: &Path) -> bool {
+        self.paths
+            .range::<Path, _>((Bound::Excluded(dir), Bound::Unbounded))
+            .next()
+            .is_some_and(|path| path.starts_with(dir))
+    }
+
+    pub fn into_paths(self) -> Vec<PathBuf> {
+        self.paths.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_by_topmost_ignored_entries() {
+        let ignored = IgnoredEntries {
+            paths: ["var/log", "tmp"].into_iter().map(PathBuf::from).collect(),
+        };
+        assert_eq!(
+            ignored.filter("tmp/a/b", Selection::All),
+            Selection::Nothing
+        );
+        assert_eq!(
+            ignored.filter("var/log", Selection::All),
+            Selection::Nothing
+        );
+        assert_eq!(ignored.filter("var", Selection::All), Selection::Partial);
+        assert_eq!(ignored.filter("", Selection::All), Selection::Partial);
+        // 'var/log-old' sorts after 'var/log' but is not under it
+        assert_eq!(
+            ignored.filter("var/log-old", Selection::All),
+            Selection::All
+        );
+        assert_eq!(ignored.filter("var/lib", Selection::All), Selection::All);
+        assert_eq!(
+            ignored.filter("var", Selection::Nothing),
+            Selection::Nothing
+        );
+    }
+}
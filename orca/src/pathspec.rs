@@ -0,0 +1,122 @@
+use crate::layer::{to_relative, Selection};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Paths selected by '--path' and excluded by '--exclude'
+pub struct Pathspec {
+    includes: Vec<PathBuf>,
+    excludes: Vec<PathBuf>,
+}
+
+impl Pathspec {
+    pub fn new<S: AsRef<str>>(includes: &[S], excludes: &[S]) -> Result<Self> {
+        Ok(Self {
+            includes: to_relatives(includes)?,
+            excludes: to_relatives(excludes)?,
+        })
+    }
+
+    /// Returns true if every path is selected
+    pub fn is_all(&self) -> bool {
+        self.includes.is_empty() && self.excludes.is_empty()
+    }
+
+    /// `path` is relative to the root of the environment
+    pub fn select<P: AsRef<Path>>(&self, path: P) -> Selection {
+        let path = path.as_ref();
+        if self
+            .excludes
+            .iter()
+            .any(|exclude| path.starts_with(exclude))
+        {
+            return Selection::Nothing;
+        }
+
+        let included = self.includes.is_empty()
+            || self
+                .includes
+                .iter()
+                .any(|include| path.starts_with(include));
+        if included {
+            if self
+                .excludes
+                .iter()
+                .any(|exclude| exclude.starts_with(path))
+            {
+                Selection::Partial
+            } else {
+                Selection::All
+            }
+        } else if self
+            .includes
+            .iter()
+            .any(|include| include.starts_with(path))
+        {
+            Selection::Partial
+        } else {
+            Selection::Nothing
+        }
+    }
+}
+
+fn to_relatives<S: AsRef<str>>(paths: &[S]) -> Result<Vec<PathBuf>> {
+    paths
+        .iter()
+        .map(|path| {
+            to_relative(path.as_ref()).with_context(|| format!("Invalid path: '{}'", path.as_ref()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pathspec(includes: &[&str], excludes: &[&str]) -> Pathspec {
+        Pathspec::new(includes, excludes).unwrap()
+    }
+
+    #[test]
+    fn selects_everything_without_paths() {
+        let spec = pathspec(&[], &[]);
+        assert!(spec.is_all());
+        assert_eq!(spec.select("etc/hosts"), Selection::All);
+    }
+
+    #[test]
+    fn selects_included_subtrees() {
+        let spec = pathspec(&["/usr/local", "/etc/foo"], &[]);
+        assert_eq!(spec.select("usr/local"), Selection::All);
+        assert_eq!(spec.select("usr/local/bin/jq"), Selection::All);
+        assert_eq!(spec.select("etc/foo"), Selection::All);
+        assert_eq!(spec.select("usr"), Selection::Partial);
+        assert_eq!(spec.select("etc"), Selection::Partial);
+        assert_eq!(spec.select("usr/lib"), Selection::Nothing);
+        assert_eq!(spec.select("etc/foobar"), Selection::Nothing);
+    }
+
+    #[test]
+    fn excludes_win_over_includes() {
+        let spec = pathspec(&["/usr"], &["/usr/local/cache"]);
+        assert_eq!(spec.select("usr/lib"), Selection::All);
+        assert_eq!(spec.select("usr"), Selection::Partial);
+        assert_eq!(spec.select("usr/local"), Selection::Partial);
+        assert_eq!(spec.select("usr/local/cache"), Selection::Nothing);
+        assert_eq!(spec.select("usr/local/cache/x"), Selection::Nothing);
+    }
+
+    #[test]
+    fn excludes_alone_select_the_rest() {
+        let spec = pathspec(&[], &["tmp"]);
+        assert!(!spec.is_all());
+        assert_eq!(spec.select("tmp"), Selection::Nothing);
+        assert_eq!(spec.select("var"), Selection::All);
+        assert_eq!(spec.select(""), Selection::Partial);
+    }
+
+    #[test]
+    fn rejects_escaping_paths() {
+        assert!(Pathspec::new(&["../etc"], &[]).is_err());
+        assert!(Pathspec::new(&[], &["/etc/../../x"]).is_err());
+    }
+}
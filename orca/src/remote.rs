@@ -0,0 +1,477 @@
+mod directory;
+mod http;
+
+use crate::archive;
+use anyhow::{bail, Context, Result};
+use directory::DirectoryRemote;
+use http::HttpRemote;
+pub use http::HttpServer;
+use orca_vcs::{Commit, VCS};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// How a remote is reached. Layers are exchanged as blobs addressed by their SHA-256 digest
+/// ([`blob_digest`]), so that only missing ones are transferred.
+pub trait Transport {
+    /// Commits, branches and layer digests of the remote
+    fn read_index(&self) -> Result<RemoteIndex>;
+
+    /// Negotiation before sending layers: returns the digests in `have` which the remote wants
+    fn want_layers(&self, have: &[String]) -> Result<Vec<String>>;
+
+    /// Uploads the blob file at `blob` (a layer written by [`archive::pack_layer`])
+    fn put_layer(&self, digest: &str, blob: &Path) -> Result<()>;
+
+    /// Downloads the blob of `digest` into the file `dest`
+    fn get_layer(&self, digest: &str, dest: &Path) -> Result<()>;
+
+    /// Adds commits and moves a branch, only if the branch is still at `update.old_id`.
+    /// Returns false if someone else has moved it meanwhile.
+    fn update_ref(&self, update: &RefUpdateRequest) -> Result<bool>;
+}
+
+/// Commits, branches and layer digests known by a remote
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RemoteIndex {
+    #[serde(default)]
+    pub commits: Vec<Commit>,
+    /// Branch name to commit ID
+    #[serde(default)]
+    pub branches: BTreeMap<String, String>,
+    /// Commit ID to digest of its layer
+    #[serde(default)]
+    pub layers: BTreeMap<String, String>,
+}
+
+/// Request to move a remote branch, carrying the commits the remote does not have yet
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RefUpdateRequest {
+    pub branch: String,
+    pub old_id: Option<String>,
+    pub new_id: String,
+    /// Commits ordered from the oldest one
+    #[serde(default)]
+    pub commits: Vec<Commit>,
+    /// Commit ID to digest of its layer, for each commit in `commits`
+    #[serde(default)]
+    pub layers: BTreeMap<String, String>,
+}
+
+/// Movement of a branch caused by push or fetch
+#[derive(Debug)]
+pub struct RefUpdate {
+    pub branch: String,
+    pub old_id: Option<String>,
+    pub new_id: String,
+}
+
+impl RemoteIndex {
+    fn get_commit(&self, commit_id: &str) -> Option<&Commit> {
+        self.commits.iter().find(|commit| commit.id == commit_id)
+    }
+
+    /// Returns `tip` and its ancestors, from the newest one
+    fn ancestry(&self, tip: &str) -> Result<Vec<&Commit>> {
+        let mut commits = vec![];
+        let mut next = Some(tip);
+        while let Some(commit_id) = next {
+            let Some(commit) = self.get_commit(commit_id) else {
+                bail!("Remote index is broken: commit {commit_id} is missing");
+            };
+            commits.push(commit);
+            next = commit.parent_id();
+        }
+        Ok(commits)
+    }
+}
+
+/// Directories of the local environment used while syncing
+pub struct LocalStore<'a> {
+    pub lower_root: &'a Path,
+    pub tmpdir: &'a Path,
+}
+
+impl LocalStore<'_> {
+    /// Creates an empty directory under tmpdir, removing leftovers of an interrupted run
+    fn staging_dir(&self, name: &str) -> Result<PathBuf> {
+        let staging_dir = self.tmpdir.join(name);
+        if staging_dir.exists() {
+            fs::remove_dir_all(&staging_dir)?;
+        }
+        fs::create_dir_all(&staging_dir)?;
+        Ok(staging_dir)
+    }
+}
+
+/// Sends `branch` and the commits and layers the remote does not have yet.
+/// Refuses to move the remote branch backwards or sideways unless `force` is set.
+pub fn push(
+    vcs: &mut VCS,
+    remote_name: &str,
+    branch: &str,
+    force: bool,
+    local: &LocalStore,
+) -> Result<Option<RefUpdate>> {
+    let remote = open_remote(vcs, remote_name)?;
+    if !vcs.get_all_branches().contains(&branch) {
+        bail!("Branch '{branch}' does not exist");
+    }
+    let tip = match vcs.get_commit(branch) {
+        Ok(commit) => commit.id.clone(),
+        Err(orca_vcs::Error::CommitNotFound) => {
+            bail!("Branch '{branch}' does not have any commits")
+        }
+        Err(e) => Err(e)?,
+    };
+
+    let index = remote.read_index()?;
+    let old_id = index.branches.get(branch).cloned();
+    if old_id.as_deref() == Some(tip.as_str()) {
+        return Ok(None);
+    }
+    if let Some(old_id) = old_id.as_deref()
+        && !force
+        && !matches!(vcs.is_ancestor(old_id, &tip), Ok(true))
+    {
+        bail!(
+            "Updates were rejected because the remote branch '{branch}' has commits \
+            which are not in the local one (non-fast-forward). \
+            Fetch them first, or push with '--force'"
+        );
+    }
+
+    let mut commits: Vec<Commit> = vcs
+        .get_commits(&tip)?
+        .take_while(|commit| index.get_commit(&commit.id).is_none())
+        .cloned()
+        .collect();
+    commits.reverse();
+    // Layers are packed first, since blobs are addressed by their own digests
+    let staging_dir = local.staging_dir("push")?;
+    let mut layers = BTreeMap::new();
+    for commit in commits.iter() {
+        let blob = staging_dir.join(&commit.id);
+        archive::pack_layer(local.lower_root.join(&commit.id), &blob)?;
+        layers.insert(commit.id.clone(), blob_digest(&blob)?);
+    }
+
+    let have: Vec<String> = layers.values().cloned().collect();
+    let wanted: HashSet<String> = remote.want_layers(&have)?.into_iter().collect();
+    let mut sent = HashSet::new();
+    for commit in commits.iter() {
+        let digest = &layers[&commit.id];
+        if wanted.contains(digest) && sent.insert(digest) {
+            remote.put_layer(digest, &staging_dir.join(&commit.id))?;
+        }
+    }
+    fs::remove_dir_all(&staging_dir)?;
+
+    let request = RefUpdateRequest {
+        branch: branch.to_string(),
+        old_id: old_id.clone(),
+        new_id: tip.clone(),
+        commits,
+        layers,
+    };
+    if !remote.update_ref(&request)? {
+        bail!("Remote branch '{branch}' has been updated while pushing, fetch and try again");
+    }
+
+    vcs.update_remote_branch(remote_name, branch, &tip)?;
+    Ok(Some(RefUpdate {
+        branch: branch.to_string(),
+        old_id,
+        new_id: tip,
+    }))
+}
+
+/// Downloads commits and layers of all remote branches and updates remote-tracking branches
+pub fn fetch(vcs: &mut VCS, remote_name: &str, local: &LocalStore) -> Result<Vec<RefUpdate>> {
+    let remote = open_remote(vcs, remote_name)?;
+    let index = remote.read_index()?;
+    check_index(&index)?;
+
+    let staging_dir = local.staging_dir("fetch")?;
+
+    let mut updates = vec![];
+    for (branch, tip) in index.branches.iter() {
+        let missing: Vec<Commit> = index
+            .ancestry(tip)?
+            .into_iter()
+            .take_while(|commit| vcs.get_commit(&commit.id).is_err())
+            .cloned()
+            .collect();
+        for commit in missing.iter().rev() {
+            let layer_dir = local.lower_root.join(&commit.id);
+            if layer_dir.exists() {
+                continue;
+            }
+            let Some(digest) = index.layers.get(&commit.id) else {
+                bail!("Remote index is broken: no layer for commit {}", commit.id);
+            };
+            // Layers shared by several commits are downloaded once
+            let blob = staging_dir.join(digest);
+            if !blob.exists() {
+                remote.get_layer(digest, &blob)?;
+                if blob_digest(&blob)? != *digest {
+                    fs::remove_file(&blob)?;
+                    bail!("Layer of commit {} from remote is corrupted", commit.id);
+                }
+            }
+            let staged = staging_dir.join(&commit.id);
+            archive::unpack_layer(&blob, &staged)?;
+            fs::rename(&staged, &layer_dir)
+                .with_context(|| format!("Failed to add layer '{}'", layer_dir.display()))?;
+        }
+        vcs.import_commits(missing.into_iter().rev().collect())?;
+
+        let old_id = vcs
+            .get_remote_branches()
+            .iter()
+            .find(|tracking| tracking.remote == remote_name && tracking.name == *branch)
+            .map(|tracking| tracking.commit_id.clone());
+        if old_id.as_deref() != Some(tip.as_str()) {
+            vcs.update_remote_branch(remote_name, branch, tip)?;
+            updates.push(RefUpdate {
+                branch: branch.clone(),
+                old_id,
+                new_id: tip.clone(),
+            });
+        }
+    }
+    fs::remove_dir_all(&staging_dir)?;
+    Ok(updates)
+}
+
+/// Checks commit IDs and digests of a remote index, since they become paths of layers and blobs
+fn check_index(index: &RemoteIndex) -> Result<()> {
+    let ids = index
+        .commits
+        .iter()
+        .flat_map(|commit| [Some(commit.id.as_str()), commit.parent_id()])
+        .flatten()
+        .chain(index.branches.values().map(|id| id.as_str()))
+        .chain(index.layers.keys().map(|id| id.as_str()));
+    for id in ids {
+        if !Commit::is_valid_id(id) {
+            bail!("Remote index is broken: invalid commit ID '{id}'");
+        }
+    }
+    for digest in index.layers.values() {
+        check_digest(digest)?;
+    }
+    Ok(())
+}
+
+/// Digests become file names, so anything but a SHA-256 hex string is rejected
+fn check_digest(digest: &str) -> Result<()> {
+    if digest.len() != 64 || !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("Invalid layer digest: '{digest}'");
+    }
+    Ok(())
+}
+
+/// SHA-256 digest of the blob file at `path`, which addresses it on remotes
+fn blob_digest(path: &Path) -> Result<String> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open '{}'", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Remote named by the user: 'origin' if it exists, or the only one
+pub fn default_remote(vcs: &VCS) -> Result<String> {
+    let remotes = vcs.get_remotes();
+    if remotes.iter().any(|remote| remote.name == "origin") {
+        return Ok("origin".to_string());
+    }
+    match remotes {
+        [] => bail!("No remotes, add one with 'remote add'"),
+        [remote] => Ok(remote.name.clone()),
+        _ => bail!("Several remotes exist, specify one of them"),
+    }
+}
+
+/// Normalizes a remote URL given by the user: HTTP URLs are kept and paths become absolute
+pub fn normalize_url(url: &str) -> Result<String> {
+    if HttpRemote::is_url(url) {
+        return Ok(url.trim_end_matches('/').to_string());
+    }
+    let path = fs::canonicalize(url)
+        .or_else(|_| std::path::absolute(PathBuf::from(url)))
+        .with_context(|| format!("Invalid remote path: '{url}'"))?;
+    Ok(path.to_string_lossy().into_owned())
+}
+
+fn open_remote(vcs: &VCS, remote_name: &str) -> Result<Box<dyn Transport>> {
+    let url = &vcs.get_remote(remote_name)?.url;
+    if HttpRemote::is_url(url) {
+        Ok(Box::new(HttpRemote::new(url)))
+    } else {
+        Ok(Box::new(DirectoryRemote::new(url)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use tempfile::TempDir;
+
+    /// Environment with a remote named 'origin'
+    struct TestEnv {
+        _dir: TempDir,
+        vcs: VCS,
+        lower_root: PathBuf,
+        tmpdir: PathBuf,
+    }
+
+    impl TestEnv {
+        fn new(remote_url: &str) -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let commits_file = dir.path().join("commits.toml");
+            VCS::init(&commits_file).unwrap();
+            let mut vcs = VCS::new(&commits_file).unwrap();
+            vcs.add_remote("origin", remote_url).unwrap();
+            let lower_root = dir.path().join("layers");
+            let tmpdir = dir.path().join("tmp");
+            fs::create_dir_all(&lower_root).unwrap();
+            fs::create_dir_all(&tmpdir).unwrap();
+            Self {
+                _dir: dir,
+                vcs,
+                lower_root,
+                tmpdir,
+            }
+        }
+
+        /// Commits a layer holding the file `name`
+        fn commit(&mut self, name: &str, content: &str) -> String {
+            let id = self.vcs.commit(Some(name)).unwrap().id.clone();
+            let layer_dir = self.lower_root.join(&id);
+            fs::create_dir_all(&layer_dir).unwrap();
+            fs::write(layer_dir.join(name), content).unwrap();
+            id
+        }
+
+        fn push_main(&mut self) -> Result<Option<RefUpdate>> {
+            let local = LocalStore {
+                lower_root: &self.lower_root,
+                tmpdir: &self.tmpdir,
+            };
+            push(&mut self.vcs, "origin", "main", false, &local)
+        }
+
+        fn fetch_origin(&mut self) -> Result<Vec<RefUpdate>> {
+            let local = LocalStore {
+                lower_root: &self.lower_root,
+                tmpdir: &self.tmpdir,
+            };
+            fetch(&mut self.vcs, "origin", &local)
+        }
+    }
+
+    /// Serves a directory remote over HTTP until the tests end
+    fn serve(root: &Path) -> String {
+        let server = HttpServer::bind("127.0.0.1:0", root).unwrap();
+        let url = server.url();
+        thread::spawn(move || server.serve());
+        url
+    }
+
+    fn round_trip(url: &str) {
+        let mut pusher = TestEnv::new(url);
+        let first = pusher.commit("a.txt", "a\n");
+        let second = pusher.commit("b.txt", "b\n");
+        let update = pusher.push_main().unwrap().unwrap();
+        assert_eq!((update.old_id, update.new_id), (None, second.clone()));
+        assert!(pusher.push_main().unwrap().is_none());
+
+        let mut fetcher = TestEnv::new(url);
+        let updates = fetcher.fetch_origin().unwrap();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].new_id, second);
+        for (id, name, content) in [(&first, "a.txt", "a\n"), (&second, "b.txt", "b\n")] {
+            assert!(fetcher.vcs.get_commit(id).is_ok());
+            let file = fetcher.lower_root.join(id).join(name);
+            assert_eq!(fs::read_to_string(file).unwrap(), content);
+        }
+        assert!(fetcher.fetch_origin().unwrap().is_empty());
+    }
+
+    #[test]
+    fn round_trip_over_directory() {
+        let remote = tempfile::tempdir().unwrap();
+        round_trip(remote.path().to_str().unwrap());
+    }
+
+    #[test]
+    fn round_trip_over_http() {
+        let remote = tempfile::tempdir().unwrap();
+        round_trip(&serve(remote.path()));
+    }
+
+    #[test]
+    fn rejects_blobs_not_matching_digests() {
+        let remote = tempfile::tempdir().unwrap();
+        let url = serve(remote.path());
+        let blob_dir = tempfile::tempdir().unwrap();
+        let blob = blob_dir.path().join("blob");
+        fs::write(&blob, "blob").unwrap();
+        let wrong = "0".repeat(64);
+        let transports: [Box<dyn Transport>; 2] = [
+            Box::new(DirectoryRemote::new(remote.path())),
+            Box::new(HttpRemote::new(&url)),
+        ];
+        for transport in transports {
+            assert!(transport.put_layer(&wrong, &blob).is_err());
+            assert!(transport.put_layer("../../escape", &blob).is_err());
+            let wanted = transport.want_layers(std::slice::from_ref(&wrong)).unwrap();
+            assert_eq!(wanted, [wrong.as_str()]);
+        }
+        assert!(!remote.path().join("escape").exists());
+    }
+
+    #[test]
+    fn rejects_corrupted_layers_on_fetch() {
+        let remote = tempfile::tempdir().unwrap();
+        let url = remote.path().to_str().unwrap();
+        let mut pusher = TestEnv::new(url);
+        let id = pusher.commit("a.txt", "a\n");
+        pusher.push_main().unwrap();
+        for entry in fs::read_dir(remote.path().join("layers")).unwrap() {
+            fs::write(entry.unwrap().path(), "corrupted").unwrap();
+        }
+
+        let mut fetcher = TestEnv::new(url);
+        let error = fetcher.fetch_origin().unwrap_err();
+        assert!(error.to_string().contains("corrupted"));
+        assert!(!fetcher.lower_root.join(&id).exists());
+        assert!(fetcher.vcs.get_commit(&id).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_commit_ids_on_fetch() {
+        let remote = tempfile::tempdir().unwrap();
+        let url = remote.path().to_str().unwrap();
+        let mut pusher = TestEnv::new(url);
+        let id = pusher.commit("a.txt", "a\n");
+        pusher.push_main().unwrap();
+        let index_file = remote.path().join("index.toml");
+        let index = fs::read_to_string(&index_file).unwrap();
+        let index = index
+            .replace(&format!("\"{id}\""), "\"../../escape\"")
+            .replace(&format!("{id} ="), "\"../../escape\" =");
+        fs::write(&index_file, index).unwrap();
+
+        let mut fetcher = TestEnv::new(url);
+        let error = fetcher.fetch_origin().unwrap_err();
+        assert!(error.to_string().contains("invalid commit ID"));
+        assert!(!fetcher.lower_root.join("../../escape").exists());
+    }
+}
@@ -0,0 +1,201 @@
+use super::{blob_digest, check_digest, RefUpdateRequest, RemoteIndex, Transport};
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+const INDEX_FILE_NAME: &str = "index.toml";
+const LOCK_FILE_NAME: &str = "index.lock";
+const LAYERS_DIR_NAME: &str = "layers";
+const LAYER_EXTENSION: &str = "tar.zst";
+
+/// Remote stored in a plain directory, e.g. on a shared NFS mount.
+/// Layers are kept as tar archives so that ownership survives even without root on the share.
+pub struct DirectoryRemote {
+    root: PathBuf,
+}
+
+/// Held while the index of a remote is being updated
+struct RemoteLock {
+    path: PathBuf,
+}
+
+impl DirectoryRemote {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Writes a layer blob read from `reader`.
+    /// The blob appears under its final name only once it is complete and matches `digest`.
+    pub fn write_layer<R: Read + ?Sized>(&self, digest: &str, reader: &mut R) -> Result<()> {
+        check_digest(digest)?;
+        let layers_dir = self.root.join(LAYERS_DIR_NAME);
+        fs::create_dir_all(&layers_dir).with_context(|| {
+            format!(
+                "Failed to create remote directory: '{}'",
+                self.root.display()
+            )
+        })?;
+        let dest = self.layer_path(digest);
+        let tmp = layers_dir.join(format!("{digest}.{}.tmp", std::process::id()));
+        let mut file =
+            File::create(&tmp).with_context(|| format!("Failed to create '{}'", tmp.display()))?;
+        io::copy(reader, &mut file)?;
+        file.sync_all()?;
+        if blob_digest(&tmp)? != digest {
+            fs::remove_file(&tmp)?;
+            bail!("Layer blob does not match its digest {digest}");
+        }
+        fs::rename(&tmp, &dest)?;
+        Ok(())
+    }
+
+    /// Opens the blob of `digest` for reading
+    pub fn open_layer(&self, digest: &str) -> Result<File> {
+        check_digest(digest)?;
+        let path = self.layer_path(digest);
+        match File::open(&path) {
+            Ok(file) => Ok(file),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                bail!("Remote does not have layer {digest}")
+            }
+            Err(e) => Err(e).with_context(|| format!("Failed to open '{}'", path.display())),
+        }
+    }
+
+    fn has_layer(&self, digest: &str) -> bool {
+        check_digest(digest).is_ok() && self.layer_path(digest).exists()
+    }
+
+    /// Replaces the index atomically so that readers never see a partial one
+    fn write_index(&self, index: &RemoteIndex, _lock: &RemoteLock) -> Result<()> {
+        let index_file = self.root.join(INDEX_FILE_NAME);
+        let tmp_file = self.root.join(format!("{INDEX_FILE_NAME}.tmp"));
+        fs::write(&tmp_file, toml::to_string(index)?)
+            .with_context(|| format!("Failed to write '{}'", tmp_file.display()))?;
+        fs::rename(&tmp_file, &index_file)?;
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<RemoteLock> {
+        fs::create_dir_all(&self.root).with_context(|| {
+            format!(
+                "Failed to create remote directory: '{}'",
+                self.root.display()
+            )
+        })?;
+        let path = self.root.join(LOCK_FILE_NAME);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => Ok(RemoteLock { path }),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => bail!(
+                "Remote is being updated by someone else. \
+                If no push is running, remove '{}'",
+                path.display()
+            ),
+            Err(e) => Err(e).with_context(|| format!("Failed to create '{}'", path.display())),
+        }
+    }
+
+    fn layer_path(&self, digest: &str) -> PathBuf {
+        self.root
+            .join(LAYERS_DIR_NAME)
+            .join(format!("{digest}.{LAYER_EXTENSION}"))
+    }
+}
+
+impl Transport for DirectoryRemote {
+    /// Returns an empty index if nothing has been pushed yet
+    fn read_index(&self) -> Result<RemoteIndex> {
+        let index_file = self.root.join(INDEX_FILE_NAME);
+        match fs::read_to_string(&index_file) {
+            Ok(content) => toml::from_str(&content)
+                .with_context(|| format!("Invalid remote index: '{}'", index_file.display())),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                if !self.root.is_dir() {
+                    bail!("Remote directory does not exist: '{}'", self.root.display());
+                }
+                Ok(RemoteIndex::default())
+            }
+            Err(e) => Err(e).with_context(|| format!("Failed to read '{}'", index_file.display())),
+        }
+    }
+
+    fn want_layers(&self, have: &[String]) -> Result<Vec<String>> {
+        Ok(have
+            .iter()
+            .filter(|digest| !self.has_layer(digest))
+            .cloned()
+            .collect())
+    }
+
+    fn put_layer(&self, digest: &str, blob: &Path) -> Result<()> {
+        let mut file =
+            File::open(blob).with_context(|| format!("Failed to open '{}'", blob.display()))?;
+        self.write_layer(digest, &mut file)
+    }
+
+    fn get_layer(&self, digest: &str, dest: &Path) -> Result<()> {
+        let mut source = self.open_layer(digest)?;
+        let mut file =
+            File::create(dest).with_context(|| format!("Failed to create '{}'", dest.display()))?;
+        io::copy(&mut source, &mut file)?;
+        Ok(())
+    }
+
+    fn update_ref(&self, update: &RefUpdateRequest) -> Result<bool> {
+        let lock = self.lock()?;
+        let mut index = self.read_index()?;
+        if index.branches.get(&update.branch) != update.old_id.as_ref() {
+            return Ok(false);
+        }
+
+        let mut parent_id = None;
+        let mut added = vec![];
+        for commit in update.commits.iter() {
+            // Someone else may have pushed the same commits meanwhile
+            if index.get_commit(&commit.id).is_some() {
+                parent_id = Some(commit.id.as_str());
+                continue;
+            }
+            let parent_known = match commit.parent_id() {
+                Some(parent) => parent_id == Some(parent) || index.get_commit(parent).is_some(),
+                None => true,
+            };
+            if !parent_known {
+                bail!("Parent of commit {} is missing", commit.id);
+            }
+            match update.layers.get(&commit.id) {
+                Some(digest) if self.has_layer(digest) => {}
+                _ => bail!("Layer of commit {} has not been uploaded", commit.id),
+            }
+            parent_id = Some(commit.id.as_str());
+            added.push(commit.clone());
+        }
+        let new_known = update
+            .commits
+            .iter()
+            .any(|commit| commit.id == update.new_id)
+            || index.get_commit(&update.new_id).is_some();
+        if !new_known {
+            bail!("Commit {} is missing", update.new_id);
+        }
+
+        for commit in added {
+            index
+                .layers
+                .insert(commit.id.clone(), update.layers[&commit.id].clone());
+            index.commits.push(commit);
+        }
+        index
+            .branches
+            .insert(update.branch.clone(), update.new_id.clone());
+        self.write_index(&index, &lock)?;
+        Ok(true)
+    }
+}
+
+impl Drop for RemoteLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,198 @@
+use super::directory::DirectoryRemote;
+use super::{RefUpdateRequest, RemoteIndex, Transport};
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::blocking::{Body, Client, Response};
+use reqwest::StatusCode;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use tiny_http::{Method, Request, ResponseBox};
+
+/// Remote reached over HTTP.
+///
+/// | Request                 | Body                      | Response                      |
+/// |-------------------------|---------------------------|-------------------------------|
+/// | `GET /index`            |                           | [`RemoteIndex`] as TOML       |
+/// | `POST /layers/want`     | digests, one per line     | wanted digests, one per line  |
+/// | `GET /layers/<digest>`  |                           | layer blob                    |
+/// | `PUT /layers/<digest>`  | layer blob                |                               |
+/// | `POST /refs`            | [`RefUpdateRequest`] TOML | 409 if the branch has moved   |
+pub struct HttpRemote {
+    url: String,
+    client: Client,
+}
+
+impl HttpRemote {
+    pub fn is_url(url: &str) -> bool {
+        url.starts_with("http://") || url.starts_with("https://")
+    }
+
+    pub fn new(url: &str) -> Self {
+        let client = Client::builder()
+            .timeout(None)
+            .build()
+            .expect("HTTP client should be built without TLS configuration");
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            client,
+        }
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}/{path}", self.url)
+    }
+}
+
+impl Transport for HttpRemote {
+    fn read_index(&self) -> Result<RemoteIndex> {
+        let response = check(self.client.get(self.endpoint("index")).send()?)?;
+        toml::from_str(&response.text()?).context("Invalid remote index")
+    }
+
+    fn want_layers(&self, have: &[String]) -> Result<Vec<String>> {
+        let response = self
+            .client
+            .post(self.endpoint("layers/want"))
+            .body(have.join("\n"))
+            .send()?;
+        Ok(check(response)?
+            .text()?
+            .lines()
+            .map(|digest| digest.to_string())
+            .collect())
+    }
+
+    fn put_layer(&self, digest: &str, blob: &Path) -> Result<()> {
+        let file =
+            File::open(blob).with_context(|| format!("Failed to open '{}'", blob.display()))?;
+        let response = self
+            .client
+            .put(self.endpoint(&format!("layers/{digest}")))
+            .body(Body::from(file))
+            .send()?;
+        check(response)?;
+        Ok(())
+    }
+
+    fn get_layer(&self, digest: &str, dest: &Path) -> Result<()> {
+        let response = self
+            .client
+            .get(self.endpoint(&format!("layers/{digest}")))
+            .send()?;
+        let mut response = check(response)?;
+        let mut file =
+            File::create(dest).with_context(|| format!("Failed to create '{}'", dest.display()))?;
+        io::copy(&mut response, &mut file)?;
+        Ok(())
+    }
+
+    fn update_ref(&self, update: &RefUpdateRequest) -> Result<bool> {
+        let response = self
+            .client
+            .post(self.endpoint("refs"))
+            .body(toml::to_string(update)?)
+            .send()?;
+        if response.status() == StatusCode::CONFLICT {
+            return Ok(false);
+        }
+        check(response)?;
+        Ok(true)
+    }
+}
+
+/// Turns error responses into errors carrying the message from the server
+fn check(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let url = response.url().clone();
+    let message = response.text().unwrap_or_default();
+    bail!("Remote '{url}' responded {status}: {}", message.trim())
+}
+
+/// Small HTTP server exposing a directory remote, standing in for a real server.
+/// Requests are handled one at a time.
+pub struct HttpServer {
+    server: tiny_http::Server,
+    remote: DirectoryRemote,
+}
+
+impl HttpServer {
+    /// Listens on `addr`, where port 0 picks a free port (see [`HttpServer::url`])
+    pub fn bind<P: Into<PathBuf>>(addr: &str, root: P) -> Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)
+            .with_context(|| format!("Failed to create '{}'", root.display()))?;
+        let server = tiny_http::Server::http(addr)
+            .map_err(|e| anyhow!("Failed to listen on {addr}: {e}"))?;
+        Ok(Self {
+            server,
+            remote: DirectoryRemote::new(root),
+        })
+    }
+
+    /// URL to register with 'remote add'
+    pub fn url(&self) -> String {
+        format!("http://{}", self.server.server_addr())
+    }
+
+    /// Handles requests until the process ends
+    pub fn serve(&self) -> Result<()> {
+        for mut request in self.server.incoming_requests() {
+            let response = match self.handle(&mut request) {
+                Ok(response) => response,
+                Err(e) => tiny_http::Response::from_string(format!("{e:#}"))
+                    .with_status_code(500)
+                    .boxed(),
+            };
+            // The client may have gone away, which must not stop the server
+            let _ = request.respond(response);
+        }
+        Ok(())
+    }
+
+    fn handle(&self, request: &mut Request) -> Result<ResponseBox> {
+        let url = request.url().to_string();
+        let path = url.trim_start_matches('/');
+        let mut body = String::new();
+        let response = match (request.method(), path.split_once('/')) {
+            (Method::Get, None) if path == "index" => {
+                let index = self.remote.read_index()?;
+                tiny_http::Response::from_string(toml::to_string(&index)?).boxed()
+            }
+            (Method::Post, None) if path == "refs" => {
+                request.as_reader().read_to_string(&mut body)?;
+                let update: RefUpdateRequest =
+                    toml::from_str(&body).context("Invalid ref update")?;
+                if self.remote.update_ref(&update)? {
+                    tiny_http::Response::empty(204).boxed()
+                } else {
+                    tiny_http::Response::from_string("Branch has been moved")
+                        .with_status_code(409)
+                        .boxed()
+                }
+            }
+            (Method::Post, Some(("layers", "want"))) => {
+                request.as_reader().read_to_string(&mut body)?;
+                let have: Vec<String> = body.lines().map(|line| line.to_string()).collect();
+                let want = self.remote.want_layers(&have)?;
+                tiny_http::Response::from_string(want.join("\n")).boxed()
+            }
+            (Method::Get, Some(("layers", digest))) => match self.remote.open_layer(digest) {
+                Ok(file) => tiny_http::Response::from_file(file).boxed(),
+                Err(e) => tiny_http::Response::from_string(format!("{e:#}"))
+                    .with_status_code(404)
+                    .boxed(),
+            },
+            (Method::Put, Some(("layers", digest))) => {
+                self.remote.write_layer(digest, request.as_reader())?;
+                tiny_http::Response::empty(201).boxed()
+            }
+            _ => tiny_http::Response::from_string(format!("Not found: {url}"))
+                .with_status_code(404)
+                .boxed(),
+        };
+        Ok(response)
+    }
+}